@@ -15,8 +15,10 @@
 use anyhow::{Result, Context};
 use std::{
     collections::HashSet,
-    path::PathBuf,
-    time::{SystemTime, Duration},
+    path::{Path, PathBuf},
+    fs,
+    io::{self, BufReader, BufWriter},
+    time::{SystemTime, Duration, Instant},
 };
 use nix::{
     poll::{PollFd, PollFlags},
@@ -24,23 +26,28 @@ use nix::{
     unistd::Pid,
 };
 use structopt::StructOpt;
-use serde::Serialize;
+use serde::{Serialize, Deserialize};
 use crate::{
     consts::*,
-    store::ImageUrl,
+    logger::{self, LogFormat},
+    store::{ImageUrl, FileExt},
     container,
-    image::{ImageManifest, CpuBudget, shard, check_passphrase_file_exists},
+    image::{ImageManifest, ManifestFetchResult, CpuBudget, shard, check_passphrase_file_exists},
     process::{Command, ProcessExt, ProcessGroup, Stdio},
-    metrics::{with_metrics, emit_metrics},
+    metrics::{with_metrics, emit_metrics, record_phase, PhaseTimer},
+    trace,
     util::poll_nointr,
     image_streamer::{Stats, ImageStreamer},
     lock::with_checkpoint_restore_lock,
-    signal::{kill_process_tree, get_proc_state},
+    signal::{kill_process_tree, get_proc_state, get_process_tree},
     criu,
-    filesystem,
+    filesystem::{self, PreserveOptions, FsLayers, FileIndex},
     virt,
 };
-use super::run::AppConfig;
+use super::{
+    config::ConfigFile,
+    run::AppConfig,
+};
 
 
 /// Perform a checkpoint of the running application
@@ -49,37 +56,84 @@ use super::run::AppConfig;
 ENVS:
     FF_METRICS_RECORDER         When specified, FastFreeze invokes the specified program to report metrics.
                                 The metrics are formatted in JSON and passed as first argument
+    FF_METRICS_SINK             Comma separated URLs where to send metrics, without spawning a program:
+                                udp://host:port (StatsD), file:/path or unix:/path (JSON lines)
+    FF_TRACE_EXPORTER           Where to export a trace of the command in the OTLP/JSON format:
+                                http://host:port/path (OTLP/HTTP collector) or file:/path (JSON lines)
+    FF_LOG_ROTATE_SIZE_MB       Size at which a log file is rotated. Defaults to 10
+    FF_LOG_MAX_FILES            Number of log files kept, including rotated ones. Defaults to 100
+    FF_LOG_MAX_SIZE_MB          Total size of the log files kept. Defaults to 50
     CRIU_OPTS                   Additional arguments to pass to CRIU, whitespace separated
     S3_CMD                      Command to access AWS S3. Defaults to 'aws s3'
     GS_CMD                      Command to access Google Storage. Defaults to 'gcsthin'
-    TAR_CMD                     Command to tar the file system. Defaults to 'tar'"
+    TAR_CMD                     Command to tar the file system. Defaults to a built-in archiver
+    FF_IMAGE_CACHE_DIR          When specified, images stored on S3 or GCS are cached in this local directory
+    FF_IMAGE_CACHE_MAX_SIZE_MB  Size cap of the image cache. Defaults to 10240
+    FF_IMAGE_REPLICA_QUORUM     Number of image URLs that must be written successfully. Defaults to a majority
+    FF_GHOST_FILE_LIMIT_MB      Size cap of each deleted file that the application has open or mapped.
+                                These files are saved in the image. Defaults to 64
+    FF_S3_RETRY_ATTEMPTS        Attempts of S3 operations before giving up. Defaults to 3.
+                                Same for FF_GS_RETRY_ATTEMPTS, and FF_FILE_RETRY_ATTEMPTS (defaults to 1)
+    FF_S3_RETRY_BACKOFF_MS      Delay before the first retry, doubled on each retry. Defaults to 500.
+                                Same for FF_GS_* and FF_FILE_*
+    FF_S3_RETRYABLE_ERRORS      Comma separated error messages on which to retry. Same for FF_GS_* and FF_FILE_*"
 ))]
 pub struct Checkpoint {
     /// Image URL, defaults to the value used during the run command
     #[structopt(short, long)]
     pub image_url: Option<String>,
 
+    /// Config file, in TOML or JSON. Options given on the command line take precedence.
+    /// It defaults to the config file of the run command. See the run command help.
+    #[structopt(long, name = "config-file")]
+    pub config: Option<PathBuf>,
+
     /// Dir/file to include in the image in addition to the ones specified during the run command.
     /// May be specified multiple times. Multiple paths can also be specified colon separated.
     // require_delimiter is set to avoid clap's non-standard way of accepting lists.
     #[structopt(long="preserve-path", name="path", require_delimiter=true, value_delimiter=":")]
     pub preserved_paths: Vec<PathBuf>,
 
+    // Preserve options are combined with the ones specified during the run command
+    #[structopt(flatten)]
+    pub preserve_options: PreserveOptions,
+
+    /// Only archive the preserved files that changed since the previous checkpoint.
+    /// The file system is stored in layers next to the shards, and restored in order.
+    /// A full snapshot is taken every 10 checkpoints to bound the restore time, and when
+    /// the compression or encryption of the image changes.
+    #[structopt(long)]
+    pub incremental: bool,
+
     /// Leave application running after checkpoint
     #[structopt(long)]
     pub leave_running: bool,
 
-    /// Level of parallelism. Split the image in multiple shards.
+    /// Upload the log file of the checkpoint next to the image, so that it
+    /// can be inspected when a restore fails on another machine. The extract
+    /// command downloads it as checkpoint.log.
+    #[structopt(long)]
+    pub upload_log: bool,
+
+    /// Validate the checkpoint settings without checkpointing. It shows the paths
+    /// that would be preserved, including the files the application has open, and
+    /// the estimated image size. It checks that the image store is writable, and
+    /// that CRIU can checkpoint the application, which freezes it briefly.
+    #[structopt(long)]
+    pub dry_run: bool,
+
+    /// Level of parallelism. Split the image in multiple shards. Defaults to 4.
     // We use a default of 4 shards to benefit from some parallelism.
     // It should be set to something related to the number of CPUs available.
-    #[structopt(long, default_value="4")]
-    pub num_shards: u32,
+    // The default is applied after the config file, see resolve_settings().
+    #[structopt(long)]
+    pub num_shards: Option<u32>,
 
     /// Amount of CPU at disposal. Possible values are [low, medium, high].
     /// Currently, `low` skips compression, `medium` uses lz4, and
-    /// `high` uses zstd.
-    #[structopt(long, default_value="medium")]
-    pub cpu_budget: CpuBudget,
+    /// `high` uses zstd. Defaults to medium.
+    #[structopt(long)]
+    pub cpu_budget: Option<CpuBudget>,
 
     /// Enable image encryption. This points to a file containing a passphrase
     /// used to encrypt the image. The passphrase should contain at least 256 bits
@@ -91,36 +145,112 @@ pub struct Checkpoint {
     #[structopt(short, long, parse(from_occurrences))]
     pub verbose: u8,
 
+    /// Log format. Possible values are [text, json]. Defaults to text,
+    /// or to FF_LOG_FORMAT when set. Log files use the same format.
+    #[structopt(long)]
+    pub log_format: Option<LogFormat>,
+
     /// Checkpoint the specified application. See the run command help about
     /// --app-name for more details.
     #[structopt()]
     pub app_name: Option<String>,
 }
 
-pub fn do_checkpoint(opts: Checkpoint) -> Result<Stats> {
+/// Outcome of the last successful checkpoint, reported by the status command.
+/// It is also recorded in the image manifest, so that it survives restores.
+#[derive(Serialize, Deserialize)]
+pub struct LastCheckpoint {
+    pub image_url: String,
+    pub completed_at: SystemTime,
+    pub duration_sec: f64,
+    pub stats: Stats,
+}
+
+impl LastCheckpoint {
+    pub fn save(&self) -> Result<()> {
+        let file = fs::File::create(&*LAST_CHECKPOINT_PATH)
+            .with_context(|| format!("Failed to create {}", LAST_CHECKPOINT_PATH.display()))?;
+        serde_json::to_writer_pretty(BufWriter::new(file), &self)?;
+        Ok(())
+    }
+
+    /// Returns None if no checkpoint happened since the application started.
+    pub fn load() -> Result<Option<Self>> {
+        let file = match fs::File::open(&*LAST_CHECKPOINT_PATH) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("Failed to open {}", LAST_CHECKPOINT_PATH.display())),
+        };
+        Ok(Some(serde_json::from_reader(BufReader::new(file))?))
+    }
+
+    /// Returns the record of the checkpoint that produced the image. None for
+    /// images written by older versions.
+    pub fn from_manifest(image_url: String, img_manifest: &ImageManifest) -> Option<Self> {
+        Some(Self {
+            image_url,
+            completed_at: img_manifest.completed_at?,
+            duration_sec: img_manifest.duration_sec?,
+            stats: *img_manifest.stats.clone()?,
+        })
+    }
+
+    /// Called when the application starts, as a previous record would be stale.
+    pub fn remove() -> Result<()> {
+        match fs::remove_file(&*LAST_CHECKPOINT_PATH) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e)
+                .with_context(|| format!("Failed to remove {}", LAST_CHECKPOINT_PATH.display())),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The checkpoint settings given on the command line, combined with the ones
+/// of the config file and of the previous operations.
+struct Settings {
+    image_url: ImageUrl,
+    preserved_paths: HashSet<PathBuf>,
+    preserve_options: PreserveOptions,
+    passphrase_file: Option<PathBuf>,
+    num_shards: u32,
+    cpu_budget: CpuBudget,
+    incremental: bool,
+    upload_log: bool,
+    config_file: ConfigFile,
+    config: AppConfig,
+}
+
+fn resolve_settings(opts: Checkpoint) -> Result<Settings> {
     let Checkpoint {
-        image_url, num_shards, cpu_budget, passphrase_file,
-        preserved_paths, leave_running, app_name: _, verbose: _,
+        image_url, config: config_path, num_shards, cpu_budget, passphrase_file,
+        preserved_paths, preserve_options, incremental, upload_log, ..
     } = opts;
 
-    // We override TMPDIR with a safe location. The uploader (or metrics CLI)
-    // may create a tmp file (e.g., bash script using here documents). This
-    // would cause tar to fail as it detects changes in /tmp.
-    // `NO_PRESERVE_FF_DIR` is excluded from the list of paths to preserve.
-    std::env::set_var("TMPDIR", &*NO_PRESERVE_FF_DIR);
-
     let mut preserved_paths: HashSet<_> = preserved_paths.into_iter().collect();
 
     let config = AppConfig::restore()?;
 
+    // The config file given with --config replaces the one of the run command.
+    // The environment must be set before accessing the store.
+    let explicit_config_file = config_path.as_deref().map(ConfigFile::load).transpose()?;
+    let config_file = explicit_config_file.clone().unwrap_or_else(|| config.config_file.clone());
+    config_file.apply_env();
+
     // If the image_url is not supplied, we use the one that we stashed during
     // the run operation.
-    let image_url = ImageUrl::parse(&image_url.unwrap_or(config.image_url))?;
+    let image_url = image_url
+        .or_else(|| explicit_config_file.and_then(|c| c.image_url))
+        .unwrap_or_else(|| config.image_url.clone());
+    let image_url = ImageUrl::parse(&image_url)?;
 
     // As for preserved_paths, we join all the paths we know of.
     // There is the downside of not being able to forget a path that was once preserved.
     // The upside is that is less prone to bugs for users.
-    preserved_paths.extend(config.preserved_paths);
+    preserved_paths.extend(config.preserved_paths.iter().cloned());
+    preserved_paths.extend(config_file.preserve_path.iter().cloned());
+    let preserve_options = preserve_options
+        .merge(config_file.preserve_options()?)
+        .merge(config.preserve_options.clone());
 
     // For the passphrase_file, we take the one provided, or the one specified in
     // a previous operation. This means that once we use encryption, there is no
@@ -128,22 +258,123 @@ pub fn do_checkpoint(opts: Checkpoint) -> Result<Stats> {
     // Note that if the passphrase file is contained in the preserved_paths,
     // we'll include it. It would be a little odd, but not necessarily harmful.
     // We won't emit a warning if that's the case.
-    let passphrase_file = passphrase_file.or(config.passphrase_file);
+    let passphrase_file = passphrase_file
+        .or_else(|| config_file.passphrase_file.clone())
+        .or_else(|| config.passphrase_file.clone());
     if let Some(ref passphrase_file) = passphrase_file {
         check_passphrase_file_exists(passphrase_file)?;
     }
 
+    let num_shards = num_shards.or(config_file.checkpoint.num_shards).unwrap_or(DEFAULT_NUM_SHARDS);
+    ensure!(num_shards > 0, "--num-shards must be positive");
+    let cpu_budget = cpu_budget.or(config_file.cpu_budget()?).unwrap_or(CpuBudget::Medium);
+    let incremental = incremental || config_file.checkpoint.incremental;
+    let upload_log = upload_log || config_file.checkpoint.upload_log;
+
+    Ok(Settings {
+        image_url, preserved_paths, preserve_options, passphrase_file,
+        num_shards, cpu_budget, incremental, upload_log, config_file, config,
+    })
+}
+
+pub fn do_checkpoint(opts: Checkpoint) -> Result<Stats> {
+    // The process may serve many checkpoints (see the control API), so we
+    // can't use START_TIME to measure the duration of this one.
+    let started_at = Instant::now();
+    let leave_running = opts.leave_running;
+
+    // We override TMPDIR with a safe location. The uploader (or metrics CLI)
+    // may create a tmp file (e.g., bash script using here documents). This
+    // would cause TAR_CMD to fail as it detects changes in /tmp.
+    // `NO_PRESERVE_FF_DIR` is excluded from the list of paths to preserve.
+    std::env::set_var("TMPDIR", &*NO_PRESERVE_FF_DIR);
+
+    let Settings {
+        image_url, preserved_paths, preserve_options, passphrase_file,
+        num_shards, cpu_budget, incremental, upload_log, config_file, config,
+    } = resolve_settings(opts)?;
+
+    // The open files are archived along with the preserved paths, but they are
+    // not saved in the app config. The application is not frozen yet, but files
+    // opened in the meantime are unlikely to matter.
+    let mut archived_paths = preserved_paths.clone();
+    if preserve_options.preserve_open_files {
+        let open_files = filesystem::find_open_files(&preserved_paths, &preserve_options)?;
+        for path in &open_files {
+            debug!("Preserving open file {}", path.display());
+        }
+        archived_paths.extend(open_files);
+    }
+
+    // We'd rather fail before freezing the application than produce a bloated image.
+    preserve_options.check_size(&archived_paths)?;
+    criu::check_ghost_files(&criu::ghost_files()?)?;
+
     // The manifest contains the name of the shards, which are generated at random.
     // We combine it with the store to generate the shard upload commands.
     // A shard upload command is of the form:
     //     "lz4 -1 - - | aws s3 cp - s3://bucket/img/XXXXXX.ffs"
-    let img_manifest = ImageManifest::new(
+    let mut img_manifest = ImageManifest::new(
         num_shards, passphrase_file.is_some(), cpu_budget.into());
+    trace::set_root_attribute("fastfreeze.shard_prefix", json!(img_manifest.shard_prefix));
 
+    let mut phases = PhaseTimer::new("checkpoint");
+    phases.start("store_prepare");
     let store = image_url.store();
     store.prepare(true)?;
+
+    // With incremental checkpoints, the file system goes into a layer stored
+    // next to the shards. It builds on the layers of the previous image if we can.
+    // On restore, the base layers are decoded with the codec of the new manifest,
+    // and they must still be part of the image, which `image convert` changes.
+    let base_fs_layers = if incremental {
+        ensure!(!filesystem::has_external_tar(), "--incremental cannot be used with TAR_CMD");
+        let base = match FsLayers::load()? {
+            None => None,
+            Some(base) if base.image_url != image_url.to_string() => {
+                info!("Taking a full file system snapshot, the image URL changed");
+                None
+            }
+            Some(base) if base.layers.len() >= MAX_FS_LAYERS => {
+                info!("Taking a full file system snapshot, the image has {} layers", base.layers.len());
+                None
+            }
+            Some(base) => match ImageManifest::fetch_from_store(&*store, false)? {
+                ManifestFetchResult::Some(current) if current.fs_layers != base.layers => {
+                    info!("Taking a full file system snapshot, the layers of the image changed");
+                    None
+                }
+                ManifestFetchResult::Some(current) if current.compression != img_manifest.compression ||
+                                                      current.encryption != img_manifest.encryption => {
+                    info!("Taking a full file system snapshot, the compression or encryption changed");
+                    None
+                }
+                ManifestFetchResult::Some(_) => Some(base),
+                _ => {
+                    info!("Taking a full file system snapshot, the previous image is gone");
+                    None
+                }
+            }
+        };
+        img_manifest.fs_layers = base.as_ref().map(|b| b.layers.clone()).unwrap_or_default();
+        img_manifest.fs_layers.push(shard::fs_layer_filename(&img_manifest.shard_prefix));
+        base
+    } else {
+        None
+    };
+
     let shard_upload_cmds = shard::upload_cmds(
         &img_manifest, passphrase_file.as_ref(), &*store)?;
+    let fs_layer_upload_cmd = match img_manifest.fs_layers.last() {
+        Some(fs_layer) => Some(shard::retried_upload_cmd(
+            &img_manifest, passphrase_file.as_ref(), &*store, fs_layer)?),
+        None => None,
+    };
+    let log_upload_cmd = match upload_log {
+        true => Some(shard::retried_upload_cmd(&img_manifest, passphrase_file.as_ref(), &*store,
+            &shard::log_filename(&img_manifest.shard_prefix))?),
+        false => None,
+    };
 
     // We emit a "checkpoint_start" event to make it easier to track down
     // containers that vanish during checkpoints. We don't wait for the metrics
@@ -159,8 +390,9 @@ pub fn do_checkpoint(opts: Checkpoint) -> Result<Stats> {
     }
 
     // `pgrp` monitors all our child processes. If one fails, the whole group fails
+    phases.start("streamer_init");
     let mut pgrp = ProcessGroup::new()?;
-    let mut img_streamer = ImageStreamer::spawn_capture(num_shards as usize)?;
+    let mut img_streamer = ImageStreamer::spawn_capture(num_shards as usize, &CRIU_SOCKET_DIR)?;
     img_streamer.process.join(&mut pgrp);
 
     // Spawn the upload processes connected to the image streamer's output
@@ -178,6 +410,7 @@ pub fn do_checkpoint(opts: Checkpoint) -> Result<Stats> {
     // Spawn the CRIU dump process. CRIU sends the image to the image streamer.
     // CRIU will leave the application in a stopped state when done,
     // so that we can continue tarring the filesystem.
+    phases.start("time_to_freeze");
     let criu_ps = criu::criu_dump_cmd()
         .enable_stderr_logging("criu")
         .spawn()?
@@ -197,7 +430,7 @@ pub fn do_checkpoint(opts: Checkpoint) -> Result<Stats> {
     let mut img_streamer_progress = img_streamer.progress;
     let img_streamer_tar_fs_pipe = img_streamer.tar_fs_pipe;
 
-    let stats = || -> Result<Stats> {
+    let (stats, fs_index) = || -> Result<(Stats, Option<FileIndex>)> {
         // We want to start dumping the file system ASAP, but we must wait for the
         // application to be stopped by CRIU, otherwise the filesystem might still
         // be changing under us. We wait for the "checkpoint-start" message from the
@@ -219,6 +452,8 @@ pub fn do_checkpoint(opts: Checkpoint) -> Result<Stats> {
             }
         }
         debug!("Checkpoint started, application is frozen");
+        let frozen_at = Instant::now();
+        phases.start("fs_tar");
 
         {
             // We save the current time of the application so we can resume time
@@ -231,6 +466,7 @@ pub fn do_checkpoint(opts: Checkpoint) -> Result<Stats> {
             let config = AppConfig {
                 image_url: image_url.to_string(),
                 preserved_paths: preserved_paths.clone(),
+                preserve_options: preserve_options.clone(),
                 passphrase_file,
                 app_clock,
                 // Ideally, we want the clock time once the checkpoint has ended,
@@ -240,33 +476,69 @@ pub fn do_checkpoint(opts: Checkpoint) -> Result<Stats> {
                 // For now, we have the time at which the checkpoint started.
                 created_at: SystemTime::now(),
                 inherited_resources,
+                config_file,
             };
             config.save()?;
         }
 
-        // We dump the filesystem with tar. The stdout of tar connects to
-        // criu-image-streamer, which incorporates the tarball into the checkpoint
-        // image.
+        // We dump the filesystem as a tarball into criu-image-streamer, which
+        // incorporates it into the checkpoint image.
         // Note that CRIU can complete at any time, but it leaves the application in
         // a stopped state, so the filesystem remains consistent.
         debug!("Dumping filesystem");
-        let tar_ps = filesystem::tar_cmd(preserved_paths, img_streamer_tar_fs_pipe.unwrap())
-            .enable_stderr_logging("tar")
-            .spawn()?
-            .join(&mut pgrp);
-        pgrp.get_mut(tar_ps).wait()?; // wait for tar to finish
-
-        pgrp.try_wait_for_success()?; // if tar errored, this is where we exit
+        let tar_fs_pipe = img_streamer_tar_fs_pipe.unwrap();
+        let fs_archive = if filesystem::has_external_tar() {
+            let tar_ps = filesystem::tar_cmd(&archived_paths, &preserve_options, tar_fs_pipe)
+                .enable_stderr_logging("tar")
+                .spawn()?
+                .join(&mut pgrp);
+            pgrp.wait_for_exit(tar_ps)?; // wait for tar to finish
+            Ok(None)
+        } else if let Some(ref upload_cmd) = fs_layer_upload_cmd {
+            filesystem::write_empty_archive(tar_fs_pipe)?;
+            let mut upload_ps = Command::new_shell(upload_cmd)
+                .stdin(Stdio::piped())
+                .enable_stderr_logging("upload fs layer")
+                .spawn()?;
+            // unwrap() is safe, stdin is piped
+            let upload_stdin = upload_ps.take_stdin().unwrap();
+            upload_ps.join(&mut pgrp);
+            let base_index = base_fs_layers.as_ref().map(|b| &b.index);
+            filesystem::archive(&archived_paths, &preserve_options, base_index, upload_stdin).map(Some)
+        } else {
+            filesystem::archive(&archived_paths, &preserve_options, None, tar_fs_pipe).map(Some)
+        };
+
+        // If the archiving failed because the streamer died, we'd rather report
+        // the streamer errors. If tar errored, this is where we exit.
+        pgrp.try_wait_for_success()?;
+        let (fs_stats, fs_index) = match fs_archive? {
+            Some((fs_stats, fs_index)) => (Some(fs_stats), Some(fs_index)),
+            None => (None, None),
+        };
+        if let Some(ref fs_stats) = fs_stats {
+            fs_stats.show();
+        }
         // We print this debug message so that in the logs, we can have a timestamp
         // to tell us how long it took. Maybe it would be better to have a metric event.
         debug!("Filesystem dumped. Finishing dumping processes");
 
+        // We wait for CRIU on its own first, to tell the time spent dumping
+        // memory apart from the time spent uploading the rest of the shards.
+        phases.start("criu_dump");
+        pgrp.wait_for_exit(criu_ps)?;
+
         // Wait for checkpoint to complete
+        phases.start("shard_upload_tail");
         pgrp.wait_for_success()?;
 
-        let stats = img_streamer_progress.wait_for_stats()?;
+        let mut stats = img_streamer_progress.wait_for_stats()?;
         stats.show();
-        Ok(stats)
+        stats.filesystem = fs_stats;
+        // CRIU is done, the application is about to be resumed or killed.
+        stats.time_frozen_sec = Some(frozen_at.elapsed().as_secs_f64());
+        phases.end();
+        Ok((stats, fs_index))
     }().map_err(|e| {
         // Something went sideways while checkpointing (reading the file system?
         // uploading the image?).
@@ -302,39 +574,235 @@ pub fn do_checkpoint(opts: Checkpoint) -> Result<Stats> {
         // We kill the app later, once metrics are emitted.
     }
 
+    // The log is uploaded before the manifest so that the manifest can refer to it.
+    // A missing log is not worth failing the checkpoint for.
+    if let Some(ref upload_cmd) = log_upload_cmd {
+        phases.start("log_upload");
+        match upload_log_file(upload_cmd) {
+            Ok(true) => img_manifest.log = Some(shard::log_filename(&img_manifest.shard_prefix)),
+            Ok(false) => warn!("No log file to upload, the log file is disabled"),
+            Err(e) => warn!("{:#}", e),
+        }
+    }
+
     // At this point, all the shards are written successfully. We can now write
     // the manifest file to the store. The manifest file existence indicates
     // whether the image exists, so it must be written at the very end.
     debug!("Writing image manifest");
+    phases.start("manifest_write");
+    img_manifest.completed_at = Some(SystemTime::now());
+    img_manifest.duration_sec = Some(started_at.elapsed().as_secs_f64());
+    img_manifest.stats = Some(Box::new(stats));
     img_manifest.persist_to_store(&*store)
         .with_context(|| format!("Failed to upload image manifest at {}", image_url))?;
+    phases.end();
 
-    info!("Checkpoint completed in {:.1}s", START_TIME.elapsed().as_secs_f64());
+    // The image is committed. Failing to cleanup older generations is not fatal.
+    if let Err(e) = store.on_generation_selected(&img_manifest) {
+        warn!("{:#}", e);
+    }
 
-    Ok(stats)
+    // The next incremental checkpoint builds on the layers of this image.
+    let fs_layers_result = match fs_index {
+        Some(index) if incremental => FsLayers {
+            image_url: image_url.to_string(),
+            layers: img_manifest.fs_layers.clone(),
+            index,
+        }.save(),
+        _ => FsLayers::remove(),
+    };
+    if let Err(e) = fs_layers_result {
+        warn!("{:#}. The next incremental checkpoint will take a full snapshot", e);
+        let _ = FsLayers::remove();
+    }
+
+    info!("Checkpoint completed in {:.1}s", started_at.elapsed().as_secs_f64());
+
+    // unwrap() is safe, the manifest has all the fields of the record.
+    let last_checkpoint = LastCheckpoint::from_manifest(image_url.to_string(), &img_manifest).unwrap();
+    if let Err(e) = last_checkpoint.save() {
+        warn!("{:#}", e);
+    }
+
+    Ok(last_checkpoint.stats)
+}
+
+/// Uploads the log file of this command with `upload_cmd`. Its rotated
+/// segments come first. Returns false when we don't log to a file.
+fn upload_log_file(upload_cmd: &str) -> Result<bool> {
+    let paths = match logger::flushed_log_file_paths() {
+        Some(paths) => paths,
+        None => return Ok(false),
+    };
+    let mut upload_ps = Command::new_shell(upload_cmd)
+        .stdin(Stdio::piped())
+        .enable_stderr_logging("upload log")
+        .spawn()?;
+    // unwrap() is safe, stdin is piped.
+    let mut upload_stdin = upload_ps.take_stdin().unwrap();
+    let copy_result = paths.iter().try_for_each(|path| -> Result<()> {
+        let mut file = fs::File::open(path)
+            .with_context(|| format!("Failed to open the log file {}", path.display()))?;
+        io::copy(&mut file, &mut upload_stdin)
+            .with_context(|| format!("Failed to upload the log file {}", path.display()))?;
+        Ok(())
+    });
+    drop(upload_stdin);
+    // The upload error comes first, as it explains a failed copy.
+    upload_ps.wait_for_success().context("Failed to upload the log file")?;
+    copy_result?;
+    Ok(true)
+}
+
+/// Returns the memory used by the application, which is roughly what CRIU dumps.
+/// Shared pages are accounted proportionally (PSS) when the kernel tells us.
+fn app_memory_size() -> Result<u64> {
+    let mut size_kb = 0;
+    for pid in get_process_tree(Pid::from_raw(APP_ROOT_PID))? {
+        let proc_dir = Path::new("/proc").join(pid.to_string());
+        // smaps_rollup is available since Linux 4.14. The process may be gone.
+        let (content, key) = match fs::read_to_string(proc_dir.join("smaps_rollup")) {
+            Ok(content) => (content, "Pss:"),
+            Err(_) => (fs::read_to_string(proc_dir.join("status")).unwrap_or_default(), "VmRSS:"),
+        };
+        // Lines are of the form "Pss:    1234 kB"
+        size_kb += content.lines()
+            .find_map(|line| line.strip_prefix(key))
+            .and_then(|v| v.trim().trim_end_matches("kB").trim().parse::<u64>().ok())
+            .unwrap_or(0);
+    }
+    Ok(size_kb * KB as u64)
+}
+
+/// Shows what a checkpoint would do, and validates the configuration without
+/// checkpointing: the image store must be writable, and CRIU must be able to
+/// checkpoint the application.
+fn do_checkpoint_dry_run(opts: Checkpoint) -> Result<()> {
+    // See do_checkpoint()
+    std::env::set_var("TMPDIR", &*NO_PRESERVE_FF_DIR);
+
+    let Settings {
+        image_url, preserved_paths, preserve_options, passphrase_file,
+        num_shards, cpu_budget, ..
+    } = resolve_settings(opts)?;
+    let open_files = filesystem::find_open_files(&preserved_paths, &preserve_options)?;
+
+    info!("Image URL: {}", image_url);
+    let mut preserved_paths: Vec<_> = preserved_paths.iter().collect();
+    preserved_paths.sort();
+    info!("Preserved paths:");
+    for path in &preserved_paths {
+        info!("    {}", path.display());
+    }
+
+    if open_files.is_empty() {
+        info!("The application has no open files outside of the preserved paths");
+    } else {
+        if preserve_options.preserve_open_files {
+            info!("Open files, preserved with --preserve-open-files:");
+        } else {
+            info!("Open files, not preserved. Use --preserve-path or --preserve-open-files to preserve them:");
+        }
+        for path in &open_files {
+            let size = fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+            info!("    {} ({:.1} MiB)", path.display(), size as f64 / MB as f64);
+        }
+    }
+
+    let ghost_files = criu::ghost_files()?;
+    if !ghost_files.is_empty() {
+        info!("Deleted files, saved in the image:");
+        for file in &ghost_files {
+            match file.size {
+                Some(size) => info!("    {} ({:.1} MiB)", file.path.display(), size as f64 / MB as f64),
+                None => info!("    {} (unknown size)", file.path.display()),
+            }
+        }
+    }
+
+    let mut archived_paths: HashSet<_> = preserved_paths.into_iter().cloned().collect();
+    if preserve_options.preserve_open_files {
+        archived_paths.extend(open_files);
+    }
+    preserve_options.check_size(&archived_paths)?;
+    criu::check_ghost_files(&ghost_files)?;
+
+    let memory_size = app_memory_size()?;
+    let fs_size = preserve_options.preserved_size(&archived_paths)?;
+    info!("Estimated image size before compression: {:.1} MiB (memory: {:.1} MiB, file system: {:.1} MiB)",
+          (memory_size + fs_size) as f64 / MB as f64,
+          memory_size as f64 / MB as f64, fs_size as f64 / MB as f64);
+
+    // We build the upload commands as a checkpoint would, which validates the
+    // image settings. The probe file checks that we have write access, and is
+    // deleted right away. Its name is unique, so concurrent dry runs don't race.
+    let img_manifest = ImageManifest::new(
+        num_shards, passphrase_file.is_some(), cpu_budget.into());
+    let store = image_url.store();
+    store.prepare(true)?;
+    for upload_cmd in shard::upload_cmds(&img_manifest, passphrase_file.as_ref(), &*store)? {
+        debug!("Shard upload command: {}", upload_cmd);
+    }
+    let probe_file = store.file(&format!("{}-{}", STORE_PROBE_FILE_NAME, *INVOCATION_ID));
+    probe_file.write("probe", &[])
+        .with_context(|| format!("Failed to write to {}", image_url))?;
+    probe_file.delete("probe")
+        .with_context(|| format!("Failed to delete the probe file from {}", image_url))?;
+    info!("The image store is writable");
+
+    criu::criu_dump_check_cmd()
+        .enable_stderr_logging("criu")
+        .spawn()?
+        .wait_for_success()
+        .context("CRIU cannot checkpoint the application")?;
+    info!("The application can be checkpointed");
+
+    Ok(())
 }
 
 impl super::CLI for Checkpoint {
     fn run(self) -> Result<()> {
         container::maybe_nsenter_app(self.app_name.as_ref())?;
 
+        if self.dry_run {
+            // CRIU freezes the application briefly, we can't have a checkpoint running.
+            return with_checkpoint_restore_lock(|| do_checkpoint_dry_run(self));
+        }
+
+        self.lock_and_checkpoint_app()
+    }
+}
+
+impl Checkpoint {
+    /// Takes the checkpoint/restore lock, and checkpoints the application.
+    /// The time spent waiting for the lock is reported as the `lock_wait` phase.
+    pub fn lock_and_checkpoint_app(self) -> Result<()> {
         // Holding the lock while invoking the metrics CLI is preferable to avoid
         // disturbing another instance trying to do PID control.
+        let lock_requested_at = Instant::now();
         with_checkpoint_restore_lock(|| {
-            let leave_running = self.leave_running;
-            with_metrics("checkpoint",
-                || do_checkpoint(self),
-                |stats| json!({"stats": stats}))?;
-
-            // We kill the app after the metrics are emitted. Killing the app
-            // risk terminating the container, preventing metrics from being emitted.
-            if !leave_running {
-                debug!("Killing application");
-                kill_process_tree(Pid::from_raw(APP_ROOT_PID), signal::SIGKILL)
-                    .context("Failed to kill application")?;
-            }
-
-            Ok(())
+            record_phase("checkpoint", "lock_wait", lock_requested_at.elapsed());
+            self.checkpoint_app()
         })
     }
+
+    /// Checkpoints the application, and kills it unless `leave_running` is set.
+    /// The caller must have entered the application namespaces, and must hold
+    /// the checkpoint/restore lock.
+    pub fn checkpoint_app(self) -> Result<()> {
+        let leave_running = self.leave_running;
+        with_metrics("checkpoint",
+            || do_checkpoint(self),
+            |stats| json!({"stats": stats}))?;
+
+        // We kill the app after the metrics are emitted. Killing the app
+        // risk terminating the container, preventing metrics from being emitted.
+        if !leave_running {
+            debug!("Killing application");
+            kill_process_tree(Pid::from_raw(APP_ROOT_PID), signal::SIGKILL)
+                .context("Failed to kill application")?;
+        }
+
+        Ok(())
+    }
 }
@@ -0,0 +1,234 @@
+//  Copyright 2020 Two Sigma Investments, LP.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+use anyhow::{Result, Context};
+use std::{
+    collections::BTreeMap,
+    ffi::OsString,
+    fs,
+    path::{Path, PathBuf},
+};
+use serde::{Serialize, Deserialize};
+use crate::{
+    consts::*,
+    filesystem::PreserveOptions,
+    image::CpuBudget,
+    store::{ImageUrl, FileExt},
+};
+
+// The config file holds the options of the run, checkpoint, and extract
+// commands, so that they don't have to be repeated on each invocation.
+// It is given with --config, or found next to the image as fastfreeze.toml
+// or fastfreeze.json. The keys are the names of the command line options.
+// Options given on the command line take precedence, and so do environment
+// variables. For example:
+//
+//     image-url = "s3://bucket/app"
+//     preserve-path = ["/data"]
+//
+//     [env]
+//     S3_CMD = "aws s3 --endpoint-url http://minio:9000"
+//     CRIU_OPTS = "--tcp-close"
+//
+//     [run]
+//     command = ["python", "app.py"]
+//
+//     [checkpoint]
+//     num-shards = 8
+//
+// The run command persists the config in the app config, so that the
+// checkpoint command uses it as well.
+//
+// A config found next to the image is read through the store, which reads the
+// store environment variables (e.g., S3_CMD) once, and keeps them. Such a config
+// cannot set them, and we refuse it rather than silently ignoring them.
+
+#[derive(Serialize, Deserialize, Default, Clone, PartialEq, Debug)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ConfigFile {
+    /// Environment variables, e.g., S3_CMD, CRIU_OPTS, or FF_APP_PATH
+    pub env: BTreeMap<String, String>,
+    pub image_url: Option<String>,
+    pub passphrase_file: Option<PathBuf>,
+    pub preserve_path: Vec<PathBuf>,
+    pub preserve_exclude: Vec<String>,
+    pub preserve_max_size: Option<u64>,
+    pub preserve_max_size_action: Option<String>,
+    pub preserve_open_files: bool,
+    pub run: RunConfig,
+    pub checkpoint: CheckpointConfig,
+    pub extract: ExtractConfig,
+}
+
+#[derive(Serialize, Deserialize, Default, Clone, PartialEq, Debug)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct RunConfig {
+    /// Application command, used when running the app from scratch
+    pub command: Vec<String>,
+    pub app_name: Option<String>,
+    pub on_app_ready: Option<String>,
+    pub tcp_listen_remap: Vec<String>,
+    pub no_container: bool,
+    pub control_listen: Option<String>,
+    pub metrics_listen: Option<String>,
+    pub metrics_textfile: Option<PathBuf>,
+}
+
+#[derive(Serialize, Deserialize, Default, Clone, PartialEq, Debug)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct CheckpointConfig {
+    pub num_shards: Option<u32>,
+    pub cpu_budget: Option<String>,
+    pub incremental: bool,
+    pub upload_log: bool,
+}
+
+#[derive(Serialize, Deserialize, Default, Clone, PartialEq, Debug)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ExtractConfig {
+    pub output_dir: Option<PathBuf>,
+    pub allow_bad_image_version: bool,
+}
+
+/// Returns whether the environment variable is read when accessing the store.
+fn is_store_env_var(key: &str) -> bool {
+    key == "S3_CMD" || key == "GS_CMD" ||
+        ["FF_IMAGE_", "FF_S3_", "FF_GS_", "FF_FILE_"].iter().any(|prefix| key.starts_with(prefix))
+}
+
+impl RunConfig {
+    pub fn app_args(&self) -> Vec<OsString> {
+        self.command.iter().map(OsString::from).collect()
+    }
+}
+
+impl ConfigFile {
+    /// Parses a TOML or JSON config file.
+    pub fn parse(content: &str) -> Result<Self> {
+        // A JSON config is an object. A TOML document cannot start with '{'.
+        if content.trim_start().starts_with('{') {
+            serde_json::from_str(content).context("Malformed JSON config")
+        } else {
+            toml::from_str(content).context("Malformed TOML config")
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        Self::parse(&content)
+            .with_context(|| format!("Failed to parse {}", path.display()))
+    }
+
+    /// Looks for a config file next to the image.
+    pub fn discover(image_url: &ImageUrl) -> Result<Option<Self>> {
+        let store = image_url.store();
+        for filename in CONFIG_FILE_NAMES {
+            if let Some(content) = store.file(filename).try_read("download config")? {
+                debug!("Using config file {} of {}", filename, image_url);
+                let config = Self::parse(&String::from_utf8_lossy(&content))
+                    .with_context(|| format!("Failed to parse {} of {}", filename, image_url))?;
+                if let Some(key) = config.env.keys().find(|k| is_store_env_var(k)) {
+                    bail!("{} of {} cannot set {}, which is needed to read it. \
+                           Set it in the environment, or use --config", filename, image_url, key);
+                }
+                return Ok(Some(config));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Returns the config given with `--config`, or the one found next to the image.
+    pub fn load_or_discover(path: Option<&Path>, image_url: &ImageUrl) -> Result<Option<Self>> {
+        match path {
+            Some(path) => Self::load(path).map(Some),
+            None => Self::discover(image_url),
+        }
+    }
+
+    /// Sets the environment variables of the config that are not already set.
+    /// It must be called before the environment is read (e.g., S3_CMD is read
+    /// when accessing the store for the first time).
+    pub fn apply_env(&self) {
+        for (key, value) in &self.env {
+            if std::env::var_os(key).is_none() {
+                std::env::set_var(key, value);
+            }
+        }
+    }
+
+    pub fn preserve_options(&self) -> Result<PreserveOptions> {
+        Ok(PreserveOptions {
+            excludes: self.preserve_exclude.clone(),
+            max_size_mb: self.preserve_max_size,
+            max_size_action: self.preserve_max_size_action.as_ref()
+                .map(|action| action.parse()).transpose()
+                .context("Invalid preserve-max-size-action in config")?,
+            preserve_open_files: self.preserve_open_files,
+        })
+    }
+
+    pub fn cpu_budget(&self) -> Result<Option<CpuBudget>> {
+        self.checkpoint.cpu_budget.as_ref()
+            .map(|budget| budget.parse()).transpose()
+            .context("Invalid cpu-budget in config")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() -> Result<()> {
+        let toml_config = ConfigFile::parse(r#"
+            image-url = "s3://bucket/app"
+            preserve-path = ["/data"]
+            [env]
+            S3_CMD = "aws s3"
+            [run]
+            command = ["sleep", "10"]
+            [checkpoint]
+            num-shards = 8
+        "#)?;
+        let json_config = ConfigFile::parse(r#"{
+            "image-url": "s3://bucket/app",
+            "preserve-path": ["/data"],
+            "env": {"S3_CMD": "aws s3"},
+            "run": {"command": ["sleep", "10"]},
+            "checkpoint": {"num-shards": 8}
+        }"#)?;
+        assert_eq!(toml_config, json_config);
+        assert_eq!(toml_config.checkpoint.num_shards, Some(8));
+        assert_eq!(toml_config.cpu_budget()?, None);
+
+        assert!(ConfigFile::parse("image_url = \"s3://bucket/app\"").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_discover_rejects_store_env() -> Result<()> {
+        let dir = PathBuf::from("/tmp/ff-test-config-discover");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir)?;
+        let image_url = ImageUrl::parse(&format!("file:{}", dir.display()))?;
+
+        fs::write(dir.join("fastfreeze.toml"), "[env]\nCRIU_OPTS = \"--tcp-close\"\n")?;
+        assert!(ConfigFile::discover(&image_url)?.is_some());
+
+        fs::write(dir.join("fastfreeze.toml"), "[env]\nS3_CMD = \"aws s3\"\n")?;
+        assert!(ConfigFile::discover(&image_url).is_err());
+        Ok(())
+    }
+}
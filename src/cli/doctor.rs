@@ -0,0 +1,305 @@
+//  Copyright 2020 Two Sigma Investments, LP.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+use anyhow::Result;
+use std::{
+    collections::BTreeSet,
+    fs,
+    path::Path,
+};
+use nix::unistd::Pid;
+use caps::{CapSet, Capability};
+use structopt::StructOpt;
+use serde::Serialize;
+use crate::{
+    consts::*,
+    container::{self, NSCapabilities},
+    criu,
+    process::Stdio,
+    signal::get_process_tree,
+    virt,
+};
+use super::install::is_ff_installed;
+
+/// Check whether the environment, and the running application, can be checkpointed
+#[derive(StructOpt, PartialEq, Debug, Serialize)]
+pub struct Doctor {
+    /// Print the findings in JSON
+    #[structopt(long)]
+    json: bool,
+
+    /// Verbosity. Can be repeated
+    #[structopt(short, long, parse(from_occurrences))]
+    pub verbose: u8,
+
+    /// Check the specified application. See the run command help about
+    /// --app-name for more details.
+    #[structopt()]
+    app_name: Option<String>,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Severity {
+    Ok,
+    Warning,
+    Error,
+}
+
+#[derive(Serialize, Debug)]
+struct Finding {
+    check: &'static str,
+    severity: Severity,
+    message: String,
+    /// What to do about it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hint: Option<String>,
+}
+
+#[derive(Default)]
+struct Findings(Vec<Finding>);
+
+impl Findings {
+    fn add(&mut self, check: &'static str, severity: Severity, message: String, hint: Option<&str>) {
+        self.0.push(Finding { check, severity, message, hint: hint.map(Into::into) });
+    }
+
+    fn ok(&mut self, check: &'static str, message: impl Into<String>) {
+        self.add(check, Severity::Ok, message.into(), None);
+    }
+
+    fn warn(&mut self, check: &'static str, message: impl Into<String>, hint: &str) {
+        self.add(check, Severity::Warning, message.into(), Some(hint));
+    }
+
+    fn error(&mut self, check: &'static str, message: impl Into<String>, hint: &str) {
+        self.add(check, Severity::Error, message.into(), Some(hint));
+    }
+
+    fn show(&self) {
+        for finding in &self.0 {
+            let tag = match finding.severity {
+                Severity::Ok      => "[  OK  ]",
+                Severity::Warning => "[ WARN ]",
+                Severity::Error   => "[ FAIL ]",
+            };
+            println!("{} {}: {}", tag, finding.check, finding.message);
+            if let Some(ref hint) = finding.hint {
+                println!("         {}", hint);
+            }
+        }
+    }
+}
+
+fn read_proc_file(path: &str) -> Option<String> {
+    fs::read_to_string(path).ok().map(|s| s.trim().to_string())
+}
+
+fn check_namespaces(f: &mut Findings, nscaps: &Result<NSCapabilities>) {
+    match nscaps {
+        Ok(NSCapabilities::Full) => f.ok("namespaces", "User, mount, and PID namespaces are available"),
+        Ok(NSCapabilities::MountOnly) => f.warn("namespaces",
+            "PID namespaces are not available. Running multiple applications is not supported, \
+             and controlling PIDs may be slow",
+            "This is typical of Kubernetes, where /proc is read-only protected"),
+        Ok(NSCapabilities::None) => match is_ff_installed() {
+            Ok(true) => f.ok("namespaces", "Namespaces are not available, but FastFreeze is installed"),
+            Ok(false) => f.error("namespaces", "Namespaces are not available, and FastFreeze is not installed",
+                "Run `fastfreeze install`, e.g., when building the docker image"),
+            Err(e) => f.error("namespaces", format!("{:#}", e), "Run `fastfreeze install`"),
+        },
+        Err(e) => f.error("namespaces", format!("Failed to probe namespaces: {:#}", e),
+            "Check that fork() and unshare() are permitted"),
+    }
+}
+
+fn check_ptrace(f: &mut Findings, has_pid_ns: bool) {
+    // In our own user namespace, CRIU has CAP_SYS_PTRACE over the application.
+    let can_ptrace = has_pid_ns ||
+        caps::has_cap(None, CapSet::Effective, Capability::CAP_SYS_PTRACE).unwrap_or(false);
+
+    match read_proc_file("/proc/sys/kernel/yama/ptrace_scope").as_deref() {
+        None | Some("0") => f.ok("ptrace", "ptrace is not restricted"),
+        Some("3") => f.error("ptrace", "ptrace is disabled (YAMA ptrace_scope=3), CRIU cannot work",
+            "ptrace_scope=3 can only be lowered by rebooting the machine"),
+        Some(scope) if can_ptrace => f.ok("ptrace", format!(
+            "ptrace is restricted (YAMA ptrace_scope={}), but we have CAP_SYS_PTRACE", scope)),
+        Some(scope) => f.error("ptrace", format!(
+            "ptrace is restricted (YAMA ptrace_scope={}). CRIU will fail with \"Unable to interrupt task\"", scope),
+            "Set kernel.yama.ptrace_scope=0, or grant CAP_SYS_PTRACE"),
+    }
+}
+
+fn check_pid_control(f: &mut Findings, has_pid_ns: bool) {
+    // In our own PID namespace, PIDs are under our control.
+    if has_pid_ns {
+        f.ok("pid_control", "PIDs are controlled in a PID namespace");
+        return;
+    }
+
+    match fs::OpenOptions::new().write(true).open("/proc/sys/kernel/ns_last_pid") {
+        Ok(_) => f.ok("pid_control", "/proc/sys/kernel/ns_last_pid is writable"),
+        Err(_) => {
+            f.warn("pid_control", "/proc/sys/kernel/ns_last_pid is not writable, which can slow down restores",
+                "Upgrade the kernel, or remap your uid to uid=0 with FF_FAKE_ROOT=1");
+
+            if let Some(pid_max) = read_proc_file("/proc/sys/kernel/pid_max").and_then(|s| s.parse::<u64>().ok()) {
+                if pid_max > 100_000 {
+                    f.warn("pid_control", format!("pid_max={} is high, controlling PIDs can be slow", pid_max),
+                        "Set kernel.pid_max lower than 100000");
+                }
+            }
+        }
+    }
+}
+
+fn check_criu(f: &mut Findings) {
+    let output = criu::criu_check_cmd()
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .and_then(|p| p.wait_with_output());
+
+    match output {
+        Ok(output) if output.status.success() => f.ok("criu", "`criu check` succeeded"),
+        Ok(output) => {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            let last_line = stderr.lines().last().unwrap_or_default();
+            f.error("criu", format!("`criu check` failed: {}", last_line),
+                "Run `criu check` for details")
+        }
+        Err(e) => f.error("criu", format!("{:#}", e), "Ensure that criu is in the PATH"),
+    }
+}
+
+fn check_virtualization(f: &mut Findings) {
+    match virt::ensure_system_wide_virtualization_is_enabled() {
+        Ok(()) => f.ok("virtualization", "Time and CPUID virtualization is enabled system wide"),
+        Err(e) => f.error("virtualization", format!("{:#}", e), "Run `fastfreeze install`"),
+    }
+}
+
+/// Looks for the application features that CRIU cannot checkpoint.
+fn check_app(f: &mut Findings) -> Result<()> {
+    let pids = get_process_tree(Pid::from_raw(APP_ROOT_PID))?;
+    f.ok("app", format!("The application has {} processes", pids.len()));
+
+    let mut unsupported_fds = BTreeSet::new();
+    let mut uses_musl = false;
+    for pid in &pids {
+        let proc_dir = Path::new("/proc").join(pid.to_string());
+
+        let status = fs::read_to_string(proc_dir.join("status")).unwrap_or_default();
+        let tracer_pid = status.lines()
+            .find_map(|line| line.strip_prefix("TracerPid:"))
+            .map(|s| s.trim())
+            .unwrap_or("0");
+        if tracer_pid != "0" {
+            f.error("ptraced", format!("Process {} is traced by process {}", pid, tracer_pid),
+                "Detach the debugger (e.g., strace or gdb) before checkpointing");
+        }
+
+        if let Ok(entries) = fs::read_dir(proc_dir.join("fd")) {
+            for entry in entries.filter_map(|e| e.ok()) {
+                if let Ok(target) = fs::read_link(entry.path()) {
+                    let target = target.to_string_lossy().into_owned();
+                    if UNSUPPORTED_FD_KINDS.iter().any(|kind| target == *kind) {
+                        unsupported_fds.insert(target);
+                    }
+                }
+            }
+        }
+
+        let maps = fs::read_to_string(proc_dir.join("maps")).unwrap_or_default();
+        uses_musl |= maps.contains("/ld-musl-");
+    }
+
+    for kind in unsupported_fds {
+        f.error("fds", format!("The application uses {}, which CRIU cannot checkpoint", kind),
+            "Disable the feature in the application");
+    }
+
+    if uses_musl {
+        f.warn("musl", "The application uses musl libc, which time and CPUID virtualization does not support",
+            "Use a glibc build of the application");
+    }
+
+    let sysv_in_use = ["/proc/sysvipc/shm", "/proc/sysvipc/msg", "/proc/sysvipc/sem"].iter()
+        .filter_map(|path| fs::read_to_string(path).ok())
+        .any(|content| content.lines().count() > 1); // The first line is a header
+    if sysv_in_use {
+        f.warn("sysv_ipc", "System V IPC objects exist. They are not checkpointed",
+            "Use POSIX shared memory or files instead");
+    }
+
+    let ghost_files = criu::ghost_files()?;
+    for file in &ghost_files {
+        if let Some(size) = file.size.filter(|s| *s > *criu::GHOST_FILE_LIMIT) {
+            f.error("deleted_files", format!("The deleted file {} of {:.1} MiB exceeds the limit of {} MiB",
+                                             file.path.display(), size as f64 / MB as f64,
+                                             *criu::GHOST_FILE_LIMIT / MB as u64),
+                "Set FF_GHOST_FILE_LIMIT_MB to raise the limit");
+        }
+    }
+    if !ghost_files.is_empty() {
+        f.ok("deleted_files", format!("{} deleted files are open or mapped, they are saved in the image",
+                                      ghost_files.len()));
+    }
+
+    Ok(())
+}
+
+/// File descriptors kinds that CRIU does not support
+const UNSUPPORTED_FD_KINDS: &[&str] = &[
+    "anon_inode:[io_uring]",
+    "anon_inode:[perf_event]",
+    "anon_inode:[userfaultfd]",
+];
+
+impl super::CLI for Doctor {
+    fn run(self) -> Result<()> {
+        let Self { json, app_name, verbose: _ } = self;
+        let mut f = Findings::default();
+
+        let nscaps = container::ns_capabilities();
+        let has_pid_ns = matches!(nscaps, Ok(NSCapabilities::Full));
+        check_namespaces(&mut f, &nscaps);
+        check_ptrace(&mut f, has_pid_ns);
+        check_pid_control(&mut f, has_pid_ns);
+        check_criu(&mut f);
+
+        match container::maybe_nsenter_app_if_any(app_name.as_ref()) {
+            Ok(true) => {
+                if is_ff_installed().unwrap_or(false) {
+                    check_virtualization(&mut f);
+                }
+                if let Err(e) = check_app(&mut f) {
+                    f.error("app", format!("{:#}", e), "Check that the application is running");
+                }
+            }
+            Ok(false) => f.ok("app", "No application is running, skipping the application checks"),
+            Err(e) => f.error("app", format!("{:#}", e), "Pick an application to check"),
+        }
+
+        if json {
+            println!("{}", serde_json::to_string_pretty(&f.0)?);
+        } else {
+            f.show();
+        }
+
+        let num_errors = f.0.iter().filter(|f| f.severity == Severity::Error).count();
+        ensure!(num_errors == 0, "{} check(s) failed", num_errors);
+        Ok(())
+    }
+}
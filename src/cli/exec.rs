@@ -0,0 +1,118 @@
+//  Copyright 2020 Two Sigma Investments, LP.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+use anyhow::{Result, Context};
+use std::{
+    ffi::OsString,
+    fs,
+};
+use nix::unistd::Pid;
+use structopt::StructOpt;
+use serde::Serialize;
+use crate::{
+    consts::*,
+    container,
+    lock::with_checkpoint_restore_lock,
+    process::{monitor_child, set_ns_last_pid, ChildDied, Command},
+    signal::get_process_tree,
+};
+use super::ExitCode;
+
+/// Run a command in the container of the application
+#[derive(StructOpt, PartialEq, Debug, Serialize)]
+pub struct Exec {
+    /// Attach the command to the terminal of the application
+    #[structopt(long)]
+    tty: bool,
+
+    /// Verbosity. Can be repeated
+    #[structopt(short, long, parse(from_occurrences))]
+    pub verbose: u8,
+
+    /// Run the command in the specified application. See the run command help
+    /// about --app-name for more details.
+    #[structopt()]
+    app_name: Option<String>,
+
+    /// Command to run, e.g., `fastfreeze exec -- bash`
+    #[structopt(last = true, required = true)]
+    cmd: Vec<OsString>,
+}
+
+fn read_ns_last_pid() -> Result<i32> {
+    let path = "/proc/sys/kernel/ns_last_pid";
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path))?;
+    content.trim().parse().with_context(|| format!("Failed to parse {}", path))
+}
+
+/// The command is our child, and not a descendant of APP_ROOT_PID, so it is
+/// not part of checkpoints. Yet, its PID could take a spot in the range of PIDs
+/// that the application occupies. After a restore, ns_last_pid is typically
+/// lower than the application PIDs. We move ns_last_pid past the application
+/// PIDs so that the PIDs of the command do not interleave with the application's.
+fn move_ns_last_pid_past_app() -> Result<()> {
+    let max_app_pid = get_process_tree(Pid::from_raw(APP_ROOT_PID))?.into_iter()
+        .map(|pid| pid.as_raw())
+        .max()
+        .unwrap_or(APP_ROOT_PID);
+
+    if read_ns_last_pid()? < max_app_pid {
+        debug!("Moving ns_last_pid to {}", max_app_pid);
+        set_ns_last_pid(max_app_pid)?;
+    }
+    Ok(())
+}
+
+impl super::CLI for Exec {
+    fn run(self) -> Result<()> {
+        let Self { tty, app_name, cmd, verbose: _ } = self;
+
+        // We enter the namespaces of the application, and fork. We become the
+        // child, and our parent forwards our exit code.
+        container::maybe_nsenter_app(app_name.as_ref())?;
+
+        let mut cmd = Command::new(&cmd);
+        if tty {
+            // CONTAINER_PTY only exists when the application runs with a terminal.
+            ensure!(CONTAINER_PTY.exists(), "The application has no terminal");
+            let open_pty = || fs::OpenOptions::new().read(true).write(true).open(&*CONTAINER_PTY)
+                .with_context(|| format!("Failed to open {}", CONTAINER_PTY.display()));
+            cmd.stdin(open_pty()?)
+               .stdout(open_pty()?)
+               .stderr(open_pty()?);
+        }
+
+        // A restore could otherwise bring back application PIDs between the
+        // ns_last_pid move and the spawn. We hold the lock until the command has
+        // its PID, and not while it runs.
+        let process = with_checkpoint_restore_lock(|| {
+            move_ns_last_pid_past_app()
+                .context("Failed to allocate a PID outside of the application range")?;
+            cmd.spawn()
+        })?;
+        debug!("Command running with pid={}", process.pid());
+
+        // monitor_child() forwards the signals we receive to the command, which
+        // is what we want for interactive commands such as shells.
+        if let Err(e) = monitor_child(Pid::from_raw(process.pid())) {
+            // The command's exit code becomes ours. There's nothing to report.
+            if e.downcast_ref::<ChildDied>().is_some() {
+                std::process::exit(ExitCode::from_error(&e) as i32);
+            }
+            return Err(e);
+        }
+        Ok(())
+    }
+}
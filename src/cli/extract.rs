@@ -12,24 +12,39 @@
 //  See the License for the specific language governing permissions and
 //  limitations under the License.
 
-use anyhow::Result;
-use std::path::PathBuf;
+use anyhow::{Result, Context};
+use std::{
+    path::PathBuf,
+    fs,
+};
 use structopt::StructOpt;
 use serde::Serialize;
 use crate::{
     consts::*,
+    logger::LogFormat,
     store::ImageUrl,
     image::{ManifestFetchResult, ImageManifest, shard, check_passphrase_file_exists},
     process::{Command, ProcessExt, ProcessGroup, Stdio},
     image_streamer::ImageStreamer,
+    trace,
 };
+use super::config::ConfigFile;
 
 /// Extract a FastFreeze image to local disk
 #[derive(StructOpt, PartialEq, Debug, Serialize)]
 #[structopt(after_help("\
 ENVS:
-    S3_CMD   Command to access AWS S3. Defaults to 'aws s3'
-    GS_CMD   Command to access Google Storage. Defaults to 'gcsthin'"
+    S3_CMD                      Command to access AWS S3. Defaults to 'aws s3'
+    GS_CMD                      Command to access Google Storage. Defaults to 'gcsthin'
+    FF_IMAGE_CACHE_DIR          When specified, images stored on S3 or GCS are cached in this local directory
+    FF_IMAGE_CACHE_MAX_SIZE_MB  Size cap of the image cache. Defaults to 10240
+    FF_S3_RETRY_ATTEMPTS        Attempts of S3 operations before giving up. Defaults to 3.
+                                Same for FF_GS_RETRY_ATTEMPTS, and FF_FILE_RETRY_ATTEMPTS (defaults to 1)
+    FF_S3_RETRY_BACKOFF_MS      Delay before the first retry, doubled on each retry. Defaults to 500.
+                                Same for FF_GS_* and FF_FILE_*
+    FF_S3_RETRYABLE_ERRORS      Comma separated error messages on which to retry. Same for FF_GS_* and FF_FILE_*
+    FF_TRACE_EXPORTER           Where to export a trace of the command in the OTLP/JSON format:
+                                http://host:port/path (OTLP/HTTP collector) or file:/path (JSON lines)"
 ))]
 pub struct Extract {
     /// Image URL, which can also be a regular local path
@@ -40,6 +55,11 @@ pub struct Extract {
     #[structopt(short, long)]
     output_dir: Option<PathBuf>,
 
+    /// Config file, in TOML or JSON. Options given on the command line take precedence.
+    /// It defaults to fastfreeze.toml or fastfreeze.json next to the image, if present.
+    #[structopt(long, name = "config-file")]
+    config: Option<PathBuf>,
+
     /// Allow restoring of images that don't match the version we expect.
     #[structopt(long)]
     allow_bad_image_version: bool,
@@ -53,10 +73,17 @@ pub struct Extract {
     /// Verbosity. Can be repeated
     #[structopt(short, long, parse(from_occurrences))]
     pub verbose: u8,
+
+    /// Log format. Possible values are [text, json]. Defaults to text,
+    /// or to FF_LOG_FORMAT when set. Log files use the same format.
+    #[structopt(long)]
+    pub log_format: Option<LogFormat>,
 }
 
 pub fn extract_image(
     shard_download_cmds: Vec<String>,
+    fs_layer_download_cmds: Vec<String>,
+    log_download_cmd: Option<String>,
     output_dir: PathBuf,
 ) -> Result<()> {
     let num_shards = shard_download_cmds.len();
@@ -74,6 +101,33 @@ pub fn extract_image(
             .join(&mut pgrp);
     }
 
+    // The file system layers of incremental checkpoints are written as
+    // fs-layer-<n>.tar, to be extracted in order before fs.tar.
+    if !fs_layer_download_cmds.is_empty() || log_download_cmd.is_some() {
+        fs::create_dir_all(&output_dir)
+            .with_context(|| format!("Failed to create {}", output_dir.display()))?;
+    }
+    for (i, download_cmd) in fs_layer_download_cmds.into_iter().enumerate() {
+        let path = output_dir.join(format!("fs-layer-{}.tar", i+1));
+        let file = fs::File::create(&path)
+            .with_context(|| format!("Failed to create {}", path.display()))?;
+        Command::new_shell(&download_cmd)
+            .stdout(Stdio::from(file))
+            .spawn()?
+            .join(&mut pgrp);
+    }
+
+    // The log of the checkpoint, when it was uploaded with the image.
+    if let Some(download_cmd) = log_download_cmd {
+        let path = output_dir.join("checkpoint.log");
+        let file = fs::File::create(&path)
+            .with_context(|| format!("Failed to create {}", path.display()))?;
+        Command::new_shell(&download_cmd)
+            .stdout(Stdio::from(file))
+            .spawn()?
+            .join(&mut pgrp);
+    }
+
     pgrp.wait_for_success()?;
 
     let stats = img_streamer.progress.wait_for_stats()?;
@@ -87,12 +141,20 @@ pub fn extract_image(
 
 impl super::CLI for Extract {
     fn run(self) -> Result<()> {
-        let Self { image_url, output_dir,
-            allow_bad_image_version, passphrase_file, verbose: _
+        let Self { image_url, output_dir, config,
+            allow_bad_image_version, passphrase_file, verbose: _, log_format: _,
         } = self;
 
         let image_url = ImageUrl::parse(&image_url)?;
 
+        // Options given on the command line take precedence over the config file.
+        let config_file = ConfigFile::load_or_discover(config.as_deref(), &image_url)?
+            .unwrap_or_default();
+        config_file.apply_env();
+        let output_dir = output_dir.or_else(|| config_file.extract.output_dir.clone());
+        let allow_bad_image_version = allow_bad_image_version || config_file.extract.allow_bad_image_version;
+        let passphrase_file = passphrase_file.or_else(|| config_file.passphrase_file.clone());
+
         let store = image_url.store();
         store.prepare(false)?;
 
@@ -108,9 +170,17 @@ impl super::CLI for Extract {
         match ImageManifest::fetch_from_store(&*store, allow_bad_image_version)? {
             ManifestFetchResult::Some(img_manifest) => {
                 debug!("Image manifest found: {}", img_manifest);
+                trace::link_to_checkpoint(&img_manifest.shard_prefix);
+                store.on_generation_selected(&img_manifest)?;
                 let dl_cmds = shard::download_cmds(
                     &img_manifest, passphrase_file.as_ref(), &*store)?;
-                extract_image(dl_cmds, output_dir)?;
+                let fs_layer_dl_cmds = img_manifest.fs_layers.iter()
+                    .map(|name| shard::download_cmd(&img_manifest, passphrase_file.as_ref(), &*store, name))
+                    .collect::<Result<_>>()?;
+                let log_dl_cmd = img_manifest.log.as_ref()
+                    .map(|name| shard::download_cmd(&img_manifest, passphrase_file.as_ref(), &*store, name))
+                    .transpose()?;
+                extract_image(dl_cmds, fs_layer_dl_cmds, log_dl_cmd, output_dir)?;
             }
             ManifestFetchResult::VersionMismatch { fetched, desired } => {
                 bail!("Image manifest found, but has version {} while the expected version is {}. \
@@ -0,0 +1,462 @@
+//  Copyright 2020 Two Sigma Investments, LP.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+use anyhow::{Result, Context};
+use std::{
+    path::{Path, PathBuf},
+    fs,
+};
+use structopt::StructOpt;
+use serde::Serialize;
+use crate::{
+    consts::*,
+    store::{ImageUrl, Store, FileExt},
+    image::{ManifestFetchResult, ImageManifest, CpuBudget, shard, check_passphrase_file_exists},
+    process::{Command, ProcessExt, ProcessGroup, Stdio},
+    image_streamer::{ImageStreamer, send_image_files},
+};
+use super::extract::extract_image;
+
+/// Manipulate FastFreeze images offline
+#[derive(StructOpt, PartialEq, Debug, Serialize)]
+pub struct Image {
+    #[structopt(subcommand)]
+    command: ImageCommand,
+}
+
+#[derive(StructOpt, PartialEq, Debug, Serialize)]
+enum ImageCommand {
+    Convert(Convert),
+    Copy(Copy),
+}
+
+impl Image {
+    pub fn verbosity(&self) -> u8 {
+        match self.command {
+            ImageCommand::Convert(Convert { verbose, .. }) |
+            ImageCommand::Copy(Copy { verbose, .. }) => verbose,
+        }
+    }
+}
+
+impl super::CLI for Image {
+    fn run(self) -> Result<()> {
+        match self.command {
+            ImageCommand::Convert(opts) => opts.run(),
+            ImageCommand::Copy(opts) => opts.run(),
+        }
+    }
+}
+
+/// Rewrite an image with a different compression, encryption, or number of shards,
+/// or to a different store
+#[derive(StructOpt, PartialEq, Debug, Serialize)]
+#[structopt(after_help("\
+ENVS:
+    S3_CMD                      Command to access AWS S3. Defaults to 'aws s3'
+    GS_CMD                      Command to access Google Storage. Defaults to 'gcsthin'
+    TMPDIR                      Where the image is unpacked when changing the number of shards.
+                                It needs room for the uncompressed image. Defaults to /tmp"
+))]
+pub struct Convert {
+    /// URL of the image to convert
+    src_image_url: String,
+
+    /// URL of the converted image. Defaults to the source image URL, in which case
+    /// the converted image replaces the source image once complete, and the files
+    /// of the source image are deleted.
+    dst_image_url: Option<String>,
+
+    /// Amount of CPU at disposal, which selects the compression of the converted image.
+    /// Possible values are [low, medium, high]. See the checkpoint command for details.
+    /// Defaults to the compression of the source image.
+    #[structopt(long)]
+    cpu_budget: Option<CpuBudget>,
+
+    /// Number of shards of the converted image. Defaults to the number of shards
+    /// of the source image. Changing it unpacks the image in a temporary directory.
+    #[structopt(long)]
+    num_shards: Option<u32>,
+
+    /// File containing the passphrase to decrypt the source image.
+    /// It is also used to encrypt the converted image, unless --new-passphrase-file
+    /// or --decrypt is specified.
+    #[structopt(long)]
+    passphrase_file: Option<PathBuf>,
+
+    /// Encrypt the converted image with the passphrase contained in this file.
+    #[structopt(long, conflicts_with = "decrypt")]
+    new_passphrase_file: Option<PathBuf>,
+
+    /// Do not encrypt the converted image.
+    #[structopt(long)]
+    decrypt: bool,
+
+    /// Allow converting images that don't match the version we expect.
+    #[structopt(long)]
+    allow_bad_image_version: bool,
+
+    /// Verbosity. Can be repeated
+    #[structopt(short, long, parse(from_occurrences))]
+    pub verbose: u8,
+}
+
+/// Fetches the manifest of an image that must exist.
+fn fetch_manifest(image_url: &ImageUrl, store: &dyn Store, allow_bad_image_version: bool) -> Result<ImageManifest> {
+    debug!("Fetching image manifest for {}", image_url);
+
+    match ImageManifest::fetch_from_store(store, allow_bad_image_version)? {
+        ManifestFetchResult::Some(img_manifest) => {
+            debug!("Image manifest found: {}", img_manifest);
+            store.on_generation_selected(&img_manifest)?;
+            Ok(img_manifest)
+        }
+        ManifestFetchResult::VersionMismatch { fetched, desired } => {
+            bail!("Image manifest found, but has version {} while the expected version is {}. \
+                   You may try again with --allow-bad-image-version",
+                  fetched, desired);
+        }
+        ManifestFetchResult::NotFound => {
+            bail!("Image manifest not found at {}", image_url);
+        }
+    }
+}
+
+/// Returns the filenames of the shards, file system layers, and log of an image.
+fn image_filenames(img_manifest: &ImageManifest) -> Vec<String> {
+    (0..img_manifest.num_shards)
+        .map(|shard_index| shard::shard_filename(&img_manifest.shard_prefix, shard_index))
+        .chain(img_manifest.fs_layers.iter().cloned())
+        .chain(img_manifest.log.iter().cloned())
+        .collect()
+}
+
+/// Runs the transfer commands of each file in parallel, and writes the manifest
+/// once all files are transferred. The manifest existence indicates whether the
+/// image exists, so it must be written at the very end.
+fn transfer_shards(cmds: Vec<(String, String)>, dst_manifest: &ImageManifest, dst_manifest_json: &[u8],
+                   dst_url: &ImageUrl, dst_store: &dyn Store) -> Result<()> {
+    let mut pgrp = ProcessGroup::new()?;
+    for (filename, cmd) in cmds {
+        Command::new_shell(&cmd)
+            .enable_stderr_logging(filename)
+            .spawn()?
+            .join(&mut pgrp);
+    }
+    pgrp.wait_for_success()?;
+
+    debug!("Writing image manifest");
+    dst_store.file(MANIFEST_FILE_NAME).write("upload manifest", dst_manifest_json)
+        .with_context(|| format!("Failed to upload image manifest at {}", dst_url))?;
+
+    if let Err(e) = dst_store.on_generation_selected(dst_manifest) {
+        warn!("{:#}", e);
+    }
+
+    Ok(())
+}
+
+/// Splits an image in a different number of shards. The source shards are
+/// unpacked by criu-image-streamer in extract mode, and the image files are
+/// sent to criu-image-streamer in capture mode, as CRIU would during a checkpoint.
+fn reshard(shard_download_cmds: Vec<String>, shard_upload_cmds: Vec<String>, work_dir: &Path) -> Result<()> {
+    let files_dir = work_dir.join("files");
+    extract_image(shard_download_cmds, vec![], None, files_dir.clone())?;
+
+    info!("Capturing image in {} shards", shard_upload_cmds.len());
+
+    let mut pgrp = ProcessGroup::new()?;
+    let mut img_streamer = ImageStreamer::spawn_capture(shard_upload_cmds.len(), work_dir)?;
+    img_streamer.process.join(&mut pgrp);
+
+    for (i, (upload_cmd, shard_pipe)) in shard_upload_cmds.into_iter().zip(img_streamer.shard_pipes).enumerate() {
+        Command::new_shell(&upload_cmd)
+            .stdin(Stdio::from(shard_pipe))
+            .enable_stderr_logging(format!("upload shard {}", i+1))
+            .spawn()?
+            .join(&mut pgrp);
+    }
+
+    img_streamer.progress.wait_for_socket_init()?;
+    // unwrap() is safe: the capture mode always has a file system pipe.
+    let tar_fs_pipe = img_streamer.tar_fs_pipe.take().expect("missing fs tar pipe");
+    send_image_files(work_dir, &files_dir, tar_fs_pipe)?;
+
+    pgrp.wait_for_success()?;
+
+    img_streamer.progress.wait_for_checkpoint_start()?;
+    let stats = img_streamer.progress.wait_for_stats()?;
+    stats.show();
+
+    Ok(())
+}
+
+impl super::CLI for Convert {
+    fn run(self) -> Result<()> {
+        let Self { src_image_url, dst_image_url, cpu_budget, num_shards, passphrase_file,
+                   new_passphrase_file, decrypt, allow_bad_image_version, verbose: _ } = self;
+
+        let src_url = ImageUrl::parse(&src_image_url)?;
+        let dst_url = ImageUrl::parse(dst_image_url.as_ref().unwrap_or(&src_image_url))?;
+
+        for file in passphrase_file.iter().chain(new_passphrase_file.iter()) {
+            check_passphrase_file_exists(file)?;
+        }
+
+        let src_store = src_url.store();
+        src_store.prepare(false)?;
+        let src_manifest = fetch_manifest(&src_url, &*src_store, allow_bad_image_version)?;
+
+        // By default, the converted image is encrypted like the source image.
+        let dst_passphrase_file = match (decrypt, new_passphrase_file) {
+            (true, _) => None,
+            (false, Some(file)) => Some(file),
+            (false, None) if src_manifest.encryption.is_some() => passphrase_file.clone(),
+            (false, None) => None,
+        };
+        let compression = match cpu_budget {
+            Some(cpu_budget) => cpu_budget.into(),
+            None => src_manifest.compression.clone(),
+        };
+
+        let num_shards = num_shards.unwrap_or(src_manifest.num_shards);
+        ensure!(num_shards > 0, "--num-shards must be positive");
+        // The shards contain the output of criu-image-streamer. Changing the
+        // number of shards means capturing the image again.
+        let reshard_needed = num_shards != src_manifest.num_shards;
+
+        let mut dst_manifest = ImageManifest::new(
+            num_shards, dst_passphrase_file.is_some(), compression);
+        // Unless captured again, the shard content is unchanged, and so is its format version.
+        if !reshard_needed {
+            dst_manifest.version = src_manifest.version.clone();
+        }
+        dst_manifest.created_at = src_manifest.created_at;
+        dst_manifest.completed_at = src_manifest.completed_at;
+        dst_manifest.duration_sec = src_manifest.duration_sec;
+        dst_manifest.stats = src_manifest.stats.clone();
+        // File system layers are re-encoded too. They get new names, so that converting
+        // in place never rewrites a file that the current manifest refers to.
+        dst_manifest.fs_layers = (0..src_manifest.fs_layers.len())
+            .map(|i| shard::converted_fs_layer_filename(&dst_manifest.shard_prefix, i))
+            .collect();
+        dst_manifest.log = src_manifest.log.as_ref()
+            .map(|_| shard::log_filename(&dst_manifest.shard_prefix));
+
+        let dst_store = dst_url.store();
+        dst_store.prepare(true)?;
+
+        info!("Converting image {} ({}) to {} ({})", src_url, src_manifest, dst_url, dst_manifest);
+
+        // A shard conversion command is of the form:
+        //     "aws s3 cp s3://bucket/img/XXXXXX-1.ffs - | lz4 -d - - | zstd -1 - - | gcsthin cp - gs://..."
+        let src_filenames = image_filenames(&src_manifest);
+        let dst_filenames = image_filenames(&dst_manifest);
+        // When re-sharding, the shards are written before the other files, which
+        // are transferred as-is. The shards are the first files of the lists.
+        let (src_skip, dst_skip) = match reshard_needed {
+            true => (src_manifest.num_shards as usize, num_shards as usize),
+            false => (0, 0),
+        };
+        let cmds = src_filenames.iter().skip(src_skip).zip(dst_filenames.iter().skip(dst_skip))
+            .map(|(src_filename, dst_filename)| {
+                let download = shard::download_cmd(&src_manifest, passphrase_file.as_ref(), &*src_store, src_filename)?;
+                let upload = shard::upload_cmd(&dst_manifest, dst_passphrase_file.as_ref(), &*dst_store, dst_filename)?;
+                Ok((dst_filename.clone(), format!("{} | {}", download, upload)))
+            }).collect::<Result<_>>()?;
+
+        if reshard_needed {
+            let work_dir = std::env::temp_dir().join(format!("ff-convert-{}", &*INVOCATION_ID));
+            fs::create_dir_all(&work_dir)
+                .with_context(|| format!("Failed to create {}", work_dir.display()))?;
+            let result = reshard(
+                shard::download_cmds(&src_manifest, passphrase_file.as_ref(), &*src_store)?,
+                shard::upload_cmds(&dst_manifest, dst_passphrase_file.as_ref(), &*dst_store)?,
+                &work_dir);
+            // The unpacked image is as large as the uncompressed image.
+            if let Err(e) = fs::remove_dir_all(&work_dir) {
+                warn!("Failed to remove {}: {}", work_dir.display(), e);
+            }
+            result?;
+        }
+
+        transfer_shards(cmds, &dst_manifest, dst_manifest.to_json().as_bytes(), &dst_url, &*dst_store)?;
+
+        // When converting in place, the files of the source image are no longer
+        // referenced once the new manifest is written. Failing to delete them
+        // leaves garbage behind, but the image is converted.
+        if src_url.to_string() == dst_url.to_string() {
+            debug!("Deleting files of the source image");
+            for filename in src_filenames.iter().filter(|f| !dst_filenames.contains(f)) {
+                if let Err(e) = src_store.file(filename).delete("delete image") {
+                    warn!("Failed to delete {}: {:#}", filename, e);
+                }
+            }
+        }
+
+        info!("Image converted in {:.1}s", START_TIME.elapsed().as_secs_f64());
+
+        Ok(())
+    }
+}
+
+/// Copy an image to another store as-is, without decompressing or decrypting it
+#[derive(StructOpt, PartialEq, Debug, Serialize)]
+#[structopt(after_help("\
+ENVS:
+    S3_CMD                      Command to access AWS S3. Defaults to 'aws s3'
+    GS_CMD                      Command to access Google Storage. Defaults to 'gcsthin'"
+))]
+pub struct Copy {
+    /// URL of the image to copy
+    src_image_url: String,
+
+    /// URL of the destination image
+    dst_image_url: String,
+
+    /// Skip reading back the copied shards to verify their checksums
+    #[structopt(long)]
+    no_verify: bool,
+
+    /// Verbosity. Can be repeated
+    #[structopt(short, long, parse(from_occurrences))]
+    pub verbose: u8,
+}
+
+impl super::CLI for Copy {
+    fn run(self) -> Result<()> {
+        let Self { src_image_url, dst_image_url, no_verify, verbose: _ } = self;
+
+        let src_url = ImageUrl::parse(&src_image_url)?;
+        let dst_url = ImageUrl::parse(&dst_image_url)?;
+        // Copying an image onto itself would truncate its files while reading them.
+        ensure!(src_url.to_string() != dst_url.to_string(),
+                "The source and destination images are the same: {}", src_url);
+
+        let src_store = src_url.store();
+        src_store.prepare(false)?;
+
+        // The manifest is copied verbatim, so that the destination image is
+        // identical to the source image. The image version does not matter
+        // as we are not interpreting the shards.
+        debug!("Fetching image manifest for {}", src_url);
+        let manifest_json = src_store.file(MANIFEST_FILE_NAME).try_read("download manifest")?
+            .ok_or_else(|| anyhow!("Image manifest not found at {}", src_url))?;
+        let img_manifest = match ImageManifest::from_json(&String::from_utf8_lossy(&manifest_json), true)? {
+            ManifestFetchResult::Some(img_manifest) => img_manifest,
+            _ => unreachable!("the image version is not checked"),
+        };
+        src_store.on_generation_selected(&img_manifest)?;
+
+        let dst_store = dst_url.store();
+        dst_store.prepare(true)?;
+        // The copied files are read back from the destination store itself. Reading
+        // them from the image cache, which is written during the copy, would verify nothing.
+        let verify_store = dst_url.uncached_store();
+
+        info!("Copying image {} ({}) to {}", src_url, img_manifest, dst_url);
+
+        // A shard copy command is of the form:
+        //     "aws s3 cp s3://bucket/img/XXXXXX-1.ffs - | tee $fifo | gcsthin cp - gs://..."
+        // followed by reading back the destination shard to compare checksums.
+        let cmds = image_filenames(&img_manifest).into_iter().map(|filename| {
+            let src_file = src_store.file(&filename);
+            let dst_file = dst_store.file(&filename);
+            let verify_file = verify_store.file(&filename);
+
+            let copy_cmd = format!("{} | {}", src_file.download_shell_cmd(), dst_file.upload_shell_cmd());
+            if no_verify {
+                return (filename, copy_cmd);
+            }
+
+            // The checksum is computed by `sha256sum` in the background, reading
+            // from a fifo fed by `tee`. `wait $!` waits for it to write the checksum.
+            let cmd = format!("{{ \
+                d=$(mktemp -d) && mkfifo \"$d/fifo\" || exit 1; \
+                sha256sum < \"$d/fifo\" > \"$d/sum\" & \
+                {download} | tee \"$d/fifo\" | {upload} && wait $! || exit 1; \
+                expected=$(cut -d' ' -f1 \"$d/sum\"); rm -rf \"$d\"; \
+                actual=$({verify} | sha256sum | cut -d' ' -f1) || exit 1; \
+                [ \"$expected\" = \"$actual\" ] || \
+                    {{ echo \"Checksum mismatch for {filename}: expected $expected, got $actual\" >&2; false; }}; }}",
+                download=src_file.download_shell_cmd(), upload=dst_file.upload_shell_cmd(),
+                verify=verify_file.download_shell_cmd(), filename=filename);
+            (filename, cmd)
+        }).collect();
+
+        transfer_shards(cmds, &img_manifest, &manifest_json, &dst_url, &*dst_store)?;
+
+        info!("Image copied in {:.1}s", START_TIME.elapsed().as_secs_f64());
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{cli::CLI, process::Stdio};
+    use std::fs;
+
+    fn read_shard(image_url: &str) -> Result<Vec<u8>> {
+        let url = ImageUrl::parse(image_url)?;
+        let store = url.store();
+        let img_manifest = fetch_manifest(&url, &*store, false)?;
+        let cmd = shard::download_cmd(&img_manifest, None, &*store,
+            &shard::shard_filename(&img_manifest.shard_prefix, 0))?;
+        let output = Command::new_shell(&cmd).stdout(Stdio::piped()).spawn()?.wait_with_output()?;
+        output.ensure_success()?;
+        Ok(output.stdout)
+    }
+
+    #[test]
+    fn test_convert_round_trip() -> Result<()> {
+        let dir = PathBuf::from("/tmp/ff-test-image-convert");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("src"))?;
+
+        let mut src_manifest = ImageManifest::new(1, false, None);
+        src_manifest.shard_prefix = "src".to_string();
+        src_manifest.fs_layers = vec!["base-fs.ffl".to_string()];
+        fs::write(dir.join("src").join(MANIFEST_FILE_NAME), src_manifest.to_json())?;
+        fs::write(dir.join("src/src-1.ffs"), "shard data")?;
+        fs::write(dir.join("src/base-fs.ffl"), "layer data")?;
+
+        let convert = |src: &str, dst: Option<&str>, cpu_budget| Convert {
+            src_image_url: src.to_string(),
+            dst_image_url: dst.map(String::from),
+            cpu_budget: Some(cpu_budget),
+            num_shards: None,
+            passphrase_file: None,
+            new_passphrase_file: None,
+            decrypt: false,
+            allow_bad_image_version: false,
+            verbose: 0,
+        }.run();
+
+        // Compress in place. The source files must be gone.
+        let src_url = format!("file:{}/src", dir.display());
+        convert(&src_url, None, CpuBudget::Medium)?;
+        assert!(!dir.join("src/src-1.ffs").exists());
+        assert!(!dir.join("src/base-fs.ffl").exists());
+        assert_eq!(read_shard(&src_url)?, b"shard data");
+
+        // And decompress it to another image.
+        let dst_url = format!("file:{}/dst", dir.display());
+        convert(&src_url, Some(&dst_url), CpuBudget::Low)?;
+        assert_eq!(read_shard(&dst_url)?, b"shard data");
+
+        Ok(())
+    }
+}
@@ -12,17 +12,23 @@
 //  See the License for the specific language governing permissions and
 //  limitations under the License.
 
-use anyhow::Result;
+use anyhow::{Result, Context};
 use structopt::{StructOpt, clap::AppSettings};
 use serde::Serialize;
-use crate::logger;
+use crate::{logger::{self, LogFormat}, trace};
 use super::{
     CLI,
     checkpoint::Checkpoint,
     extract::Extract,
+    image::Image,
     install::Install,
     run::Run,
     wait::Wait,
+    doctor::Doctor,
+    status::Status,
+    ps::Ps,
+    exec::Exec,
+    stop::Stop,
 };
 
 #[derive(StructOpt, PartialEq, Debug, Serialize)]
@@ -48,8 +54,14 @@ enum Command {
     Run(Run),
     Checkpoint(Checkpoint),
     Extract(Extract),
+    Image(Image),
     Wait(Wait),
     Install(Install),
+    Doctor(Doctor),
+    Status(Status),
+    Ps(Ps),
+    Exec(Exec),
+    Stop(Stop),
 }
 
 impl Opts {
@@ -63,7 +75,28 @@ impl Opts {
             Command::Run(Run { verbose, .. }) |
             Command::Checkpoint(Checkpoint { verbose, .. }) |
             Command::Extract(Extract { verbose, .. }) |
-            Command::Wait(Wait { verbose, .. }) => verbose,
+            Command::Wait(Wait { verbose, .. }) |
+            Command::Doctor(Doctor { verbose, .. }) |
+            Command::Status(Status { verbose, .. }) |
+            Command::Ps(Ps { verbose, .. }) |
+            Command::Exec(Exec { verbose, .. }) |
+            Command::Stop(Stop { verbose, .. }) => verbose,
+            Command::Image(ref image) => image.verbosity(),
+        }
+    }
+
+    fn log_format(&self) -> Result<LogFormat> {
+        let log_format = match self.command {
+            Command::Run(Run { log_format, .. }) |
+            Command::Checkpoint(Checkpoint { log_format, .. }) |
+            Command::Extract(Extract { log_format, .. }) |
+            Command::Stop(Stop { log_format, .. }) => log_format,
+            _ => None,
+        };
+        match (log_format, std::env::var("FF_LOG_FORMAT")) {
+            (Some(log_format), _) => Ok(log_format),
+            (None, Ok(log_format)) => log_format.parse().context("Invalid FF_LOG_FORMAT"),
+            (None, Err(_)) => Ok(LogFormat::Text),
         }
     }
 
@@ -81,7 +114,13 @@ impl Opts {
             Command::Run(_)        => "run",
             Command::Checkpoint(_) => "checkpoint",
             Command::Extract(_)    => "extract",
+            Command::Image(_)      => "image",
             Command::Wait(_)       => "wait",
+            Command::Doctor(_)     => "doctor",
+            Command::Status(_)     => "status",
+            Command::Ps(_)         => "ps",
+            Command::Exec(_)       => "exec",
+            Command::Stop(_)       => "stop",
         }
     }
 
@@ -90,23 +129,44 @@ impl Opts {
         // application in the checkpointed image.
         matches!(self.command,
             Command::Run(_) |
-            Command::Checkpoint(_)
+            Command::Checkpoint(_) |
+            Command::Stop(_)
+        )
+    }
+
+    fn use_trace(&self) -> bool {
+        // The commands that checkpoint or restore, or transfer images.
+        matches!(self.command,
+            Command::Run(_) |
+            Command::Checkpoint(_) |
+            Command::Extract(_) |
+            Command::Stop(_)
         )
     }
 
     pub fn init_logger(&self) -> Result<()> {
-        logger::init(self.log_level(), self.log_prefix(), self.use_log_file())
+        logger::init(self.log_level(), self.log_prefix(), self.use_log_file(), self.log_format()?)
     }
 }
 
 impl CLI for Opts {
     fn run(self) -> Result<()> {
+        if self.use_trace() {
+            trace::start(self.log_prefix());
+        }
+
         match self.command {
             Command::Install(opts)    => opts.run(),
             Command::Run(opts)        => opts.run(),
             Command::Checkpoint(opts) => opts.run(),
             Command::Extract(opts)    => opts.run(),
+            Command::Image(opts)      => opts.run(),
             Command::Wait(opts)       => opts.run(),
+            Command::Doctor(opts)     => opts.run(),
+            Command::Status(opts)     => opts.run(),
+            Command::Ps(opts)         => opts.run(),
+            Command::Exec(opts)       => opts.run(),
+            Command::Stop(opts)       => opts.run(),
         }
     }
 }
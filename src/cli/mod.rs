@@ -15,11 +15,21 @@
 pub mod run;
 pub mod checkpoint;
 mod extract;
+mod image;
 mod wait;
+mod doctor;
+pub mod status;
+mod ps;
+mod exec;
+pub mod stop;
+pub mod config;
 pub mod install;
 mod main;
 
-use crate::consts::*;
+use crate::{
+    consts::*,
+    signal::is_termination_requested,
+};
 
 pub trait CLI {
     fn run(self) -> anyhow::Result<()>;
@@ -35,6 +45,11 @@ impl std::fmt::Display for ExitCode {
 
 impl ExitCode {
     pub fn from_error(e: &anyhow::Error) -> u8 {
+        // A termination request takes precedence over the exit code of the
+        // operation it interrupted (e.g., a restore failure).
+        if is_termination_requested(e) {
+            return EXIT_CODE_TERMINATED;
+        }
         e.downcast_ref::<Self>()
             .map(|exit_code| exit_code.0)
             .unwrap_or(EXIT_CODE_FAILURE)
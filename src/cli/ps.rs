@@ -0,0 +1,179 @@
+//  Copyright 2020 Two Sigma Investments, LP.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+use anyhow::{Result, Context};
+use std::{
+    fs,
+    path::Path,
+    time::{Duration, SystemTime},
+};
+use nix::unistd::Pid;
+use structopt::StructOpt;
+use serde::{Serialize, de::DeserializeOwned};
+use crate::{
+    consts::*,
+    container,
+    lock::is_file_lock_held,
+    signal::get_process_tree,
+};
+use super::{
+    checkpoint::LastCheckpoint,
+    run::AppConfig,
+};
+
+/// List the applications running in FastFreeze containers
+#[derive(StructOpt, PartialEq, Debug, Serialize)]
+pub struct Ps {
+    /// Print the list in JSON
+    #[structopt(long)]
+    json: bool,
+
+    /// Remove the directories of the containers that are no longer running
+    #[structopt(long)]
+    clean: bool,
+
+    /// Verbosity. Can be repeated
+    #[structopt(short, long, parse(from_occurrences))]
+    pub verbose: u8,
+}
+
+#[derive(Serialize)]
+struct ContainerStatus {
+    name: String,
+    running: bool,
+    /// PIDs are as seen from outside the container
+    container_pid: Option<u32>,
+    app_pid: Option<i32>,
+    uptime_sec: Option<f64>,
+    image_url: Option<String>,
+    /// Seconds since the last checkpoint completed
+    last_checkpoint_age_sec: Option<f64>,
+    /// A checkpoint or a restore is in progress
+    locked: bool,
+}
+
+// The directory of a container is mounted on FF_DIR in the container. From the
+// outside, we can read the files that the container writes in FF_DIR.
+
+fn read_json<T: DeserializeOwned>(path: &Path) -> Option<T> {
+    let content = fs::read(path).ok()?;
+    serde_json::from_slice(&content).ok()
+}
+
+/// Returns how long the process has been running.
+fn process_uptime(pid: u32) -> Option<Duration> {
+    // The start time is the 22nd field, in clock ticks since boot. The process
+    // name (2nd field) is in parenthesis, and may contain spaces.
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    let start_ticks: u64 = stat.rsplit(')').next()?
+        .split_whitespace()
+        .nth(22 - 3)?
+        .parse().ok()?;
+    let ticks_per_sec = unsafe { libc::sysconf(libc::_SC_CLK_TCK) };
+    let boot_uptime: f64 = fs::read_to_string("/proc/uptime").ok()?
+        .split_whitespace().next()?
+        .parse().ok()?;
+    let uptime = boot_uptime - start_ticks as f64 / ticks_per_sec as f64;
+    Some(Duration::from_secs_f64(uptime.max(0.0)))
+}
+
+/// Returns the PID of the application in the container, as seen from outside.
+fn find_app_pid(container_pid: u32) -> Option<i32> {
+    // The NSpid line lists the PIDs of the process in each nested PID namespace.
+    // In the container, the application runs as APP_ROOT_PID.
+    let app_root_pid = APP_ROOT_PID.to_string();
+    get_process_tree(Pid::from_raw(container_pid as i32)).ok()?.into_iter()
+        .find(|pid| {
+            fs::read_to_string(format!("/proc/{}/status", pid)).unwrap_or_default()
+                .lines()
+                .find_map(|line| line.strip_prefix("NSpid:"))
+                .and_then(|pids| pids.split_whitespace().last())
+                == Some(app_root_pid.as_str())
+        })
+        .map(|pid| pid.as_raw())
+}
+
+fn container_status(name: String) -> Result<ContainerStatus> {
+    let container_pid = container::container_pid(&name)?;
+    let running = container_pid.is_some_and(|pid| Path::new("/proc").join(pid.to_string()).exists());
+
+    let config: Option<AppConfig> = read_json(&container::container_path(&name, &APP_CONFIG_PATH));
+    let last_checkpoint: Option<LastCheckpoint> =
+        read_json(&container::container_path(&name, &LAST_CHECKPOINT_PATH));
+    let lock_path = container::container_path(&name, &LOCK_FILE_PATH);
+    let locked = running && lock_path.exists() && is_file_lock_held(&lock_path)?;
+
+    Ok(ContainerStatus {
+        running,
+        container_pid,
+        app_pid: container_pid.filter(|_| running).and_then(find_app_pid),
+        uptime_sec: container_pid.filter(|_| running).and_then(process_uptime).map(|d| d.as_secs_f64()),
+        image_url: config.map(|c| c.image_url),
+        last_checkpoint_age_sec: last_checkpoint.map(|c|
+            SystemTime::now().duration_since(c.completed_at).unwrap_or_default().as_secs_f64()),
+        locked,
+        name,
+    })
+}
+
+fn show(containers: &[ContainerStatus]) {
+    let or_dash = |v: Option<String>| v.unwrap_or_else(|| "-".to_string());
+    let duration = |secs: Option<f64>| or_dash(secs.map(|s| format!("{:.0}s", s)));
+
+    println!("{:<20} {:<8} {:>13} {:>8} {:>10} {:>15} {:<7} IMAGE URL",
+             "NAME", "STATE", "CONTAINER PID", "APP PID", "UPTIME", "LAST CHECKPOINT", "LOCKED");
+    for c in containers {
+        println!("{:<20} {:<8} {:>13} {:>8} {:>10} {:>15} {:<7} {}",
+                 c.name,
+                 match (c.running, c.container_pid) {
+                     (true, _) => "running",
+                     (false, Some(_)) => "stopped",
+                     (false, None) => "starting",
+                 },
+                 or_dash(c.container_pid.map(|p| p.to_string())),
+                 or_dash(c.app_pid.map(|p| p.to_string())),
+                 duration(c.uptime_sec),
+                 duration(c.last_checkpoint_age_sec),
+                 if c.locked { "yes" } else { "no" },
+                 c.image_url.as_deref().unwrap_or("-"));
+    }
+}
+
+impl super::CLI for Ps {
+    fn run(self) -> Result<()> {
+        let Self { json, clean, verbose: _ } = self;
+
+        let mut containers = container::get_containers()?.into_iter()
+            .map(container_status)
+            .collect::<Result<Vec<_>>>()?;
+
+        if clean {
+            // A container without a pid file is being created, we leave it alone.
+            for c in containers.iter().filter(|c| !c.running && c.container_pid.is_some()) {
+                let dir = CONTAINERS_DIR.join(&c.name);
+                fs::remove_dir_all(&dir)
+                    .with_context(|| format!("Failed to remove {}", dir.display()))?;
+                info!("Removed the directory of the stopped container `{}`", c.name);
+            }
+            containers.retain(|c| c.running || c.container_pid.is_none());
+        }
+
+        if json {
+            println!("{}", serde_json::to_string_pretty(&containers)?);
+        } else {
+            show(&containers);
+        }
+        Ok(())
+    }
+}
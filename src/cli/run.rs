@@ -13,19 +13,24 @@
 //  limitations under the License.
 
 use crate::{
-    cli::{install, ExitCode},
+    cli::{install, ExitCode, checkpoint::LastCheckpoint, config::ConfigFile, stop::StopMode},
     consts::*,
+    logger::LogFormat,
     ff_socket::FastFreezeListener,
-    container, criu, filesystem,
+    control::{ControlAddr, ControlListener},
+    prometheus::{self, SampleReceiver},
+    container, criu,
+    filesystem::{self, PreserveOptions, FsLayers, FileIndex},
     image::{check_passphrase_file_exists, shard, ImageManifest, ManifestFetchResult},
     image_streamer::{ImageStreamer, Stats},
     lock::with_checkpoint_restore_lock,
-    metrics::{metrics_error_json, with_metrics, with_metrics_raw},
+    metrics::{metrics_error_json, with_metrics, with_metrics_raw, record_phase, PhaseTimer},
+    trace,
     process::{
         monitor_child, set_ns_last_pid, spawn_set_ns_last_pid_server, Command, CommandPidExt,
         ProcessExt, ProcessGroup, Stdio, MIN_PID,
     },
-    signal::kill_process_tree,
+    signal::{kill_process_tree, TerminableReader},
     store::{ImageUrl, Store},
     util::JsonMerge,
     virt,
@@ -41,8 +46,9 @@ use std::{
     ffi::OsString,
     fs,
     io::{BufReader, BufWriter},
+    os::unix::io::AsRawFd,
     path::{Path, PathBuf},
-    time::{Duration, SystemTime},
+    time::{Duration, Instant, SystemTime},
 };
 use structopt::StructOpt;
 use virt::time::Nanos;
@@ -60,15 +66,33 @@ ENVS:
                                 For example, FF_APP_INJECT_LD_PRELOAD=/opt/lib/libx.so
     FF_METRICS_RECORDER         When specified, FastFreeze invokes the specified program to report metrics.
                                 The metrics are formatted in JSON and passed as first argument
+    FF_METRICS_SINK             Comma separated URLs where to send metrics, without spawning a program:
+                                udp://host:port (StatsD), file:/path or unix:/path (JSON lines)
+    FF_TRACE_EXPORTER           Where to export a trace of the command in the OTLP/JSON format:
+                                http://host:port/path (OTLP/HTTP collector) or file:/path (JSON lines)
+    FF_LOG_ROTATE_SIZE_MB       Size at which a log file is rotated. Defaults to 10
+    FF_LOG_MAX_FILES            Number of log files kept, including rotated ones. Defaults to 100
+    FF_LOG_MAX_SIZE_MB          Total size of the log files kept. Defaults to 50
     FF_FAKE_ROOT                Setting to 1 instructs FastFreeze to use uid=0 when creating user namespaces
     CRIU_OPTS                   Additional arguments to pass to CRIU, whitespace separated
     S3_CMD                      Command to access AWS S3. Defaults to 'aws s3'
     GS_CMD                      Command to access Google Storage. Defaults to 'gcsthin'
-    TAR_CMD                     Command to untar the file system. Defaults to 'tar'
+    TAR_CMD                     Command to untar the file system. Defaults to a built-in archiver
+    FF_IMAGE_CACHE_DIR          When specified, images stored on S3 or GCS are cached in this local directory
+    FF_IMAGE_CACHE_MAX_SIZE_MB  Size cap of the image cache. Defaults to 10240
+    FF_IMAGE_REPLICA_QUORUM     Number of image URLs that must be written successfully. Defaults to a majority
+    FF_S3_RETRY_ATTEMPTS        Attempts of S3 operations before giving up. Defaults to 3.
+                                Same for FF_GS_RETRY_ATTEMPTS, and FF_FILE_RETRY_ATTEMPTS (defaults to 1)
+    FF_S3_RETRY_BACKOFF_MS      Delay before the first retry, doubled on each retry. Defaults to 500.
+                                Same for FF_GS_* and FF_FILE_*
+    FF_S3_RETRYABLE_ERRORS      Comma separated error messages on which to retry. Same for FF_GS_* and FF_FILE_*
 
 EXIT CODES:
     171          A failure happened during restore, or while fetching the image manifest.
                  Retrying with --no-restore will avoid that failure
+    172          The application was stopped with the stop command
+    173          SIGTERM, SIGINT, or SIGHUP was received before the application was ready.
+                 The restore is aborted, and a partially restored application is killed
     170          A failure happened before the application was ready
     128+sig_nr   The application caught a fatal signal corresponding to `sig_nr`
     exit_code    The application exited with `exit_code`"
@@ -78,11 +102,19 @@ pub struct Run {
     ///   * s3://bucket_name/image_path {n}
     ///   * gs://bucket_name/image_path {n}
     ///   * file:image_path
+    ///
+    /// Multiple URLs can be specified comma separated to replicate the image. {n}
     /// It defaults to file:$HOME/.fastfreeze/<app_name>
     // {n} means new line in the CLI's --help command
     #[structopt(short, long, name = "url")]
     image_url: Option<String>,
 
+    /// Config file, in TOML or JSON, holding the options of the run, checkpoint,
+    /// and extract commands. Options given on the command line take precedence.
+    /// It defaults to fastfreeze.toml or fastfreeze.json next to the image, if present.
+    #[structopt(long, name = "config-file")]
+    config: Option<PathBuf>,
+
     /// Application command, used when running the app from scratch.
     /// When absent, FastFreeze runs in restore-only mode.
     #[structopt()]
@@ -118,6 +150,9 @@ pub struct Run {
     )]
     preserved_paths: Vec<PathBuf>,
 
+    #[structopt(flatten)]
+    preserve_options: PreserveOptions,
+
     /// Remap the TCP listen socket ports during restore.
     /// Format is old_port:new_port.
     /// Multiple tcp port remaps may be passed as a comma separated list.
@@ -134,6 +169,11 @@ pub struct Run {
     #[structopt(short, long, parse(from_occurrences))]
     pub verbose: u8,
 
+    /// Log format. Possible values are [text, json]. Defaults to text,
+    /// or to FF_LOG_FORMAT when set. Log files use the same format.
+    #[structopt(long)]
+    pub log_format: Option<LogFormat>,
+
     /// Specify the application name. This is used to distinguish applications
     /// when running multiple ones. The default is the file name of the image-url.
     /// Note: application specific files are located in /tmp/fastfreeze/<app_name>.
@@ -144,6 +184,25 @@ pub struct Run {
     /// This requires to run the install command prior.
     #[structopt(long)]
     no_container: bool,
+
+    /// Serve the HTTP control API on the given address, either a loopback host:port,
+    /// or the path of a unix socket. It provides POST /checkpoint, GET /status,
+    /// GET /metrics, and POST /stop. The query string holds the options of the
+    /// corresponding command, e.g., POST /checkpoint?leave-running
+    #[structopt(long, name = "addr")]
+    control_listen: Option<ControlAddr>,
+
+    /// Serve the checkpoint and restore metrics in the Prometheus text format
+    /// on the given address, either host:port, or the path of a unix socket.
+    /// Only GET /metrics is served. See also --control-listen
+    #[structopt(long, name = "metrics-addr")]
+    metrics_listen: Option<ControlAddr>,
+
+    /// Write the checkpoint and restore metrics in the Prometheus text format
+    /// to the given file, for the node-exporter textfile collector. The file
+    /// is updated atomically after each checkpoint
+    #[structopt(long, name = "file")]
+    metrics_textfile: Option<PathBuf>,
 }
 
 /// `AppConfig` is created during the run command, and updated during checkpoint.
@@ -154,6 +213,9 @@ pub struct Run {
 pub struct AppConfig {
     pub image_url: String,
     pub preserved_paths: HashSet<PathBuf>,
+    // Absent in configs written by older versions
+    #[serde(default)]
+    pub preserve_options: PreserveOptions,
     pub passphrase_file: Option<PathBuf>,
     pub app_clock: Nanos,
     // Used to compute the duration between a restore and a checkpoint, for metrics only.
@@ -162,6 +224,9 @@ pub struct AppConfig {
     // we need to remember the pipe inodes that we passed, so that when we restore,
     // we can replace the original external pipes by the new ones.
     pub inherited_resources: criu::InheritableResources,
+    // The config file of the run command, for the checkpoint command to use.
+    #[serde(default)]
+    pub config_file: ConfigFile,
 }
 
 impl AppConfig {
@@ -201,13 +266,18 @@ pub fn is_app_running() -> bool {
 
 // It returns Stats, that's the transfer speeds and all given by criu-image-streamer,
 // and the duration since the checkpoint happened. This is helpful for emitting metrics.
+#[allow(clippy::too_many_arguments)]
 fn restore(
     image_url: ImageUrl,
     mut preserved_paths: HashSet<PathBuf>,
+    preserve_options: PreserveOptions,
     tcp_listen_remaps: Vec<String>,
     passphrase_file: Option<PathBuf>,
     shard_download_cmds: Vec<String>,
+    // Filenames of the file system layers, and their download commands
+    fs_layers: Vec<(String, String)>,
     leave_stopped: bool,
+    config_file: ConfigFile,
 ) -> Result<(Stats, Duration)> {
     info!(
         "Restoring application{}",
@@ -217,6 +287,29 @@ fn restore(
             ""
         }
     );
+    // The file system is downloaded and extracted concurrently, so the `untar`
+    // phase includes its download. The `download` phase is the rest of the image.
+    let mut phases = PhaseTimer::new("restore");
+    phases.start("untar");
+
+    // The file system layers of incremental checkpoints come first, oldest first.
+    // The fs.tar of the shards is then empty.
+    for (i, (_, download_cmd)) in fs_layers.iter().enumerate() {
+        debug!("Restoring filesystem layer {}/{}", i+1, fs_layers.len());
+        let mut download_ps = Command::new_shell(download_cmd)
+            .stdout(Stdio::piped())
+            .enable_stderr_logging(format!("download fs layer {}", i+1))
+            .spawn()?;
+        let stdout = download_ps.stdout();
+        let stdout_fd = stdout.as_raw_fd();
+        if let Err(e) = filesystem::extract(TerminableReader::new(stdout, stdout_fd)) {
+            let _ = download_ps.kill(signal::SIGKILL);
+            let _ = download_ps.wait();
+            return Err(e);
+        }
+        download_ps.wait_for_success()?;
+    }
+
     let mut pgrp = ProcessGroup::new()?;
 
     let mut img_streamer =
@@ -237,27 +330,37 @@ fn restore(
     }
 
     debug!("Restoring filesystem");
-    let untar_ps = filesystem::untar_cmd(img_streamer.tar_fs_pipe.unwrap())
-        .enable_stderr_logging("untar")
-        .spawn()?
-        .join(&mut pgrp);
-    // We want to wait for tar to complete successfully. But if tar errors,
-    // we want to report the errors of tar and all other processes involved.
-    // The easiest way to use the process group.
-    pgrp.get_mut(untar_ps).wait()?; // wait for tar to finish
-    pgrp.try_wait_for_success()?; // if tar errored, this is where we exit.
+    let tar_fs_pipe = img_streamer.tar_fs_pipe.unwrap();
+    if filesystem::has_external_tar() {
+        let untar_ps = filesystem::untar_cmd(tar_fs_pipe)
+            .enable_stderr_logging("untar")
+            .spawn()?
+            .join(&mut pgrp);
+        // We want to wait for tar to complete successfully. But if tar errors,
+        // we want to report the errors of tar and all other processes involved.
+        // The easiest way to use the process group.
+        pgrp.wait_for_exit(untar_ps)?; // if tar errored, this is where we exit.
+
+        // Because the tar command is overridden by the user via TAR_CMD,
+        // it may consume many pids. Later, when we invoke the "criu restore" tool,
+        // we must ensure that its PID is lower than APP_ROOT_PID, otherwise it could
+        // clash with itself.
+        // We set ns_last_pid to APP_ROOT_PID-100 to balance performance and safety:
+        // too low, and we might have to do a PID round trip over pid_max, too high and
+        // we risk set_ns_last_pid and criu to go over APP_ROOT_PID if they are invoked via
+        // bash scripts that do interesting things.
+        // Note that later, we check that criu's pid is indeed lower than APP_ROOT_PID.
+        set_ns_last_pid(APP_ROOT_PID - 100)?;
+    } else {
+        let tar_fs_fd = tar_fs_pipe.as_raw_fd();
+        let result = filesystem::extract(TerminableReader::new(tar_fs_pipe, tar_fs_fd));
+        // If the extraction failed because a download failed, we'd rather
+        // report the download errors.
+        pgrp.try_wait_for_success()?;
+        result?;
+    }
     debug!("Filesystem restored");
-
-    // Because the tar command can be overridden by the user via TAR_CMD,
-    // it may consume many pids. Later, when we invoke the "criu restore" tool,
-    // we must ensure that its PID is lower than APP_ROOT_PID, otherwise it could
-    // clash with itself.
-    // We set ns_last_pid to APP_ROOT_PID-100 to balance performance and safety:
-    // too low, and we might have to do a PID round trip over pid_max, too high and
-    // we risk set_ns_last_pid and criu to go over APP_ROOT_PID if they are invoked via
-    // bash scripts that do interesting things.
-    // Note that later, we check that criu's pid is indeed lower than APP_ROOT_PID.
-    set_ns_last_pid(APP_ROOT_PID - 100)?;
+    phases.end();
 
     // The file system is back, including the application configuration containing user-defined
     // preserved-paths, and application time offset.
@@ -269,7 +372,14 @@ fn restore(
     let (duration_since_checkpoint, previously_inherited_resources) = {
         let old_config = AppConfig::restore()?;
         preserved_paths.extend(old_config.preserved_paths);
+        let preserve_options = preserve_options.merge(old_config.preserve_options);
         let passphrase_file = passphrase_file.or(old_config.passphrase_file);
+        // Without a config file, we keep using the one of the previous run.
+        let config_file = if config_file == ConfigFile::default() {
+            old_config.config_file
+        } else {
+            config_file
+        };
 
         let previously_inherited_resources = old_config.inherited_resources;
         let current_inherited_resources = criu::InheritableResources::current()?;
@@ -283,12 +393,27 @@ fn restore(
         let config = AppConfig {
             image_url: image_url.to_string(),
             preserved_paths,
+            preserve_options,
             passphrase_file,
             created_at: SystemTime::now(),
             app_clock: old_config.app_clock,
             inherited_resources: current_inherited_resources,
+            config_file,
         };
         config.save()?;
+        LastCheckpoint::remove()?;
+        StopMode::remove()?;
+
+        // The next incremental checkpoint builds on the layers we restored.
+        if fs_layers.is_empty() {
+            FsLayers::remove()?;
+        } else {
+            FsLayers {
+                image_url: config.image_url.clone(),
+                layers: fs_layers.into_iter().map(|(name, _)| name).collect(),
+                index: FileIndex::scan(&config.preserved_paths, &config.preserve_options)?,
+            }.save()?;
+        }
 
         // old_config.created contains the date when checkpoint happened.
         // It is a wall clock time coming from another machine.
@@ -323,9 +448,11 @@ fn restore(
     // We start the ns_last_pid daemon here. Note that we join_as_daemon() instead of join(),
     // this is so we don't wait for it in wait_for_success().
     debug!("Starting set_ns_last_pid server");
+    phases.start("pid_control");
     spawn_set_ns_last_pid_server()?.join_as_daemon(&mut pgrp);
 
     debug!("Continuing reading image in memory...");
+    phases.start("download");
 
     // `check_pgrp_err()` is useful to report the process group error,
     // which is a more interesting error to report than the error of wait_for_stats(),
@@ -353,6 +480,7 @@ fn restore(
     // Restore application processes.
     // We become the parent of the application as CRIU is configured to use CLONE_PARENT.
     debug!("Restoring processes");
+    phases.start("criu_restore");
     criu::criu_restore_cmd(leave_stopped, &previously_inherited_resources)
         .enable_stderr_logging("criu")
         .spawn()
@@ -370,10 +498,14 @@ fn restore(
     // If there's an issue, kill the app if it's still laying around.
     // We might want to check that we are the parent of the process with pid APP_ROOT_PID,
     // otherwise, we might be killing an innocent process. But that would be racy anyways.
+    // CRIU is terminated first, so that it doesn't create processes while we kill the tree
+    // (e.g., when a termination is requested).
     if let Err(e) = pgrp.wait_for_success() {
+        let _ = pgrp.terminate();
         let _ = kill_process_tree(Pid::from_raw(APP_ROOT_PID), signal::SIGKILL);
         return Err(e);
     }
+    phases.end();
 
     info!(
         "Application is ready, restore took {:.1}s",
@@ -386,20 +518,26 @@ fn restore(
 fn run_from_scratch(
     image_url: ImageUrl,
     preserved_paths: HashSet<PathBuf>,
+    preserve_options: PreserveOptions,
     passphrase_file: Option<PathBuf>,
     app_cmd: Vec<OsString>,
+    config_file: ConfigFile,
 ) -> Result<()> {
     let inherited_resources = criu::InheritableResources::current()?;
 
     let config = AppConfig {
         image_url: image_url.to_string(),
         preserved_paths,
+        preserve_options,
         passphrase_file,
         app_clock: 0,
         created_at: SystemTime::now(),
         inherited_resources,
+        config_file,
     };
     config.save()?;
+    LastCheckpoint::remove()?;
+    StopMode::remove()?;
 
     virt::time::ConfigPath::default().write_intial()?;
     virt::enable_system_wide_virtualization()?;
@@ -465,6 +603,7 @@ pub fn determine_run_mode(store: &dyn Store, allow_bad_image_version: bool) -> R
     Ok(match fetch_result {
         ManifestFetchResult::Some(img_manifest) => {
             debug!("Image manifest found: {}", img_manifest);
+            store.on_generation_selected(&img_manifest)?;
             RunMode::Restore { img_manifest }
         }
         ManifestFetchResult::VersionMismatch { fetched, desired } => {
@@ -500,11 +639,13 @@ fn do_run(
     image_url: ImageUrl,
     app_args: Option<Vec<OsString>>,
     preserved_paths: HashSet<PathBuf>,
+    preserve_options: PreserveOptions,
     tcp_listen_remaps: Vec<String>,
     passphrase_file: Option<PathBuf>,
     no_restore: bool,
     allow_bad_image_version: bool,
     leave_stopped: bool,
+    config_file: ConfigFile,
 ) -> Result<()> {
     // Holding the `with_checkpoint_restore_lock` lock (done by caller) while
     // invoking any process (e.g., `criu_check_cmd`) is preferrable to avoid
@@ -528,14 +669,23 @@ fn do_run(
         RunMode::FromScratch
     } else {
         debug!("Fetching image manifest for {}", image_url);
-        determine_run_mode(&*store, allow_bad_image_version)
-            .context(ExitCode(EXIT_CODE_RESTORE_FAILURE))?
+        let fetch_started_at = Instant::now();
+        let run_mode = determine_run_mode(&*store, allow_bad_image_version)
+            .context(ExitCode(EXIT_CODE_RESTORE_FAILURE))?;
+        record_phase("restore", "manifest_fetch", fetch_started_at.elapsed());
+        run_mode
     };
 
     match (run_mode, app_args) {
         (RunMode::Restore { img_manifest }, _) => {
+            trace::link_to_checkpoint(&img_manifest.shard_prefix);
             let shard_download_cmds =
                 shard::download_cmds(&img_manifest, passphrase_file.as_ref(), &*store)?;
+            let fs_layers = img_manifest.fs_layers.iter()
+                .map(|name| Ok((name.clone(), shard::download_cmd(
+                    &img_manifest, passphrase_file.as_ref(), &*store, name)?)))
+                .collect::<Result<Vec<_>>>()?;
+            let last_checkpoint = LastCheckpoint::from_manifest(image_url.to_string(), &img_manifest);
 
             with_metrics(
                 "restore",
@@ -543,10 +693,13 @@ fn do_run(
                     restore(
                         image_url,
                         preserved_paths,
+                        preserve_options,
                         tcp_listen_remaps,
                         passphrase_file,
                         shard_download_cmds,
-                        leave_stopped
+                        fs_layers,
+                        leave_stopped,
+                        config_file,
                     )
                     .context(ExitCode(EXIT_CODE_RESTORE_FAILURE))
                 },
@@ -557,6 +710,14 @@ fn do_run(
                     })
                 },
             )?;
+
+            // The record of the checkpoint we restored from. restore() removed
+            // the one of the previous application, if any.
+            if let Some(last_checkpoint) = last_checkpoint {
+                if let Err(e) = last_checkpoint.save() {
+                    warn!("{:#}", e);
+                }
+            }
         }
         (RunMode::FromScratch, None) => {
             bail!("No application to restore, but running in restore-only mode, aborting")
@@ -569,8 +730,10 @@ fn do_run(
                     run_from_scratch(
                         image_url,
                         preserved_paths,
+                        preserve_options,
                         passphrase_file,
                         app_args,
+                        config_file,
                     )
                 },
                 |_| json!({}),
@@ -618,19 +781,58 @@ impl super::CLI for Run {
         let inner = || -> Result<()> {
             let Self {
                 image_url,
+                config,
                 app_args,
                 on_app_ready_cmd,
                 no_restore,
                 allow_bad_image_version,
                 passphrase_file,
                 preserved_paths,
+                preserve_options,
                 tcp_listen_remap,
                 leave_stopped,
                 verbose: _,
+                log_format: _,
                 app_name,
                 no_container,
+                control_listen,
+                metrics_listen,
+                metrics_textfile,
             } = self;
 
+            // The config given with --config may hold the image URL. Otherwise,
+            // we look for a config next to the image once we know where it is.
+            let mut app_args = app_args;
+            let config_file = config.as_deref().map(ConfigFile::load).transpose()?;
+            if let Some(ref config_file) = config_file {
+                config_file.apply_env();
+                if app_args.is_empty() {
+                    app_args = config_file.run.app_args();
+                }
+            }
+            let image_url = image_url.or_else(|| config_file.as_ref().and_then(|c| c.image_url.clone()));
+
+            let image_url = match (image_url, app_args.as_slice()) {
+                (Some(image_url), _) => image_url,
+                (None, []) => bail!("--image-url is necessary when running in restore-only mode"),
+                (None, app_args) => {
+                    let image_path = DEFAULT_IMAGE_DIR.join(default_image_name(app_args)?);
+                    let image_url = format!("file:{}", image_path.display());
+                    info!("image-url is {}", image_url);
+                    image_url
+                }
+            };
+            let image_url = ImageUrl::parse(&image_url)?;
+
+            let config_file = match config_file {
+                Some(config_file) => config_file,
+                None => ConfigFile::discover(&image_url)?.unwrap_or_default(),
+            };
+            config_file.apply_env();
+            if app_args.is_empty() {
+                app_args = config_file.run.app_args();
+            }
+
             // We allow app_args to be empty. This indicates a restore-only mode.
             let app_args = if app_args.is_empty() {
                 info!("Running in restore-only mode as no command is given");
@@ -640,17 +842,40 @@ impl super::CLI for Run {
                 Some(app_args)
             };
 
-            let image_url = match (image_url, app_args.as_ref()) {
-                (Some(image_url), _) => image_url,
-                (None, None) => bail!("--image-url is necessary when running in restore-only mode"),
-                (None, Some(app_args)) => {
-                    let image_path = DEFAULT_IMAGE_DIR.join(default_image_name(app_args)?);
-                    let image_url = format!("file:{}", image_path.display());
-                    info!("image-url is {}", image_url);
-                    image_url
-                }
+            // Options given on the command line take precedence over the config file.
+            let app_name = app_name.or_else(|| config_file.run.app_name.clone());
+            let no_container = no_container || config_file.run.no_container;
+            let on_app_ready_cmd = on_app_ready_cmd.or_else(|| config_file.run.on_app_ready.clone());
+            let passphrase_file = passphrase_file.or_else(|| config_file.passphrase_file.clone());
+            let preserved_paths = preserved_paths.into_iter()
+                .chain(config_file.preserve_path.iter().cloned());
+            let preserve_options = preserve_options.merge(config_file.preserve_options()?);
+            let tcp_listen_remap = if tcp_listen_remap.is_empty() {
+                config_file.run.tcp_listen_remap.clone()
+            } else {
+                tcp_listen_remap
             };
-            let image_url = ImageUrl::parse(&image_url)?;
+            let control_listen = match control_listen {
+                Some(addr) => Some(addr),
+                None => config_file.run.control_listen.as_deref().map(str::parse).transpose()
+                    .context("Invalid control-listen in config")?,
+            };
+            let metrics_listen = match metrics_listen {
+                Some(addr) => Some(addr),
+                None => config_file.run.metrics_listen.as_deref().map(str::parse).transpose()
+                    .context("Invalid metrics-listen in config")?,
+            };
+            let metrics_textfile = metrics_textfile.or_else(|| config_file.run.metrics_textfile.clone());
+
+            // We listen before entering the container so that the path of the
+            // unix socket is the one seen by the caller.
+            let mut control_listeners = vec![];
+            if let Some(ref addr) = control_listen {
+                control_listeners.push(ControlListener::bind(addr)?);
+            }
+            if let Some(ref addr) = metrics_listen {
+                control_listeners.push(ControlListener::bind_metrics(addr)?);
+            }
 
             let nscaps = container::ns_capabilities()?;
             // Note: the following may fork a child to enter the new PID namespace,
@@ -676,13 +901,17 @@ impl super::CLI for Run {
             }
 
             let preserved_paths = preserved_paths.into_iter().collect();
+            preserve_options.validate()?;
 
-            let daemon = FastFreezeListener::bind()?.into_daemon()?;
+            // The metrics are kept from here, so that the restore is accounted for.
+            prometheus::enable(metrics_textfile)?;
+            let samples = SampleReceiver::bind()?;
+            let daemon = FastFreezeListener::bind()?.into_daemon(control_listeners, samples)?;
 
             with_checkpoint_restore_lock(|| do_run(
-                image_url, app_args, preserved_paths, tcp_listen_remap,
+                image_url, app_args, preserved_paths, preserve_options, tcp_listen_remap,
                 passphrase_file, no_restore, allow_bad_image_version,
-                leave_stopped))?;
+                leave_stopped, config_file))?;
 
             if let Some(on_app_ready_cmd) = on_app_ready_cmd {
                 // Fire and forget.
@@ -690,11 +919,24 @@ impl super::CLI for Run {
                     .spawn()?;
             }
 
-            let app_exit_result = monitor_child(Pid::from_raw(APP_ROOT_PID));
+            let mut app_exit_result = monitor_child(Pid::from_raw(APP_ROOT_PID));
             if app_exit_result.is_ok() {
                 info!("Application exited with exit_code=0");
             }
 
+            // Exiting tears down the container. If the stop command is at work,
+            // we let it finish by waiting on the lock that it holds.
+            if STOP_REQUEST_PATH.exists() {
+                match with_checkpoint_restore_lock(StopMode::load) {
+                    Ok(Some(mode)) => {
+                        app_exit_result = Err(anyhow!("Application stopped by the stop command ({})", mode)
+                            .context(ExitCode(EXIT_CODE_STOPPED)));
+                    }
+                    Ok(None) => {},
+                    Err(e) => error!("{:#}", e),
+                }
+            }
+
             let _ = daemon.stop();
             // The existance of the app config indicates if the app may b
             // running (see is_app_running()), so it's better to take it out.
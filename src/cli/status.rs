@@ -0,0 +1,202 @@
+//  Copyright 2020 Two Sigma Investments, LP.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+use anyhow::Result;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+use chrono::{DateTime, Utc};
+use nix::unistd::Pid;
+use structopt::StructOpt;
+use serde::Serialize;
+use crate::{
+    consts::*,
+    container,
+    image_streamer::Stats,
+    lock::is_checkpoint_restore_lock_held,
+    signal::get_process_tree,
+};
+use super::{
+    checkpoint::LastCheckpoint,
+    run::{AppConfig, is_app_running},
+};
+
+/// Show the state of the application, and of its last checkpoint
+#[derive(StructOpt, PartialEq, Debug, Serialize)]
+pub struct Status {
+    /// Print the status in JSON
+    #[structopt(long)]
+    json: bool,
+
+    /// Verbosity. Can be repeated
+    #[structopt(short, long, parse(from_occurrences))]
+    pub verbose: u8,
+
+    /// Show the specified application. See the run command help about
+    /// --app-name for more details.
+    #[structopt()]
+    app_name: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct AppStatus {
+    running: bool,
+    /// A checkpoint or a restore is in progress
+    locked: bool,
+    processes: Vec<ProcessStatus>,
+    rss_mb: f64,
+    config: Option<ConfigStatus>,
+    last_checkpoint: Option<CheckpointStatus>,
+}
+
+#[derive(Serialize)]
+struct ProcessStatus {
+    pid: i32,
+    name: String,
+    rss_mb: f64,
+}
+
+#[derive(Serialize)]
+struct ConfigStatus {
+    image_url: String,
+    preserved_paths: Vec<PathBuf>,
+    app_clock_sec: f64,
+    /// When the app config was written: when the application started, or
+    /// when the last checkpoint started.
+    updated_at: String,
+}
+
+#[derive(Serialize)]
+struct CheckpointStatus {
+    image_url: String,
+    completed_at: String,
+    age_sec: f64,
+    duration_sec: f64,
+    stats: Stats,
+}
+
+fn format_time(time: SystemTime) -> String {
+    DateTime::<Utc>::from(time).to_rfc3339()
+}
+
+fn age(time: SystemTime) -> Duration {
+    SystemTime::now().duration_since(time).unwrap_or_default()
+}
+
+fn process_status(pid: Pid) -> ProcessStatus {
+    let proc_dir = Path::new("/proc").join(pid.to_string());
+    let name = fs::read_to_string(proc_dir.join("comm"))
+        .map(|s| s.trim().to_string())
+        .unwrap_or_default();
+    // The line is of the form "VmRSS:    1234 kB". It's absent for zombies.
+    let rss_kb = fs::read_to_string(proc_dir.join("status")).unwrap_or_default()
+        .lines()
+        .find_map(|line| line.strip_prefix("VmRSS:"))
+        .and_then(|v| v.trim().trim_end_matches("kB").trim().parse::<u64>().ok())
+        .unwrap_or(0);
+    ProcessStatus { pid: pid.as_raw(), name, rss_mb: (rss_kb * KB as u64) as f64 / MB as f64 }
+}
+
+impl AppStatus {
+    pub fn current() -> Result<Self> {
+        let running = is_app_running();
+
+        let processes: Vec<_> = if running {
+            get_process_tree(Pid::from_raw(APP_ROOT_PID))?.into_iter()
+                .map(process_status)
+                .collect()
+        } else {
+            vec![]
+        };
+        let rss_mb = processes.iter().fold(0.0, |sum, p| sum + p.rss_mb);
+
+        // The lock file lives in NO_PRESERVE_FF_DIR, which only exists once
+        // the run command has started.
+        let locked = NO_PRESERVE_FF_DIR.exists() && is_checkpoint_restore_lock_held()?;
+
+        let config = if AppConfig::exists() {
+            let config = AppConfig::restore()?;
+            let mut preserved_paths: Vec<_> = config.preserved_paths.into_iter().collect();
+            preserved_paths.sort();
+            Some(ConfigStatus {
+                image_url: config.image_url,
+                preserved_paths,
+                app_clock_sec: Duration::from_nanos(config.app_clock.max(0) as u64).as_secs_f64(),
+                updated_at: format_time(config.created_at),
+            })
+        } else {
+            None
+        };
+
+        let last_checkpoint = LastCheckpoint::load()?.map(|c| CheckpointStatus {
+            image_url: c.image_url,
+            completed_at: format_time(c.completed_at),
+            age_sec: age(c.completed_at).as_secs_f64(),
+            duration_sec: c.duration_sec,
+            stats: c.stats,
+        });
+
+        Ok(Self { running, locked, processes, rss_mb, config, last_checkpoint })
+    }
+
+    fn show(&self) {
+        if self.running {
+            println!("Application:      running, {} processes, {:.1} MiB RSS",
+                     self.processes.len(), self.rss_mb);
+            for p in &self.processes {
+                println!("    {:>7} {:<16} {:.1} MiB", p.pid, p.name, p.rss_mb);
+            }
+        } else {
+            println!("Application:      not running");
+        }
+
+        if self.locked {
+            println!("In progress:      checkpoint or restore");
+        }
+
+        if let Some(ref config) = self.config {
+            println!("Image URL:        {}", config.image_url);
+            let paths: Vec<_> = config.preserved_paths.iter().map(|p| p.display().to_string()).collect();
+            println!("Preserved paths:  {}", if paths.is_empty() { "none".to_string() } else { paths.join(", ") });
+            println!("App clock:        {:.1}s", config.app_clock_sec);
+        }
+
+        match self.last_checkpoint {
+            Some(ref c) => println!(
+                "Last checkpoint:  {} ({:.0}s ago), to {}, took {:.1}s, {:.0} MiB uncompressed",
+                c.completed_at, c.age_sec, c.image_url, c.duration_sec, c.stats.total_size_mb),
+            None => println!("Last checkpoint:  none since the application started"),
+        }
+    }
+}
+
+impl super::CLI for Status {
+    fn run(self) -> Result<()> {
+        let Self { json, app_name, verbose: _ } = self;
+
+        // When no application is running, there is no container to enter,
+        // but we can still report what's left of the last run.
+        container::maybe_nsenter_app_if_any(app_name.as_ref())?;
+
+        let status = AppStatus::current()?;
+        if json {
+            println!("{}", serde_json::to_string_pretty(&status)?);
+        } else {
+            status.show();
+        }
+        Ok(())
+    }
+}
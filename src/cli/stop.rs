@@ -0,0 +1,220 @@
+//  Copyright 2020 Two Sigma Investments, LP.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+use anyhow::{Result, Context};
+use std::{
+    fmt, fs, io,
+    time::{Duration, Instant},
+};
+use nix::{
+    sys::signal,
+    unistd::Pid,
+};
+use structopt::StructOpt;
+use serde::{Serialize, Deserialize};
+use crate::{
+    consts::*,
+    logger::LogFormat,
+    container,
+    image::ImageManifest,
+    lock::with_checkpoint_restore_lock,
+    signal::{kill_process_tree, get_proc_state},
+    store::ImageUrl,
+};
+use super::{
+    checkpoint::Checkpoint,
+    run::{AppConfig, is_app_running},
+};
+
+/// Stop the application. The run command exits with exit_code=172
+#[derive(StructOpt, PartialEq, Debug, Serialize)]
+pub struct Stop {
+    /// Checkpoint the application before stopping it. The checkpoint is done
+    /// with the default options of the checkpoint command.
+    #[structopt(long, conflicts_with = "discard")]
+    checkpoint: bool,
+
+    /// Delete the image of the application, so that the next run command
+    /// starts the application from scratch
+    #[structopt(long)]
+    discard: bool,
+
+    /// Number of seconds to wait for the application to exit once sent SIGTERM,
+    /// before sending SIGKILL. Decimals are allowed. Defaults to 3
+    #[structopt(short, long)]
+    grace_period: Option<f64>,
+
+    /// Verbosity. Can be repeated
+    #[structopt(short, long, parse(from_occurrences))]
+    pub verbose: u8,
+
+    /// Log format. Possible values are [text, json]. Defaults to text,
+    /// or to FF_LOG_FORMAT when set. Log files use the same format.
+    #[structopt(long)]
+    pub log_format: Option<LogFormat>,
+
+    /// Stop the specified application. See the run command help about
+    /// --app-name for more details.
+    #[structopt()]
+    app_name: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum StopMode {
+    /// SIGTERM, and SIGKILL after a grace period
+    Graceful,
+    /// Checkpoint, and SIGKILL
+    Checkpoint,
+    /// Delete the image, and stop gracefully
+    Discard,
+}
+
+impl fmt::Display for StopMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StopMode::Graceful   => write!(f, "graceful"),
+            StopMode::Checkpoint => write!(f, "checkpoint"),
+            StopMode::Discard    => write!(f, "discard"),
+        }
+    }
+}
+
+// The stop request tells the run command why the application exited. The run
+// command is the init process of the container, and when it exits, the stop
+// command gets killed. To avoid this, the stop command holds the
+// checkpoint/restore lock until it is done, and the run command takes the lock
+// before exiting.
+
+impl StopMode {
+    fn save(self) -> Result<()> {
+        fs::write(&*STOP_REQUEST_PATH, serde_json::to_string(&self)?)
+            .with_context(|| format!("Failed to write {}", STOP_REQUEST_PATH.display()))
+    }
+
+    /// Returns None when the application was not stopped by the stop command.
+    pub fn load() -> Result<Option<Self>> {
+        match fs::read(&*STOP_REQUEST_PATH) {
+            Ok(content) => Ok(Some(serde_json::from_slice(&content)?)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("Failed to read {}", STOP_REQUEST_PATH.display())),
+        }
+    }
+
+    /// Called when the application starts, as a previous request would be stale.
+    pub fn remove() -> Result<()> {
+        match fs::remove_file(&*STOP_REQUEST_PATH) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e)
+                .with_context(|| format!("Failed to remove {}", STOP_REQUEST_PATH.display())),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn has_app_exited() -> bool {
+    // The application root process is a zombie until the run command reaps it.
+    !matches!(get_proc_state(Pid::from_raw(APP_ROOT_PID)), Ok(state) if state != 'Z')
+}
+
+/// Sends SIGTERM to the application, and SIGKILL if it's still running after
+/// `grace_period`.
+fn terminate_app(grace_period: Duration) -> Result<()> {
+    let app_pid = Pid::from_raw(APP_ROOT_PID);
+
+    debug!("Sending SIGTERM to the application");
+    kill_process_tree(app_pid, signal::SIGTERM)
+        .context("Failed to terminate application")?;
+
+    let deadline = Instant::now() + grace_period;
+    while Instant::now() < deadline {
+        if has_app_exited() {
+            return Ok(());
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+
+    info!("Application still running after {:.1}s, sending SIGKILL", grace_period.as_secs_f64());
+    kill_process_tree(app_pid, signal::SIGKILL)
+        .context("Failed to kill application")
+}
+
+/// Deletes the image the application would be restored from.
+fn discard_image() -> Result<()> {
+    let image_url = ImageUrl::parse(&AppConfig::restore()?.image_url)?;
+    let store = image_url.store();
+    store.prepare(false)?;
+    if ImageManifest::delete_from_store(&*store)? {
+        info!("Deleted image {}", image_url);
+    } else {
+        info!("No image to delete at {}", image_url);
+    }
+    Ok(())
+}
+
+/// Stops the application. The caller must have entered the application
+/// namespaces, and must hold the checkpoint/restore lock.
+pub fn stop_app(mode: StopMode, grace_period: Duration) -> Result<()> {
+    ensure!(is_app_running(), "Error: No application is running");
+    info!("Stopping application ({})", mode);
+
+    match mode {
+        StopMode::Checkpoint => {
+            mode.save()?;
+            // Default options, as if `fastfreeze checkpoint` was invoked.
+            Checkpoint::from_iter(&["checkpoint"]).checkpoint_app()
+                .inspect_err(|_| { let _ = StopMode::remove(); })
+        }
+        StopMode::Graceful => {
+            mode.save()?;
+            terminate_app(grace_period)
+        }
+        StopMode::Discard => {
+            // The image is deleted first, while the application container is still up.
+            discard_image()?;
+            mode.save()?;
+            terminate_app(grace_period)
+        }
+    }
+}
+
+impl Stop {
+    pub fn mode(&self) -> StopMode {
+        match (self.checkpoint, self.discard) {
+            (true, _) => StopMode::Checkpoint,
+            (_, true) => StopMode::Discard,
+            _ => StopMode::Graceful,
+        }
+    }
+
+    pub fn grace_period(&self) -> Result<Duration> {
+        Ok(match self.grace_period {
+            Some(secs) => {
+                ensure!(secs >= 0.0, "The grace period must be positive");
+                Duration::from_secs_f64(secs)
+            }
+            None => Duration::from_secs(KILL_GRACE_PERIOD_SECS),
+        })
+    }
+}
+
+impl super::CLI for Stop {
+    fn run(self) -> Result<()> {
+        let mode = self.mode();
+        let grace_period = self.grace_period()?;
+
+        container::maybe_nsenter_app(self.app_name.as_ref())?;
+
+        with_checkpoint_restore_lock(|| stop_app(mode, grace_period))
+    }
+}
@@ -13,13 +13,13 @@
 //  limitations under the License.
 
 use crate::util::{gen_random_alphanum_string, get_home_dir};
-use std::{path::PathBuf, time::Instant};
+use std::{path::PathBuf, time::{Duration, Instant}};
 
 // This file gathers all fastfreeze hard-coded settings
 
 /// The image version must be bumped when libvirttime or libvirtcpuid change,
 /// or when the `ImageManifest` format changes.
-pub const CURRENT_IMG_VERSION: &str = "2021-03-22";
+pub const CURRENT_IMG_VERSION: &str = "2026-10-18";
 
 // We compute the paths at runtime. It improves readability compared to using
 // macros at compile time.
@@ -54,6 +54,14 @@ lazy_static! {
     // XXX When changing this socket path, CRIU must be changed and recompiled.
     pub static ref NS_LAST_PID_SOCK_PATH: PathBuf = NO_PRESERVE_FF_DIR.join("set_ns_last_pid.sock");
     pub static ref LOCK_FILE_PATH: PathBuf        = NO_PRESERVE_FF_DIR.join("lock");
+    // Outcome of the last checkpoint, for the status command
+    pub static ref LAST_CHECKPOINT_PATH: PathBuf  = NO_PRESERVE_FF_DIR.join("last-checkpoint.json");
+    // Written by the stop command, so that the run command knows why the application exited
+    pub static ref STOP_REQUEST_PATH: PathBuf     = NO_PRESERVE_FF_DIR.join("stop-request");
+    // Index of the preserved files, for incremental checkpoints
+    pub static ref FS_INDEX_PATH: PathBuf         = NO_PRESERVE_FF_DIR.join("fs-index");
+    // The checkpoint command sends its metrics to the run command via this socket
+    pub static ref METRICS_SOCKET_PATH: PathBuf   = NO_PRESERVE_FF_DIR.join("metrics.sock");
 
     // CONTAINERS_DIR holds container directories. Each is a private
     // /var/tmp/fastfreeze directory for a given container
@@ -73,16 +81,31 @@ pub const CONTAINER_SOCK: &str = "FASTFREEZE_SOCK_FD";
 
 /// When storing images, we use this filename to store our manifest
 pub const MANIFEST_FILE_NAME: &str = "manifest.json";
+/// Config files looked up next to the image when `--config` is not given, in order
+pub const CONFIG_FILE_NAMES: &[&str] = &["fastfreeze.toml", "fastfreeze.json"];
+/// `checkpoint --dry-run` writes this empty file to check that the store is writable
+pub const STORE_PROBE_FILE_NAME: &str = "dry-run-probe";
 
 /// Number of seconds to wait for processes to respond to a SIGTERM before sending a SIGKILL
 pub const KILL_GRACE_PERIOD_SECS: u64 = 3;
 
+/// Number of seconds to wait for the metrics sinks to send queued events when exiting
+pub const METRICS_FLUSH_TIMEOUT_SECS: u64 = 2;
+
+/// Number of seconds given to export the trace spans, including when exiting
+pub const TRACE_EXPORT_TIMEOUT_SECS: u64 = 2;
+
 /// Exit code we return when encountering a fatal error.
 /// We use 170 to distinguish from the application error codes.
 pub const EXIT_CODE_FAILURE: u8 = 170;
 /// Exit code to denote an error during restore. Meaning that passing --no-restore would help
 /// running the application.
 pub const EXIT_CODE_RESTORE_FAILURE: u8 = 171;
+/// Exit code of the run command when the application was stopped with the stop command.
+pub const EXIT_CODE_STOPPED: u8 = 172;
+/// Exit code when a SIGTERM (or SIGINT, SIGHUP) interrupted a restore, a checkpoint,
+/// or an extract. Its processes are torn down, and a half-restored application is killed.
+pub const EXIT_CODE_TERMINATED: u8 = 173;
 
 /// When a process is running, we keep its stderr buffered, so that when an error
 /// comes, we can report the stderr in metrics. This constant indicates how many
@@ -90,6 +113,39 @@ pub const EXIT_CODE_RESTORE_FAILURE: u8 = 171;
 /// 50 lines. Having too many lines makes error triage difficult.
 pub const STDERR_TAIL_NUM_LINES: usize = 50;
 
+/// Number of shards of an image, when not specified
+pub const DEFAULT_NUM_SHARDS: u32 = 4;
+
+/// Defaults of the log retention. A log file is rotated once it reaches
+/// FF_LOG_ROTATE_SIZE_MB. The oldest log files are deleted beyond FF_LOG_MAX_FILES
+/// files, or FF_LOG_MAX_SIZE_MB in total.
+pub const DEFAULT_LOG_ROTATE_SIZE_MB: u64 = 10;
+pub const DEFAULT_LOG_MAX_FILES: usize = 100;
+pub const DEFAULT_LOG_MAX_SIZE_MB: u64 = 50;
+
+/// Default size cap of the local image cache, enabled with FF_IMAGE_CACHE_DIR.
+pub const DEFAULT_IMAGE_CACHE_MAX_SIZE_MB: u64 = 10 * 1024;
+
+/// Number of file system layers of incremental checkpoints, after which we
+/// take a full snapshot. It bounds the restore time.
+pub const MAX_FS_LAYERS: usize = 10;
+
+/// Files under these directories are part of the system, or the container image.
+/// They are not preserved by --preserve-open-files.
+pub const OPEN_FILES_IGNORED_DIRS: &[&str] = &[
+    "/bin", "/boot", "/dev", "/etc", "/lib", "/lib32", "/lib64",
+    "/opt", "/proc", "/sbin", "/sys", "/usr",
+];
+
+/// Default size cap of each deleted file that the application has open or
+/// mapped. CRIU saves these files in the image.
+pub const DEFAULT_GHOST_FILE_LIMIT_MB: u64 = 64;
+
+/// Number of attempts of remote store operations (S3, GCS) before giving up.
+pub const DEFAULT_REMOTE_STORE_RETRY_ATTEMPTS: u32 = 3;
+/// Delay before retrying a failed store operation. It doubles on every retry.
+pub const DEFAULT_RETRY_BACKOFF: Duration = Duration::from_millis(500);
+
 /// The default encryption cipher for encrypting the image.
 /// We can let users define it in the future.
 pub const DEFAULT_ENCRYPTION_CIPHER: &str = "aes-256-cbc";
@@ -504,13 +504,35 @@ fn prepare_pid_namespace() -> Result<()> {
     Ok(())
 }
 
+/// Returns where a path under FF_DIR, as seen from inside a container, lives when
+/// seen from outside. The container directory is mount bound onto FF_DIR.
+pub fn container_path(name: &str, ff_path: &Path) -> PathBuf {
+    // unwrap() is safe: this is only used with the paths of consts.rs under FF_DIR.
+    let relative_path = ff_path.strip_prefix(&*FF_DIR).expect("path not under FF_DIR");
+    CONTAINERS_DIR.join(name).join(relative_path)
+}
+
+fn read_container_pid(name: &str) -> Result<u32> {
+    let pid_file_path = container_path(name, &CONTAINER_PID);
+    let pid = fs::read_to_string(&pid_file_path)
+        .with_context(|| format!("Failed to read {}", pid_file_path.display()))?;
+    pid.trim().parse::<u32>()
+        .with_context(|| format!("Failed to parse {}", pid_file_path.display()))
+}
+
+/// Returns the PID of the container init process, as seen from outside the
+/// container. It returns None when the container is not fully created yet.
+pub fn container_pid(name: &str) -> Result<Option<u32>> {
+    match read_container_pid(name) {
+        Err(e) if e.downcast_ref::<std::io::Error>().map(|e| e.kind()) == Some(ErrorKind::NotFound) => Ok(None),
+        Err(e) => Err(e),
+        Ok(pid) => Ok(Some(pid)),
+    }
+}
+
 fn open_container_proc_dir(name: &str) -> Result<Option<fs::File>> {
     let inner = || -> Result<fs::File> {
-        let pid_file_path = CONTAINERS_DIR.join(name).join("run/pid");
-        let pid = fs::read_to_string(&pid_file_path)
-            .with_context(|| format!("Failed to read {}", pid_file_path.display()))?;
-        let pid = pid.trim().parse::<u32>()
-            .with_context(|| format!("Failed to parse {}", pid_file_path.display()))?;
+        let pid = read_container_pid(name)?;
         let proc_path = format!("/proc/{}", pid);
         let proc_file = fs::File::open(&proc_path)
             .with_context(|| format!("Failed to open {}", proc_path))?;
@@ -524,19 +546,24 @@ fn open_container_proc_dir(name: &str) -> Result<Option<fs::File>> {
     }
 }
 
-pub fn get_running_containers() -> Result<Vec<String>> {
+/// Returns the names of the containers, including the ones that are no longer running.
+pub fn get_containers() -> Result<Vec<String>> {
     // CONTAINERS_DIR may not exist, so we'll get NotFound errors. The code is a
     // little ugly because read_dir() can return errors, and entries can too.
-    let containers = fs::read_dir(&*CONTAINERS_DIR)
+    let mut containers: Vec<String> = fs::read_dir(&*CONTAINERS_DIR)
         .and_then(|entries| entries
             .map(|name| name
                 .map(|n| n.file_name().to_string_lossy().into_owned())
             ).collect()
         ).or_else(|e| if e.kind() == ErrorKind::NotFound { Ok(vec![]) } else { Err(e) })
         .with_context(|| format!("Failed to readdir {}", CONTAINERS_DIR.display()))?;
+    containers.sort();
+    Ok(containers)
+}
 
+pub fn get_running_containers() -> Result<Vec<String>> {
     let mut result = vec![];
-    for name in containers {
+    for name in get_containers()? {
         if open_container_proc_dir(&name)?.is_some() {
             result.push(name);
         }
@@ -553,6 +580,7 @@ pub fn create(name: &str) -> Result<()> {
     }
 
     info!("Creating container for app named `{}`", name);
+    logger::set_app_name(name);
 
     prepare_user_namespace()?;
     prepare_fs_namespace(name)?;
@@ -581,6 +609,7 @@ pub fn create_without_pid_ns() -> Result<()> {
 fn nsenter(name: &str) -> Result<()> {
     let container_proc_dir = open_container_proc_dir(name)?
         .ok_or_else(|| anyhow!("Error: The application `{}` is not running", name))?;
+    logger::set_app_name(name);
 
     // We relocate the log file in the container's log file directory.
     // This is helpful to preserve log files in checkpointed images.
@@ -666,3 +695,14 @@ pub fn maybe_nsenter_app(app_name: Option<&String>) -> Result<()> {
         }
     }
 }
+
+/// Same as `maybe_nsenter_app()`, but it's not an error when no application is
+/// running. This is for commands that report on the application when there is
+/// one. Returns whether we are now in the application's namespaces.
+pub fn maybe_nsenter_app_if_any(app_name: Option<&String>) -> Result<bool> {
+    let has_app = app_name.is_some() || is_app_running() || !get_running_containers()?.is_empty();
+    if has_app {
+        maybe_nsenter_app(app_name)?;
+    }
+    Ok(has_app)
+}
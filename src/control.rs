@@ -0,0 +1,373 @@
+//  Copyright 2020 Two Sigma Investments, LP.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+use anyhow::{Result, Context};
+use std::{
+    fs,
+    io::{Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    os::unix::{
+        fs::FileTypeExt,
+        io::{AsRawFd, RawFd},
+        net::{UnixListener, UnixStream},
+    },
+    path::PathBuf,
+    str::FromStr,
+    time::Duration,
+};
+use serde::Serialize;
+use serde_json::Value;
+use structopt::StructOpt;
+use crate::{
+    cli::{
+        checkpoint::{Checkpoint, LastCheckpoint},
+        status::AppStatus,
+        stop::{Stop, stop_app},
+    },
+    lock::with_checkpoint_restore_lock,
+    metrics::last_events,
+    prometheus,
+};
+
+// The control API is a small HTTP/JSON API served by the run command, so that
+// sidecars and orchestrators can drive the application without entering its
+// container:
+//   POST /checkpoint   Checkpoint the application
+//   GET  /status       Same as `fastfreeze status --json`
+//   GET  /metrics      Metrics in the Prometheus text format (see prometheus.rs).
+//                      With ?format=json, the last metrics event of each action
+//                      of the run command
+//   POST /stop         Stop the application
+// The query string holds the options of the corresponding command. For example,
+// `POST /checkpoint?leave-running&num-shards=8` is the same as
+// `fastfreeze checkpoint --leave-running --num-shards=8`.
+// The API is unauthenticated. It is served on a unix socket or on a loopback
+// address only, and only the options that cannot redirect the image or read
+// files of the caller's choosing (e.g., --image-url, --config) are accepted.
+// The metrics listener serves GET /metrics only, and can listen on any address.
+// Requests are served one at a time by the FastFreeze daemon thread (see
+// ff_socket.rs). A checkpoint delays the other requests until it completes.
+
+const MAX_REQUEST_SIZE: usize = 16*1024;
+const CHECKPOINT_OPTIONS: &[&str] = &["leave-running", "num-shards", "cpu-budget", "incremental", "upload-log"];
+const STOP_OPTIONS: &[&str] = &["checkpoint", "discard", "grace-period"];
+const IO_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Address to serve the control API on: a TCP address, or the path of a unix socket.
+#[derive(PartialEq, Debug, Serialize, Clone)]
+pub enum ControlAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for ControlAddr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s.contains('/') {
+            return Ok(ControlAddr::Unix(PathBuf::from(s)));
+        }
+        let addr = s.to_socket_addrs()
+            .with_context(|| format!("Invalid address `{}`. Expected host:port or a unix socket path", s))?
+            .next()
+            .ok_or_else(|| anyhow!("`{}` does not resolve to any address", s))?;
+        Ok(ControlAddr::Tcp(addr))
+    }
+}
+
+enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+pub struct ControlListener {
+    listener: Listener,
+    /// Only GET /metrics is served, e.g., for a port open to a Prometheus server
+    metrics_only: bool,
+}
+
+trait Stream: Read + Write {}
+impl Stream for TcpStream {}
+impl Stream for UnixStream {}
+
+impl ControlListener {
+    /// Listens for the control API.
+    pub fn bind(addr: &ControlAddr) -> Result<Self> {
+        if let ControlAddr::Tcp(tcp_addr) = addr {
+            ensure!(tcp_addr.ip().is_loopback(),
+                    "The control API can only listen on a loopback address or a unix socket, not {}", tcp_addr);
+        }
+        Ok(Self { listener: Self::listen(addr)?, metrics_only: false })
+    }
+
+    /// Listens for GET /metrics only.
+    pub fn bind_metrics(addr: &ControlAddr) -> Result<Self> {
+        Ok(Self { listener: Self::listen(addr)?, metrics_only: true })
+    }
+
+    fn listen(addr: &ControlAddr) -> Result<Listener> {
+        Ok(match addr {
+            ControlAddr::Tcp(addr) => Listener::Tcp(TcpListener::bind(addr)
+                .with_context(|| format!("Failed to listen on {}", addr))?),
+            ControlAddr::Unix(path) => {
+                // A stale socket of a previous run is replaced, but nothing else.
+                if fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_socket()) {
+                    let _ = fs::remove_file(path);
+                }
+                Listener::Unix(UnixListener::bind(path)
+                    .with_context(|| format!("Failed to bind socket to {}", path.display()))?)
+            }
+        })
+    }
+
+    fn accept(&self) -> Result<Box<dyn Stream>> {
+        // The timeouts prevent an idle client from holding up the daemon.
+        Ok(match &self.listener {
+            Listener::Tcp(listener) => {
+                let (stream, _) = listener.accept()?;
+                stream.set_read_timeout(Some(IO_TIMEOUT))?;
+                stream.set_write_timeout(Some(IO_TIMEOUT))?;
+                Box::new(stream)
+            }
+            Listener::Unix(listener) => {
+                let (stream, _) = listener.accept()?;
+                stream.set_read_timeout(Some(IO_TIMEOUT))?;
+                stream.set_write_timeout(Some(IO_TIMEOUT))?;
+                Box::new(stream)
+            }
+        })
+    }
+
+    /// Accepts a connection, and serves its request.
+    pub fn serve_one(&self) -> Result<()> {
+        let mut stream = self.accept()?;
+        let (status, body) = match Request::read(&mut *stream) {
+            Ok(request) => {
+                debug!("Control API request: {} {}", request.method, request.path);
+                request.handle(self.metrics_only)
+            }
+            Err(e) => (400, Body::error(e)),
+        };
+        write_response(&mut *stream, status, body)
+    }
+}
+
+impl AsRawFd for ControlListener {
+    fn as_raw_fd(&self) -> RawFd {
+        match &self.listener {
+            Listener::Tcp(listener) => listener.as_raw_fd(),
+            Listener::Unix(listener) => listener.as_raw_fd(),
+        }
+    }
+}
+
+/// Invalid options given in the query string
+#[derive(Debug)]
+struct BadRequest(String);
+impl std::error::Error for BadRequest {}
+impl std::fmt::Display for BadRequest {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+enum Body {
+    Json(Value),
+    Text(String),
+}
+
+impl Body {
+    fn error(e: impl std::fmt::Display) -> Self {
+        Body::Json(json!({"error": format!("{:#}", e)}))
+    }
+}
+
+struct Request {
+    method: String,
+    path: String,
+    query: Vec<(String, String)>,
+}
+
+impl Request {
+    /// Reads the request line and the headers. The body, if any, is ignored.
+    fn read(stream: &mut dyn Stream) -> Result<Self> {
+        let mut buf = Vec::new();
+        let mut chunk = [0u8; 1024];
+        while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+            ensure!(buf.len() < MAX_REQUEST_SIZE, "Request too large");
+            let n = stream.read(&mut chunk).context("Failed to read request")?;
+            ensure!(n > 0, "Connection closed before the end of the request");
+            buf.extend_from_slice(&chunk[..n]);
+        }
+
+        let head = String::from_utf8_lossy(&buf);
+        let request_line = head.lines().next().unwrap_or_default();
+        let mut parts = request_line.split_whitespace();
+        let (method, target) = match (parts.next(), parts.next()) {
+            (Some(method), Some(target)) => (method, target),
+            _ => bail!("Malformed request line: {}", request_line),
+        };
+        let (path, query) = match target.find('?') {
+            Some(i) => (&target[..i], &target[i+1..]),
+            None => (target, ""),
+        };
+        let query = url::form_urlencoded::parse(query.as_bytes()).into_owned().collect();
+
+        Ok(Self { method: method.to_string(), path: path.to_string(), query })
+    }
+
+    /// Returns the HTTP status code and the body of the response.
+    fn handle(&self, metrics_only: bool) -> (u16, Body) {
+        let result = match (self.method.as_str(), self.path.as_str()) {
+            ("GET",  "/metrics")    => Ok(self.metrics()),
+            _ if metrics_only       => return (404, Body::error("Not found")),
+            ("POST", "/checkpoint") => self.checkpoint().map(Body::Json),
+            ("GET",  "/status")     => self.status().map(Body::Json),
+            ("POST", "/stop")       => self.stop().map(Body::Json),
+            (_, "/checkpoint") | (_, "/status") | (_, "/metrics") | (_, "/stop") =>
+                return (405, Body::error("Method not allowed")),
+            _ => return (404, Body::error("Not found")),
+        };
+
+        match result {
+            Ok(body) => (200, body),
+            Err(e) => {
+                let status = if e.is::<BadRequest>() { 400 } else { 500 };
+                error!("Control API: {} {} failed: {:#}", self.method, self.path, e);
+                (status, Body::error(e))
+            }
+        }
+    }
+
+    fn metrics(&self) -> Body {
+        if self.query.iter().any(|(key, value)| key == "format" && value == "json") {
+            Body::Json(last_events())
+        } else {
+            Body::Text(prometheus::render())
+        }
+    }
+
+    /// Parses the query string as the command line options of `cmd`.
+    /// Only the options in `allowed` are accepted.
+    fn options<T: StructOpt>(&self, cmd: &str, allowed: &[&str]) -> Result<T> {
+        if let Some((key, _)) = self.query.iter().find(|(key, _)| !allowed.contains(&key.as_str())) {
+            bail!(BadRequest(format!("The option `{}` is not supported by the control API. \
+                                      Supported options: {}", key, allowed.join(", "))));
+        }
+        let args = self.query.iter().map(|(key, value)|
+            if value.is_empty() { format!("--{}", key) } else { format!("--{}={}", key, value) });
+        T::from_iter_safe(std::iter::once(cmd.to_string()).chain(args))
+            .map_err(|e| anyhow!(BadRequest(e.message)))
+    }
+
+    fn checkpoint(&self) -> Result<Value> {
+        let opts: Checkpoint = self.options("checkpoint", CHECKPOINT_OPTIONS)?;
+
+        opts.lock_and_checkpoint_app()?;
+        Ok(json!({"last_checkpoint": LastCheckpoint::load()?}))
+    }
+
+    fn status(&self) -> Result<Value> {
+        Ok(serde_json::to_value(AppStatus::current()?)?)
+    }
+
+    fn stop(&self) -> Result<Value> {
+        let opts: Stop = self.options("stop", STOP_OPTIONS)?;
+        let mode = opts.mode();
+        let grace_period = opts.grace_period().map_err(|e| anyhow!(BadRequest(e.to_string())))?;
+
+        with_checkpoint_restore_lock(|| stop_app(mode, grace_period))?;
+        Ok(json!({"stopped": mode}))
+    }
+}
+
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        _   => "Internal Server Error",
+    }
+}
+
+fn write_response(stream: &mut dyn Stream, status: u16, body: Body) -> Result<()> {
+    let (content_type, body) = match body {
+        Body::Json(value) => ("application/json", serde_json::to_string_pretty(&value)? + "\n"),
+        Body::Text(text) => ("text/plain; version=0.0.4", text),
+    };
+    write!(stream, "HTTP/1.1 {} {}\r\n\
+                    Content-Type: {}\r\n\
+                    Content-Length: {}\r\n\
+                    Connection: close\r\n\
+                    \r\n\
+                    {}", status, reason_phrase(status), content_type, body.len(), body)
+        .context("Failed to write response")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_control_addr() -> Result<()> {
+        assert_eq!(ControlAddr::from_str("127.0.0.1:7070")?,
+                   ControlAddr::Tcp("127.0.0.1:7070".parse()?));
+        assert_eq!(ControlAddr::from_str("/tmp/ff.sock")?,
+                   ControlAddr::Unix(PathBuf::from("/tmp/ff.sock")));
+        assert!(ControlAddr::from_str("7070").is_err());
+        assert!(ControlListener::bind(&ControlAddr::from_str("0.0.0.0:0")?).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_requests() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("ff-control-test-{}.sock", std::process::id()));
+        let listener = ControlListener::bind(&ControlAddr::Unix(path.clone()))?;
+
+        let request = |req: &str| -> Result<String> {
+            let mut client = UnixStream::connect(&path)?;
+            client.write_all(req.as_bytes())?;
+            listener.serve_one()?;
+            let mut response = String::new();
+            client.read_to_string(&mut response)?;
+            Ok(response)
+        };
+
+        assert!(request("GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")?
+            .starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(request("GET /checkpoint HTTP/1.1\r\n\r\n")?
+            .starts_with("HTTP/1.1 405 "));
+        assert!(request("GET /nope HTTP/1.1\r\n\r\n")?
+            .starts_with("HTTP/1.1 404 "));
+        assert!(request("POST /checkpoint?no-such-option HTTP/1.1\r\n\r\n")?
+            .starts_with("HTTP/1.1 400 "));
+        assert!(request("POST /stop?grace-period=-1 HTTP/1.1\r\n\r\n")?
+            .starts_with("HTTP/1.1 400 "));
+        assert!(request("POST /checkpoint?image-url=file:/tmp/x HTTP/1.1\r\n\r\n")?
+            .starts_with("HTTP/1.1 400 "));
+
+        drop(listener);
+        let listener = ControlListener::bind_metrics(&ControlAddr::Unix(path.clone()))?;
+        let mut client = UnixStream::connect(&path)?;
+        client.write_all(b"GET /status HTTP/1.1\r\n\r\n")?;
+        listener.serve_one()?;
+        let mut response = String::new();
+        client.read_to_string(&mut response)?;
+        assert!(response.starts_with("HTTP/1.1 404 "));
+
+        let _ = fs::remove_file(&path);
+        Ok(())
+    }
+}
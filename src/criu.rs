@@ -17,13 +17,20 @@ use std::{
     collections::{HashSet, HashMap},
     os::unix::io::RawFd,
 };
+use nix::unistd::Pid;
 use serde::{Serialize, Deserialize};
 use crate::{
     consts::*,
     process::Command,
-    util::{get_inheritable_fds, readlink_fd, is_term},
+    util::{get_inheritable_fds, readlink_fd, is_term, parse_env_var_or_warn},
+    open_files::{list_open_files, OpenFile},
 };
 
+lazy_static! {
+    pub static ref GHOST_FILE_LIMIT: u64 = parse_env_var_or_warn("FF_GHOST_FILE_LIMIT_MB")
+        .unwrap_or(DEFAULT_GHOST_FILE_LIMIT_MB) * MB as u64;
+}
+
 // Say the application was originally run with "fastfreeze run app | cat".
 // It started with a pipe as stdout. The application may have had duped its fds over its lifetime.
 // When we restore, with the same command, we want to replace the occurances of the old
@@ -106,17 +113,35 @@ impl InheritableResources {
 // CRIU is running under our CPUID virtualization.
 // The CPUID that it detects is virtualized.
 
-pub fn criu_dump_cmd() -> Command {
+fn criu_dump_base_cmd() -> Command {
     let mut cmd = Command::new(&[
         "criu", "dump",
         "--tree", &APP_ROOT_PID.to_string(),
-        "--leave-stopped", // Leave app stopped: we resume app once the filesystem is tarred.
         // The rest are some networking options. In a nutshell, we want all
         // external connections to be closed on restore.
         "--empty-ns", "net", "--tcp-established", "--skip-in-flight", "--tcp-close", "--ext-unix-sk"
     ]);
+    // Deleted files that are open or mapped are saved in the image (ghost files).
+    cmd.arg("--ghost-limit").arg(GHOST_FILE_LIMIT.to_string());
+    cmd
+}
 
-    add_common_criu_opts(&mut cmd);
+pub fn criu_dump_cmd() -> Command {
+    let mut cmd = criu_dump_base_cmd();
+    cmd.arg("--leave-stopped"); // Leave app stopped: we resume app once the filesystem is tarred.
+
+    add_common_criu_opts(&mut cmd, true);
+
+    cmd
+}
+
+/// Checks that the application can be checkpointed, without producing an image.
+/// CRIU freezes the application while inspecting it, and resumes it.
+pub fn criu_dump_check_cmd() -> Command {
+    let mut cmd = criu_dump_base_cmd();
+    cmd.arg("--check-only");
+
+    add_common_criu_opts(&mut cmd, false);
 
     cmd
 }
@@ -137,7 +162,7 @@ pub fn criu_restore_cmd(
         cmd.arg("--leave-stopped");
     }
 
-    add_common_criu_opts(&mut cmd);
+    add_common_criu_opts(&mut cmd, true);
 
     previously_inherited_resources
         .add_remaps_criu_opts(&mut cmd);
@@ -145,7 +170,7 @@ pub fn criu_restore_cmd(
     cmd
 }
 
-fn add_common_criu_opts(cmd: &mut Command) {
+fn add_common_criu_opts(cmd: &mut Command, stream: bool) {
     cmd.arg("--images-dir").arg(&*CRIU_SOCKET_DIR);
     cmd.args(&[
         "--cpu-cap",    // Save and check CPUID information in the image
@@ -155,9 +180,12 @@ fn add_common_criu_opts(cmd: &mut Command) {
         // It can read the build-id in ELF headers during dump, and compare it during restore.
         // Currently, it emits warnings during dump. So we'll skip it for now.
         "--file-validation", "filesize",
-        "--stream",     // Use criu-image-streamer
     ]);
 
+    if stream {
+        cmd.arg("--stream"); // Use criu-image-streamer
+    }
+
     if log_enabled!(log::Level::Trace) {
         cmd.arg("-v4"); // verbose
         cmd.arg("--display-stats");
@@ -169,6 +197,34 @@ fn add_common_criu_opts(cmd: &mut Command) {
         .split_whitespace());
 }
 
+/// Returns the deleted files that the application has open or memory mapped.
+/// CRIU saves their content in the image, and recreates them on restore.
+/// A file is reported once, even if used by multiple processes.
+pub fn ghost_files() -> Result<Vec<OpenFile>> {
+    let mut seen = HashSet::new();
+    Ok(list_open_files(Pid::from_raw(APP_ROOT_PID))?.into_iter()
+        .filter(|f| f.deleted && seen.insert(f.path.clone()))
+        .collect())
+}
+
+/// Fails when a ghost file exceeds the size that CRIU accepts. We'd rather
+/// report it before freezing the application, with a clearer message than CRIU's.
+pub fn check_ghost_files(files: &[OpenFile]) -> Result<()> {
+    for file in files {
+        match file.size {
+            Some(size) => debug!("Deleted file {} ({} bytes) is saved in the image",
+                                 file.path.display(), size),
+            None => debug!("Deleted file {} is saved in the image", file.path.display()),
+        }
+        if let Some(size) = file.size.filter(|s| *s > *GHOST_FILE_LIMIT) {
+            bail!("The application uses the deleted file {} of {:.1} MiB, exceeding the limit of {} MiB. \
+                   Set FF_GHOST_FILE_LIMIT_MB to raise the limit",
+                  file.path.display(), size as f64 / MB as f64, *GHOST_FILE_LIMIT / MB as u64);
+        }
+    }
+    Ok(())
+}
+
 pub fn criu_check_cmd() -> Command {
     Command::new(&["criu", "check"])
 }
@@ -2,7 +2,8 @@ use crate::{
     consts::FF_SOCKET_PATH,
     poller::{Poller, EpollFlags},
     cli::checkpoint::{Checkpoint, do_checkpoint},
-    image::CpuBudget,
+    control::ControlListener,
+    prometheus::SampleReceiver,
 };
 
 use std::os::unix::{
@@ -35,6 +36,8 @@ pub struct FastFreezeListener {
 enum PollType {
     Listener(FastFreezeListener),
     Connection(FastFreezeConnection),
+    Control(ControlListener),
+    Samples(SampleReceiver),
     Stop,
 }
 
@@ -46,11 +49,20 @@ enum PollType {
 // Modify the poller object to include iterators of connection objects so we broadcast
 // functions to these connection
 
-fn main_loop(listener: FastFreezeListener, stop_pipe_r: fs::File) -> Result<()> {
+fn main_loop(
+    listener: FastFreezeListener,
+    control_listeners: Vec<ControlListener>,
+    samples: SampleReceiver,
+    stop_pipe_r: fs::File,
+) -> Result<()> {
     let mut poller = Poller::<PollType>::new()?;
     debug!("FastFreeze Socket: {}, Stop Pipe: {}", listener.listener.as_raw_fd(), stop_pipe_r.as_raw_fd());
     poller.add(stop_pipe_r.as_raw_fd(), PollType::Stop, EpollFlags::EPOLLHUP | EpollFlags::EPOLLIN)?;
     poller.add(listener.listener.as_raw_fd(), PollType::Listener(listener), EpollFlags::EPOLLIN)?;
+    for control in control_listeners {
+        poller.add(control.as_raw_fd(), PollType::Control(control), EpollFlags::EPOLLIN)?;
+    }
+    poller.add(samples.as_raw_fd(), PollType::Samples(samples), EpollFlags::EPOLLIN)?;
 
     // We currently only poll on reads as we don't believe it is reasonable to poll on writes,
     // so we are fine with blocking on writes to the application.
@@ -77,12 +89,18 @@ fn main_loop(listener: FastFreezeListener, stop_pipe_r: fs::File) -> Result<()>
                         if size != 0 {
                             let cp = Checkpoint {
                                 image_url: None, 
+                                config: None,
                                 preserved_paths: vec![] as Vec<std::path::PathBuf>, 
+                                preserve_options: Default::default(),
+                                incremental: false,
+                                upload_log: false,
                                 leave_running: true, 
-                                num_shards: 1, 
-                                cpu_budget: CpuBudget::Medium,
+                                dry_run: false,
+                                num_shards: None,
+                                cpu_budget: None,
                                 passphrase_file: None, 
                                 verbose: 0,
+                                log_format: None,
                                 app_name: None
                             };
                             let _ = do_checkpoint(cp);
@@ -96,6 +114,18 @@ fn main_loop(listener: FastFreezeListener, stop_pipe_r: fs::File) -> Result<()>
                     }
                 }
             }
+            // Control API request. Errors concern a single connection, we keep serving.
+            PollType::Control(control) => {
+                if let Err(e) = control.serve_one() {
+                    error!("Control API: {:#}", e);
+                }
+            }
+            // Metrics of a checkpoint command
+            PollType::Samples(samples) => {
+                if let Err(e) = samples.receive() {
+                    warn!("{:#}", e);
+                }
+            }
             PollType::Stop => {
                 return Ok(());
             }
@@ -142,11 +172,17 @@ impl FastFreezeListener {
         Ok(FastFreezeConnection { socket })
     }
 
-    pub fn into_daemon(self) -> Result<FastFreezeDaemon> {
+    /// `control_listeners` serve the control API, see control.rs.
+    /// `samples` receives the metrics of the checkpoint command, see prometheus.rs.
+    pub fn into_daemon(
+        self,
+        control_listeners: Vec<ControlListener>,
+        samples: SampleReceiver,
+    ) -> Result<FastFreezeDaemon> {
         let (pipe_r, pipe_w) = pipe2(OFlag::O_CLOEXEC)?;
         let thread = std::thread::spawn(move || {
-            main_loop(self, unsafe { fs::File::from_raw_fd(pipe_r) }).expect("Daemon crashed");
+            main_loop(self, control_listeners, samples, unsafe { fs::File::from_raw_fd(pipe_r) }).expect("Daemon crashed");
         });
         Ok(FastFreezeDaemon { stop_pipe_w: unsafe { fs::File::from_raw_fd(pipe_w) }, thread: thread })
     }
-
+}
@@ -13,23 +13,886 @@
 //  See the License for the specific language governing permissions and
 //  limitations under the License.
 
+use anyhow::{Result, Context};
 use std::{
-    path::PathBuf,
-    collections::HashSet,
+    path::{Path, PathBuf},
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    ffi::{CString, OsStr, OsString},
+    io::{self, Read},
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{FileExt, FileTypeExt, MetadataExt},
+        io::AsRawFd,
+    },
+    str::FromStr,
     fs,
 };
+use serde::{Serialize, Deserialize};
+use structopt::StructOpt;
+use tar::{EntryType, Header, HeaderMode};
+use nix::{
+    sys::statvfs::{statvfs, FsFlags},
+    unistd::{geteuid, Pid},
+};
 use crate::{
     consts::*,
     process::{Command, Stdio},
+    open_files::list_open_files,
 };
 
+// The file system is archived in-process, and streamed into the fs.tar pipe of
+// criu-image-streamer. Compared to invoking `tar`:
+// * Files are archived with the size they had when we stat'ed them. Our log file
+//   is part of the archive and grows while we archive, which tar would reject.
+// * Unreadable paths are skipped and reported, instead of failing the checkpoint.
+// * We get the size of each preserved path, reported in the checkpoint metrics.
+//
+// The archive is a GNU tar archive, with sparse files, hardlinks, and xattrs (in
+// PAX headers). It can be extracted with `tar`, which is useful when users
+// override TAR_CMD, as it is used for both archiving and extracting.
+
 lazy_static! {
-    static ref TAR_CMD: String = std::env::var("TAR_CMD")
-        .unwrap_or_else(|_| "tar".to_string());
+    static ref TAR_CMD: Option<String> = std::env::var("TAR_CMD").ok();
+}
+
+/// Sizes and errors gathered while archiving the file system.
+#[derive(Serialize, Deserialize, Default, Debug, Clone)]
+pub struct ArchiveStats {
+    /// Bytes of file content archived for each preserved path
+    pub sizes: BTreeMap<PathBuf, u64>,
+    /// Paths that could not be archived (or only partially), and why
+    pub errors: BTreeMap<PathBuf, String>,
+    /// Number of paths deleted since the previous layer, for incremental archives
+    pub deleted_paths: usize,
+}
+
+impl ArchiveStats {
+    pub fn show(&self) {
+        for (path, size) in &self.sizes {
+            debug!("  {}: {:.1} MiB", path.display(), *size as f64 / MB as f64);
+        }
+        for (path, error) in &self.errors {
+            warn!("Failed to archive {}: {}", path.display(), error);
+        }
+    }
+}
+
+// Options controlling what is preserved of the preserved paths. They are
+// shared by the run and checkpoint commands, and persisted in the app config.
+// Note: a doc comment here would override the about text of these commands.
+#[derive(StructOpt, Serialize, Deserialize, PartialEq, Debug, Clone, Default)]
+pub struct PreserveOptions {
+    /// Glob pattern of files to exclude from the preserved paths, e.g., '*.tmp' or 'cache/**'.
+    /// Patterns without a '/' match file names. Patterns starting with a '/' match
+    /// absolute paths. Other patterns match paths relative to the preserved path.
+    /// May be specified multiple times.
+    #[structopt(long = "preserve-exclude", name = "pattern", number_of_values = 1)]
+    #[serde(default)]
+    pub excludes: Vec<String>,
+
+    /// Maximum size in MiB of the preserved paths. It is checked before checkpointing.
+    #[structopt(long = "preserve-max-size", name = "size_mb")]
+    #[serde(default)]
+    pub max_size_mb: Option<u64>,
+
+    /// What to do when the preserved paths exceed --preserve-max-size.
+    /// Possible values are [fail, warn]. Defaults to fail.
+    #[structopt(long = "preserve-max-size-action", name = "action")]
+    #[serde(default)]
+    pub max_size_action: Option<SizeLimitAction>,
+
+    /// Also preserve the regular files that the application has open or memory
+    /// mapped at checkpoint time, when they are in a writable location outside of
+    /// the system directories (/usr, /etc, /opt, ...).
+    #[structopt(long)]
+    #[serde(default)]
+    pub preserve_open_files: bool,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Copy, Clone)]
+pub enum SizeLimitAction {
+    Fail,
+    Warn,
+}
+
+impl FromStr for SizeLimitAction {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Ok(match s {
+            "fail" => SizeLimitAction::Fail,
+            "warn" => SizeLimitAction::Warn,
+            _ => bail!("Possible values are [fail, warn], not `{}`", s)
+        })
+    }
+}
+
+impl PreserveOptions {
+    /// Combines the options given on the command line with the ones of a
+    /// previous operation. As for preserved paths, excludes accumulate.
+    pub fn merge(mut self, previous: PreserveOptions) -> Self {
+        for pattern in previous.excludes {
+            if !self.excludes.contains(&pattern) {
+                self.excludes.push(pattern);
+            }
+        }
+        self.max_size_mb = self.max_size_mb.or(previous.max_size_mb);
+        self.max_size_action = self.max_size_action.or(previous.max_size_action);
+        self.preserve_open_files |= previous.preserve_open_files;
+        self
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        self.patterns().map(|_| ())
+    }
+
+    fn patterns(&self) -> Result<Vec<glob::Pattern>> {
+        self.excludes.iter()
+            .map(|p| glob::Pattern::new(p)
+                .with_context(|| format!("Invalid --preserve-exclude pattern `{}`", p)))
+            .collect()
+    }
+
+    /// Returns the size of the files that would be archived.
+    pub fn preserved_size(&self, preserved_paths: &HashSet<PathBuf>) -> Result<u64> {
+        let filter = Filter::new(self)?;
+        let mut seen = HashSet::new();
+        Ok(roots(preserved_paths).iter()
+            .map(|root| disk_usage(root, &filter, &mut seen))
+            .sum())
+    }
+
+    /// Fails (or warns) when the preserved paths exceed the size limit.
+    pub fn check_size(&self, preserved_paths: &HashSet<PathBuf>) -> Result<()> {
+        let max_size_mb = match self.max_size_mb {
+            Some(max_size_mb) => max_size_mb,
+            None => return Ok(()),
+        };
+
+        let size_mb = self.preserved_size(preserved_paths)? as f64 / MB as f64;
+        debug!("Preserved paths total {:.1} MiB", size_mb);
+
+        if size_mb > max_size_mb as f64 {
+            let msg = format!("The preserved paths total {:.1} MiB, exceeding --preserve-max-size={} MiB. \
+                               Use --preserve-exclude to exclude unwanted files", size_mb, max_size_mb);
+            match self.max_size_action.unwrap_or(SizeLimitAction::Fail) {
+                SizeLimitAction::Fail => bail!(msg),
+                SizeLimitAction::Warn => warn!("{}", msg),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Decides which paths are excluded from the archive.
+struct Filter {
+    /// Directories that are never preserved
+    excluded_dirs: Vec<PathBuf>,
+    patterns: Vec<glob::Pattern>,
+}
+
+impl Filter {
+    fn new(options: &PreserveOptions) -> Result<Self> {
+        Ok(Self {
+            excluded_dirs: vec![NO_PRESERVE_FF_DIR.clone(), CONTAINERS_DIR.clone()],
+            patterns: options.patterns()?,
+        })
+    }
+
+    fn is_excluded(&self, path: &Path, root: &Path) -> bool {
+        if self.excluded_dirs.iter().any(|d| path.starts_with(d)) {
+            return true;
+        }
+
+        // Our own state is needed to restore. User patterns, e.g., `*.json`,
+        // must not exclude it.
+        if path.starts_with(&*FF_DIR) {
+            return false;
+        }
+
+        let match_options = glob::MatchOptions {
+            case_sensitive: true,
+            require_literal_separator: true,
+            require_literal_leading_dot: false,
+        };
+        self.patterns.iter().any(|pattern| {
+            let s = pattern.as_str();
+            let candidate = if s.starts_with('/') {
+                Some(path)
+            } else if !s.contains('/') {
+                path.file_name().map(Path::new)
+            } else {
+                path.strip_prefix(root).ok()
+            };
+            candidate.is_some_and(|c| pattern.matches_path_with(c, match_options))
+        })
+    }
+}
+
+/// Returns the paths to archive: the preserved paths, and our FF_DIR.
+fn roots(preserved_paths: &HashSet<PathBuf>) -> Vec<PathBuf> {
+    let mut roots: Vec<_> = preserved_paths.iter()
+        .map(|p| Path::new("/").join(p))
+        .chain(std::iter::once(FF_DIR.clone()))
+        .collect();
+    roots.sort();
+    roots.dedup();
+    roots
 }
 
-pub fn tar_cmd(preserved_paths: HashSet<PathBuf>, stdout: fs::File) -> Command {
-    let mut cmd = Command::new(&[&*TAR_CMD]);
+/// Calls `f` on `path` and its descendants that are not excluded.
+/// Unreadable paths are ignored.
+fn walk(path: &Path, root: &Path, filter: &Filter, f: &mut dyn FnMut(&Path, &fs::Metadata)) {
+    if filter.is_excluded(path, root) {
+        return;
+    }
+    let meta = match fs::symlink_metadata(path) {
+        Ok(meta) => meta,
+        Err(_) => return,
+    };
+    f(path, &meta);
+
+    if meta.is_dir() {
+        if let Ok(entries) = fs::read_dir(path) {
+            for entry in entries.filter_map(|e| e.ok()) {
+                walk(&entry.path(), root, filter, f);
+            }
+        }
+    }
+}
+
+/// Returns the size of the files that would be archived under `root`.
+/// Files with multiple links are counted once.
+fn disk_usage(root: &Path, filter: &Filter, seen: &mut HashSet<(u64, u64)>) -> u64 {
+    let mut size = 0;
+    walk(root, root, filter, &mut |_, meta| {
+        if meta.is_file() && seen.insert((meta.dev(), meta.ino())) {
+            size += meta.len();
+        }
+    });
+    size
+}
+
+// Open files
+// ----------
+// With --preserve-open-files, the files that the application has open or
+// memory mapped are preserved in addition to the preserved paths. They are not
+// added to the preserved paths of the app config: once closed, they are no
+// longer preserved.
+
+/// Returns the files that the application has open or memory mapped, which
+/// are not already covered by the preserved paths. Files in the system
+/// directories, on read-only mounts, or excluded by the preserve options are
+/// left out, as well as deleted files.
+pub fn find_open_files(preserved_paths: &HashSet<PathBuf>, options: &PreserveOptions)
+    -> Result<BTreeSet<PathBuf>>
+{
+    let filter = Filter::new(options)?;
+    let roots = roots(preserved_paths);
+    let is_read_only = |path: &Path| statvfs(path)
+        .map_or(true, |s| s.flags().contains(FsFlags::ST_RDONLY));
+
+    Ok(list_open_files(Pid::from_raw(APP_ROOT_PID))?.into_iter()
+        .filter(|f| !f.deleted)
+        .map(|f| f.path)
+        .filter(|path| {
+            !roots.iter().any(|r| path.starts_with(r)) &&
+            !OPEN_FILES_IGNORED_DIRS.iter().any(|d| path.starts_with(d)) &&
+            !filter.is_excluded(path, Path::new("/")) &&
+            !is_read_only(path)
+        })
+        .collect())
+}
+
+// Incremental snapshots
+// ---------------------
+// With `checkpoint --incremental`, the file system is archived in layers that
+// are stored next to the shards, and listed in the image manifest. A layer holds
+// the paths that changed since the previous layer, and the list of deleted paths.
+// Restoring replays the layers in order.
+// Changes are detected by comparing the stat() of each path with the one recorded
+// in a local index when the previous layer was made (or restored). The index is
+// not part of the image, it is rebuilt during restore.
+
+/// Archive entry holding the NUL separated list of paths deleted since the
+/// previous layer. It is not extracted.
+const DELETED_PATHS_ENTRY: &str = ".fastfreeze-deleted-paths";
+
+/// What we compare to detect that a path changed. The ctime catches changes
+/// of permissions, ownership, and link count.
+#[derive(PartialEq, Clone, Copy, Debug)]
+struct FileStamp {
+    ino: u64,
+    size: u64,
+    mtime_ns: i64,
+    ctime_ns: i64,
+    mode: u32,
+}
+
+impl From<&fs::Metadata> for FileStamp {
+    fn from(meta: &fs::Metadata) -> Self {
+        Self {
+            ino: meta.ino(),
+            size: meta.len(),
+            mtime_ns: meta.mtime() * 1_000_000_000 + meta.mtime_nsec(),
+            ctime_ns: meta.ctime() * 1_000_000_000 + meta.ctime_nsec(),
+            mode: meta.mode(),
+        }
+    }
+}
+
+/// Stamps of the paths of a file system snapshot.
+#[derive(Default, Debug)]
+pub struct FileIndex(HashMap<PathBuf, FileStamp>);
+
+impl FileIndex {
+    /// Records the current state of the preserved paths. Used after a restore.
+    pub fn scan(preserved_paths: &HashSet<PathBuf>, options: &PreserveOptions) -> Result<Self> {
+        let filter = Filter::new(options)?;
+        let mut index = Self::default();
+        for root in roots(preserved_paths) {
+            walk(&root, &root, &filter, &mut |path, meta| {
+                index.0.insert(path.to_path_buf(), meta.into());
+            });
+        }
+        Ok(index)
+    }
+}
+
+/// The layers of the last image we produced or restored, and its file index.
+#[derive(Debug)]
+pub struct FsLayers {
+    pub image_url: String,
+    /// Filenames of the layers in the store, oldest first
+    pub layers: Vec<String>,
+    pub index: FileIndex,
+}
+
+#[derive(Serialize, Deserialize)]
+struct FsLayersHeader {
+    image_url: String,
+    layers: Vec<String>,
+}
+
+impl FsLayers {
+    // The file starts with a JSON header line, followed by one record per
+    // path: "<ino> <size> <mtime_ns> <ctime_ns> <mode> <path>\0".
+    // Paths are not necessarily UTF-8, which rules out JSON.
+
+    pub fn save(&self) -> Result<()> {
+        let header = FsLayersHeader { image_url: self.image_url.clone(), layers: self.layers.clone() };
+        let mut content = serde_json::to_vec(&header)?;
+        content.push(b'\n');
+        for (path, s) in &self.index.0 {
+            content.extend_from_slice(format!("{} {} {} {} {} ",
+                s.ino, s.size, s.mtime_ns, s.ctime_ns, s.mode).as_bytes());
+            content.extend_from_slice(path.as_os_str().as_bytes());
+            content.push(0);
+        }
+        fs::write(&*FS_INDEX_PATH, content)
+            .with_context(|| format!("Failed to write {}", FS_INDEX_PATH.display()))
+    }
+
+    /// Returns None when no layers were recorded.
+    pub fn load() -> Result<Option<Self>> {
+        let content = match fs::read(&*FS_INDEX_PATH) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {}", FS_INDEX_PATH.display())),
+        };
+
+        let parse = || -> Option<Self> {
+            let header_end = content.iter().position(|&b| b == b'\n')?;
+            let header: FsLayersHeader = serde_json::from_slice(&content[..header_end]).ok()?;
+            let mut index = FileIndex::default();
+            for record in content[header_end+1..].split(|&b| b == 0).filter(|r| !r.is_empty()) {
+                let mut fields = record.splitn(6, |&b| b == b' ');
+                let mut num = || std::str::from_utf8(fields.next()?).ok()?.parse::<i64>().ok();
+                let stamp = FileStamp {
+                    ino: num()? as u64, size: num()? as u64,
+                    mtime_ns: num()?, ctime_ns: num()?, mode: num()? as u32,
+                };
+                let path = PathBuf::from(OsStr::from_bytes(fields.next()?));
+                index.0.insert(path, stamp);
+            }
+            Some(Self { image_url: header.image_url, layers: header.layers, index })
+        };
+
+        parse().map(Some).ok_or_else(|| anyhow!("{} is malformed", FS_INDEX_PATH.display()))
+    }
+
+    pub fn remove() -> Result<()> {
+        match fs::remove_file(&*FS_INDEX_PATH) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound =>
+                Err(e).with_context(|| format!("Failed to remove {}", FS_INDEX_PATH.display())),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Returns whether the user asked for an external tar command via TAR_CMD.
+pub fn has_external_tar() -> bool {
+    TAR_CMD.is_some()
+}
+
+/// Archives the preserved paths and our FF_DIR into `out`. When `base` is
+/// given, only the paths that changed since `base` are archived, along with
+/// the list of deleted paths. Returns the index of the archived file system.
+pub fn archive(preserved_paths: &HashSet<PathBuf>, options: &PreserveOptions,
+               base: Option<&FileIndex>, out: impl io::Write) -> Result<(ArchiveStats, FileIndex)> {
+    let mut archiver = Archiver {
+        builder: tar::Builder::new(out),
+        filter: Filter::new(options)?,
+        base,
+        index: FileIndex::default(),
+        hardlinks: HashMap::new(),
+        stats: ArchiveStats::default(),
+    };
+
+    for root in roots(preserved_paths) {
+        let mut size = 0;
+        archiver.append_tree(&root, &root, &mut size)?;
+        archiver.stats.sizes.insert(root, size);
+    }
+
+    if let Some(base) = base {
+        archiver.append_deleted_paths(base)
+            .context("Failed to write the file system archive")?;
+    }
+
+    archiver.builder.into_inner()
+        .context("Failed to write the file system archive")?;
+
+    Ok((archiver.stats, archiver.index))
+}
+
+struct Archiver<'a, W: io::Write> {
+    builder: tar::Builder<W>,
+    filter: Filter,
+    /// Index of the previous layer, for incremental archives
+    base: Option<&'a FileIndex>,
+    index: FileIndex,
+    /// Archived paths of files with multiple links, by (dev, ino)
+    hardlinks: HashMap<(u64, u64), PathBuf>,
+    stats: ArchiveStats,
+}
+
+impl<W: io::Write> Archiver<'_, W> {
+    /// Errors related to the source files are recorded in the stats, and the
+    /// path is skipped. Errors writing the archive are returned.
+    fn append_tree(&mut self, path: &Path, root: &Path, size: &mut u64) -> Result<()> {
+        if self.filter.is_excluded(path, root) {
+            return Ok(());
+        }
+
+        let meta = match fs::symlink_metadata(path) {
+            Ok(meta) => meta,
+            Err(e) => { self.record_error(path, e); return Ok(()); }
+        };
+
+        // Paths are stored relative to /, as tar does.
+        // unwrap() is safe, the paths are all absolute.
+        let name = path.strip_prefix("/").unwrap();
+        let stamp = FileStamp::from(&meta);
+        self.index.0.insert(path.to_path_buf(), stamp);
+
+        // Directories are always archived, they are cheap and hold the tree together.
+        let unchanged = !meta.is_dir() &&
+            self.base.and_then(|b| b.0.get(path)) == Some(&stamp);
+        if unchanged {
+            if meta.nlink() > 1 {
+                self.hardlinks.entry((meta.dev(), meta.ino())).or_insert_with(|| name.to_path_buf());
+            }
+        } else {
+            trace!("Archiving {}", path.display());
+            self.append(path, name, &meta, size)
+                .with_context(|| format!("Failed to write {} to the file system archive", path.display()))?;
+        }
+
+        if meta.is_dir() {
+            let mut children = match fs::read_dir(path).and_then(|d| d.collect::<io::Result<Vec<_>>>()) {
+                Ok(children) => children,
+                Err(e) => { self.record_error(path, e); return Ok(()); }
+            };
+            children.sort_by_key(|c| c.file_name());
+            for child in children {
+                self.append_tree(&child.path(), root, size)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn append(&mut self, path: &Path, name: &Path, meta: &fs::Metadata, size: &mut u64) -> io::Result<()> {
+        let file_type = meta.file_type();
+        if file_type.is_socket() {
+            // tar ignores sockets too. They are recreated by the application.
+            return Ok(());
+        }
+
+        let mut header = Header::new_gnu();
+        header.set_metadata_in_mode(meta, HeaderMode::Complete);
+
+        if !meta.is_dir() && meta.nlink() > 1 {
+            if let Some(target) = self.hardlinks.get(&(meta.dev(), meta.ino())) {
+                header.set_entry_type(EntryType::Link);
+                header.set_size(0);
+                return self.builder.append_link(&mut header, name, target);
+            }
+            self.hardlinks.insert((meta.dev(), meta.ino()), name.to_path_buf());
+        }
+
+        match read_xattrs(path) {
+            Ok(xattrs) if !xattrs.is_empty() => self.append_xattrs(&xattrs)?,
+            Ok(_) => {}
+            Err(e) => self.record_error(path, e),
+        }
+
+        if file_type.is_symlink() {
+            let target = match fs::read_link(path) {
+                Ok(target) => target,
+                Err(e) => { self.record_error(path, e); return Ok(()); }
+            };
+            header.set_size(0);
+            self.builder.append_link(&mut header, name, target)
+        } else if file_type.is_file() {
+            let file = match fs::File::open(path) {
+                Ok(file) => file,
+                Err(e) => { self.record_error(path, e); return Ok(()); }
+            };
+            self.append_file(path, name, header, &file, meta, size)
+        } else {
+            if file_type.is_block_device() || file_type.is_char_device() {
+                let rdev = meta.rdev();
+                header.set_device_major(nix::sys::stat::major(rdev) as u32)?;
+                header.set_device_minor(nix::sys::stat::minor(rdev) as u32)?;
+            }
+            header.set_size(0);
+            self.builder.append_data(&mut header, name, io::empty())
+        }
+    }
+
+    fn append_file(&mut self, path: &Path, name: &Path, mut header: Header,
+                   file: &fs::File, meta: &fs::Metadata, size: &mut u64) -> io::Result<()> {
+        let file_size = meta.len();
+        let mut ext_headers = Vec::new();
+
+        let segments = match data_segments(file, meta) {
+            Some(segments) => {
+                // The GNU sparse format lists the data segments in the header,
+                // followed by extended headers if it does not fit.
+                header.set_entry_type(EntryType::GNUSparse);
+                header.set_size(segments.iter().map(|(_, len)| len).sum());
+                let gnu = header.as_gnu_mut().expect("GNU header");
+                gnu.set_real_size(file_size);
+
+                // A segment marks the end of the file when it ends with a hole.
+                let mut entries = segments.clone();
+                if entries.last().is_none_or(|(off, len)| off + len < file_size) {
+                    entries.push((file_size, 0));
+                }
+                let (first, rest) = entries.split_at(entries.len().min(gnu.sparse.len()));
+                for (&(off, len), sparse) in first.iter().zip(gnu.sparse.iter_mut()) {
+                    sparse.set_offset(off);
+                    sparse.set_length(len);
+                }
+                gnu.set_is_extended(!rest.is_empty());
+
+                let mut chunks = rest.chunks(21).peekable();
+                while let Some(chunk) = chunks.next() {
+                    let mut ext = tar::GnuExtSparseHeader::new();
+                    for (&(off, len), sparse) in chunk.iter().zip(ext.sparse.iter_mut()) {
+                        sparse.set_offset(off);
+                        sparse.set_length(len);
+                    }
+                    ext.set_is_extended(chunks.peek().is_some());
+                    ext_headers.extend_from_slice(ext.as_bytes());
+                }
+                segments
+            }
+            None if file_size > 0 => vec![(0, file_size)],
+            None => vec![],
+        };
+
+        let mut data = SegmentReader { file, segments, index: 0, pos: 0, error: None };
+        self.builder.append_data(&mut header, name, io::Cursor::new(ext_headers).chain(&mut data))?;
+        *size += file_size;
+
+        if let Some(e) = data.error {
+            // The archive entry is complete, padded with zeros.
+            self.record_error(path, e);
+        }
+        Ok(())
+    }
+
+    fn append_xattrs(&mut self, xattrs: &[(OsString, Vec<u8>)]) -> io::Result<()> {
+        // Each PAX record is "<len> SCHILY.xattr.<name>=<value>\n", where
+        // <len> is the length of the record, including itself.
+        let mut records = Vec::new();
+        for (key, value) in xattrs {
+            let mut body = b" SCHILY.xattr.".to_vec();
+            body.extend_from_slice(key.as_bytes());
+            body.push(b'=');
+            body.extend_from_slice(value);
+            body.push(b'\n');
+
+            let mut len = body.len();
+            while len != body.len() + len.to_string().len() {
+                len = body.len() + len.to_string().len();
+            }
+            records.extend_from_slice(len.to_string().as_bytes());
+            records.extend_from_slice(&body);
+        }
+
+        let mut header = Header::new_ustar();
+        header.set_entry_type(EntryType::XHeader);
+        header.set_path("PaxHeader")?;
+        header.set_mode(0o644);
+        header.set_size(records.len() as u64);
+        header.set_cksum();
+        self.builder.append(&header, &records[..])
+    }
+
+    fn append_deleted_paths(&mut self, base: &FileIndex) -> io::Result<()> {
+        let mut deleted: Vec<_> = base.0.keys()
+            .filter(|path| !self.index.0.contains_key(*path))
+            .collect();
+        if deleted.is_empty() {
+            return Ok(());
+        }
+        // Children come before their parents, so that directories are empty when removed
+        deleted.sort_unstable_by(|a, b| b.cmp(a));
+        self.stats.deleted_paths = deleted.len();
+
+        let mut data = Vec::new();
+        for path in deleted {
+            data.extend_from_slice(path.as_os_str().as_bytes());
+            data.push(0);
+        }
+        let mut header = Header::new_gnu();
+        header.set_entry_type(EntryType::Regular);
+        header.set_mode(0o600);
+        header.set_size(data.len() as u64);
+        self.builder.append_data(&mut header, DELETED_PATHS_ENTRY, &data[..])
+    }
+
+    fn record_error(&mut self, path: &Path, e: io::Error) {
+        self.stats.errors.insert(path.to_path_buf(), e.to_string());
+    }
+}
+
+/// Returns the (offset, length) of the data segments of a file with holes.
+/// Returns None if the file has no holes, or if the file system can't tell.
+fn data_segments(file: &fs::File, meta: &fs::Metadata) -> Option<Vec<(u64, u64)>> {
+    const BLOCK: u64 = 512;
+    let file_size = meta.len();
+    if meta.blocks() * BLOCK >= file_size {
+        return None;
+    }
+
+    let fd = file.as_raw_fd();
+    let seek = |off: u64, whence| match unsafe { libc::lseek(fd, off as libc::off_t, whence) } {
+        -1 => Err(io::Error::last_os_error()),
+        pos => Ok(pos as u64),
+    };
+
+    let mut segments: Vec<(u64, u64)> = Vec::new();
+    let mut off = 0;
+    while off < file_size {
+        let start = match seek(off, libc::SEEK_DATA) {
+            Ok(start) => start,
+            Err(e) if e.raw_os_error() == Some(libc::ENXIO) => break,
+            Err(_) => return None,
+        };
+        let end = seek(start, libc::SEEK_HOLE).ok()?;
+        if start >= file_size {
+            break;
+        }
+        // The GNU sparse format needs segments aligned on 512 bytes, except the last one
+        let start = start / BLOCK * BLOCK;
+        let end = (end.div_ceil(BLOCK) * BLOCK).min(file_size);
+        match segments.last_mut() {
+            Some((prev_off, prev_len)) if *prev_off + *prev_len >= start => *prev_len = end - *prev_off,
+            _ => segments.push((start, end - start)),
+        }
+        off = end;
+    }
+    Some(segments)
+}
+
+/// Reads the data segments of a file. When the file shrinks, or can't be read,
+/// it emits zeros instead, so that the archive entry has the announced size.
+struct SegmentReader<'a> {
+    file: &'a fs::File,
+    segments: Vec<(u64, u64)>,
+    index: usize,
+    pos: u64,
+    error: Option<io::Error>,
+}
+
+impl Read for SegmentReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let (off, len) = match self.segments.get(self.index) {
+                Some(&segment) => segment,
+                None => return Ok(0),
+            };
+            if self.pos == len {
+                self.index += 1;
+                self.pos = 0;
+                continue;
+            }
+
+            let buf_len = buf.len().min((len - self.pos) as usize);
+            let buf = &mut buf[..buf_len];
+            let n = match self.error {
+                Some(_) => 0,
+                None => match self.file.read_at(buf, off + self.pos) {
+                    Ok(0) => {
+                        self.error = Some(io::Error::new(io::ErrorKind::UnexpectedEof,
+                            "file shrank while archiving"));
+                        0
+                    }
+                    Ok(n) => n,
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => { self.error = Some(e); 0 }
+                }
+            };
+            let n = if n == 0 {
+                buf.iter_mut().for_each(|b| *b = 0);
+                buf.len()
+            } else {
+                n
+            };
+            self.pos += n as u64;
+            return Ok(n);
+        }
+    }
+}
+
+fn read_xattrs(path: &Path) -> io::Result<Vec<(OsString, Vec<u8>)>> {
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+
+    let names = read_xattr_buf(|buf, len| unsafe {
+        libc::llistxattr(c_path.as_ptr(), buf as *mut libc::c_char, len)
+    })?;
+
+    let mut xattrs = Vec::new();
+    for name in names.split(|&b| b == 0).filter(|n| !n.is_empty()) {
+        let c_name = CString::new(name)?;
+        let value = read_xattr_buf(|buf, len| unsafe {
+            libc::lgetxattr(c_path.as_ptr(), c_name.as_ptr(), buf as *mut libc::c_void, len)
+        })?;
+        xattrs.push((OsStr::from_bytes(name).to_os_string(), value));
+    }
+    Ok(xattrs)
+}
+
+/// Calls `f` once to get the size of the buffer to allocate, and once to fill it.
+fn read_xattr_buf(f: impl Fn(*mut u8, libc::size_t) -> libc::ssize_t) -> io::Result<Vec<u8>> {
+    loop {
+        let len = match f(std::ptr::null_mut(), 0) {
+            -1 => {
+                let e = io::Error::last_os_error();
+                return match e.raw_os_error() {
+                    // The file system does not support xattrs
+                    Some(libc::ENOTSUP) => Ok(vec![]),
+                    _ => Err(e),
+                };
+            }
+            len => len as usize,
+        };
+        let mut buf = vec![0; len];
+        match f(buf.as_mut_ptr(), len) {
+            -1 => {
+                let e = io::Error::last_os_error();
+                // The value grew in between our two calls
+                if e.raw_os_error() == Some(libc::ERANGE) {
+                    continue;
+                }
+                return Err(e);
+            }
+            len => { buf.truncate(len as usize); return Ok(buf); }
+        }
+    }
+}
+
+/// Writes an archive with no entries. Used when the file system goes elsewhere.
+pub fn write_empty_archive(out: impl io::Write) -> Result<()> {
+    tar::Builder::new(out).into_inner()
+        .context("Failed to write the file system archive")?;
+    Ok(())
+}
+
+/// Extracts the archive streamed from `input` into /.
+pub fn extract(input: impl Read) -> Result<()> {
+    unpack(input, Path::new("/"))
+}
+
+fn unpack(input: impl Read, dst: &Path) -> Result<()> {
+    let mut archive = tar::Archive::new(input);
+    archive.set_preserve_permissions(true);
+    // Like tar, we restore ownership only when running as root
+    archive.set_preserve_ownerships(geteuid().is_root());
+    archive.set_preserve_mtime(true);
+    archive.set_unpack_xattrs(true);
+    archive.set_overwrite(true);
+
+    for entry in archive.entries()? {
+        let mut entry = entry.context("Failed to read the file system archive")?;
+        if entry.path()? == Path::new(DELETED_PATHS_ENTRY) {
+            let mut data = Vec::new();
+            entry.read_to_end(&mut data)?;
+            remove_paths(dst, &data)?;
+            continue;
+        }
+        let path = dst.join(entry.path()?);
+
+        // Existing directories keep their metadata, like with tar --no-overwrite-dir
+        if entry.header().entry_type().is_dir() &&
+           fs::symlink_metadata(&path).is_ok_and(|m| m.is_dir()) {
+            continue;
+        }
+
+        trace!("Restoring {}", path.display());
+        entry.unpack_in(dst)
+            .with_context(|| format!("Failed to restore {}", path.display()))?;
+    }
+
+    // The end of the archive may be followed by padding. We drain it so that
+    // criu-image-streamer does not fail writing to a closed pipe.
+    io::copy(&mut archive.into_inner(), &mut io::sink())?;
+
+    Ok(())
+}
+
+/// Removes the NUL separated absolute `paths`, relative to `dst`.
+fn remove_paths(dst: &Path, paths: &[u8]) -> Result<()> {
+    for path in paths.split(|&b| b == 0).filter(|p| !p.is_empty()) {
+        let path = Path::new(OsStr::from_bytes(path));
+        let path = dst.join(path.strip_prefix("/").unwrap_or(path));
+        trace!("Removing {}", path.display());
+        let result = match fs::symlink_metadata(&path) {
+            Ok(meta) if meta.is_dir() => fs::remove_dir_all(&path),
+            Ok(_) => fs::remove_file(&path),
+            Err(e) => Err(e),
+        };
+        match result {
+            Err(e) if e.kind() != io::ErrorKind::NotFound =>
+                return Err(e).with_context(|| format!("Failed to remove {}", path.display())),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// External tar command, used when TAR_CMD is specified.
+/// The archive is compatible with the one produced by `archive()`.
+pub fn tar_cmd(preserved_paths: &HashSet<PathBuf>, options: &PreserveOptions,
+               stdout: fs::File) -> Command {
+    // unwrap() is safe, the caller checks has_external_tar()
+    let mut cmd = Command::new([TAR_CMD.as_ref().unwrap()]);
 
     // TODO We can't emit log lines during tarring, because we log them
     // And the log file is included in the tar archive. tar detects that the log file
@@ -47,18 +910,23 @@ pub fn tar_cmd(preserved_paths: HashSet<PathBuf>, stdout: fs::File) -> Command {
         "--preserve-permissions",
         "--ignore-failed-read", // Allows us to discard EPERM errors of files in /tmp
         "--sparse", // Support sparse files efficiently, libvirttime uses one
+        "--xattrs",
         "--file", "-",
     ])
         .arg("--exclude").arg(&*NO_PRESERVE_FF_DIR)
         .arg("--exclude").arg(&*CONTAINERS_DIR)
-        .args(&preserved_paths)
+        // tar matches patterns on any part of the path, which is close enough
+        .args(options.excludes.iter().map(|p| format!("--exclude={}", p)))
+        .args(preserved_paths)
         .arg(&*FF_DIR)
         .stdout(Stdio::from(stdout));
     cmd
 }
 
+/// External untar command, used when TAR_CMD is specified.
 pub fn untar_cmd(stdin: fs::File) -> Command {
-    let mut cmd = Command::new(&[&*TAR_CMD]);
+    // unwrap() is safe, the caller checks has_external_tar()
+    let mut cmd = Command::new([TAR_CMD.as_ref().unwrap()]);
     if log_enabled!(log::Level::Trace) {
         cmd.arg("--verbose");
     }
@@ -67,8 +935,123 @@ pub fn untar_cmd(stdin: fs::File) -> Command {
         "--extract",
         "--preserve-permissions",
         "--no-overwrite-dir",
+        "--xattrs",
         "--file", "-",
     ])
         .stdin(Stdio::from(stdin));
     cmd
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn archive_dir(src: &Path, base: Option<&FileIndex>) -> Result<(Vec<u8>, FileIndex, u64)> {
+        let mut archiver = Archiver {
+            builder: tar::Builder::new(Vec::new()),
+            filter: Filter { excluded_dirs: vec![], patterns: vec![] },
+            base,
+            index: FileIndex::default(),
+            hardlinks: HashMap::new(),
+            stats: ArchiveStats::default(),
+        };
+        let mut size = 0;
+        archiver.append_tree(src, src, &mut size)?;
+        if let Some(base) = base {
+            archiver.append_deleted_paths(base)?;
+        }
+        assert!(archiver.stats.errors.is_empty());
+        Ok((archiver.builder.into_inner()?, archiver.index, size))
+    }
+
+    #[test]
+    fn test_archive_roundtrip() -> Result<()> {
+        let dir = PathBuf::from("/tmp/ff-test-archive");
+        let _ = fs::remove_dir_all(&dir);
+        let src = dir.join("src");
+        fs::create_dir_all(src.join("sub"))?;
+        fs::write(src.join("sub/file"), "hello")?;
+        fs::hard_link(src.join("sub/file"), src.join("hardlink"))?;
+        std::os::unix::fs::symlink("sub/file", src.join("symlink"))?;
+        let c_path = CString::new(src.join("sub/file").as_os_str().as_bytes())?;
+        let has_xattrs = unsafe {
+            libc::setxattr(c_path.as_ptr(), b"user.ff\0".as_ptr() as *const libc::c_char,
+                           b"1".as_ptr() as *const libc::c_void, 1, 0) == 0
+        };
+        // A file with a hole in the middle
+        let sparse = fs::File::create(src.join("sparse"))?;
+        sparse.write_all_at(b"head", 0)?;
+        sparse.write_all_at(b"tail", 10*MB as u64)?;
+
+        let (archive, _, size) = archive_dir(&src, None)?;
+        assert_eq!(size, 5 + 10*MB as u64 + 4);
+        assert!(archive.len() < MB, "the hole should not be archived");
+
+        let dst = dir.join("dst");
+        fs::create_dir(&dst)?;
+        unpack(&archive[..], &dst)?;
+        let dst = dst.join(src.strip_prefix("/")?);
+        assert_eq!(fs::read(dst.join("sub/file"))?, b"hello");
+        assert_eq!(fs::metadata(dst.join("hardlink"))?.ino(), fs::metadata(dst.join("sub/file"))?.ino());
+        assert_eq!(fs::read_link(dst.join("symlink"))?, Path::new("sub/file"));
+        if has_xattrs {
+            assert_eq!(read_xattrs(&dst.join("sub/file"))?, vec![("user.ff".into(), b"1".to_vec())]);
+        }
+        let sparse = fs::read(dst.join("sparse"))?;
+        assert_eq!(sparse.len(), 10*MB + 4);
+        assert_eq!(&sparse[..4], b"head");
+        assert_eq!(&sparse[10*MB..], b"tail");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_exclude_patterns() -> Result<()> {
+        let options = PreserveOptions {
+            excludes: vec!["*.tmp".to_string(), "cache/**".to_string(), "/var/log/big".to_string()],
+            ..Default::default()
+        };
+        let filter = Filter::new(&options)?;
+        let root = Path::new("/app");
+        assert!(filter.is_excluded(Path::new("/app/data/x.tmp"), root));
+        assert!(filter.is_excluded(Path::new("/app/cache/a/b"), root));
+        assert!(!filter.is_excluded(Path::new("/app/data/cache/a"), root));
+        assert!(!filter.is_excluded(Path::new("/app/data/x.tmpl"), root));
+        assert!(filter.is_excluded(Path::new("/var/log/big"), Path::new("/var/log")));
+        assert!(filter.is_excluded(&NO_PRESERVE_FF_DIR.join("x"), &FF_DIR));
+        assert!(!filter.is_excluded(&FF_DIR.join("x.tmp"), &FF_DIR));
+        Ok(())
+    }
+
+    #[test]
+    fn test_incremental_archive() -> Result<()> {
+        let dir = PathBuf::from("/tmp/ff-test-incremental");
+        let _ = fs::remove_dir_all(&dir);
+        let src = dir.join("src");
+        fs::create_dir_all(src.join("sub"))?;
+        fs::write(src.join("unchanged"), vec![1; 100_000])?;
+        fs::write(src.join("changed"), "v1")?;
+        fs::write(src.join("sub/deleted"), "x")?;
+        let (base_archive, base_index, _) = archive_dir(&src, None)?;
+
+        // Timestamps have a coarse granularity on some file systems
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        fs::write(src.join("changed"), "v2")?;
+        fs::write(src.join("added"), "new")?;
+        fs::remove_dir_all(src.join("sub"))?;
+        let (delta_archive, _, size) = archive_dir(&src, Some(&base_index))?;
+        assert_eq!(size, 2 + 3, "only the changed and added files should be archived");
+
+        let dst = dir.join("dst");
+        fs::create_dir(&dst)?;
+        unpack(&base_archive[..], &dst)?;
+        unpack(&delta_archive[..], &dst)?;
+        let dst = dst.join(src.strip_prefix("/")?);
+        assert_eq!(fs::read(dst.join("unchanged"))?, vec![1; 100_000]);
+        assert_eq!(fs::read(dst.join("changed"))?, b"v2");
+        assert_eq!(fs::read(dst.join("added"))?, b"new");
+        assert!(!dst.join("sub").exists());
+
+        Ok(())
+    }
+}
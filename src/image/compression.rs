@@ -18,7 +18,7 @@ use std::{
     fmt,
 };
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum Compression {
     Lz4,
     Zstd,
@@ -20,7 +20,7 @@ use std::{
 use serde::{Serialize, Deserialize};
 use crate::consts::*;
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, PartialEq)]
 pub struct Encryption {
     pub cipher: String,
 }
@@ -17,9 +17,15 @@ use serde::{Serialize, Deserialize};
 use crate::{
     consts::*,
     store::{Store, FileExt},
+    image_streamer::Stats,
 };
-use super::{Compression, Encryption};
-use std::fmt;
+use super::{Compression, Encryption, shard::shard_filename};
+use std::{
+    fmt,
+    time::SystemTime,
+    sync::atomic::{AtomicBool, Ordering},
+};
+use crate::util::gen_random_alphanum_string;
 
 // The image manifest is what describes how to consume an image.
 // It holds version, shard location, and compression used.
@@ -37,18 +43,52 @@ pub struct ImageManifest {
     pub encryption: Option<Encryption>,
     pub compression: Option<Compression>,
     pub shard_prefix: String,
+    /// Filenames of the file system layers to restore before the fs.tar of the
+    /// shards, oldest first. Only present with incremental checkpoints.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub fs_layers: Vec<String>,
+    /// Filename of the log of the checkpoint. Only present with --upload-log.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub log: Option<String>,
+    /// When the checkpoint was taken. It orders the generations of an image,
+    /// e.g., to find the newest among replicas. Absent in older images.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<SystemTime>,
+    /// When the checkpoint completed, how long it took, and its stats. The
+    /// status command reports them after a restore. Absent in older images.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub completed_at: Option<SystemTime>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration_sec: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stats: Option<Box<Stats>>,
 }
 
+/// Whether a manifest of this process took INVOCATION_ID as its shard prefix.
+static INVOCATION_ID_PREFIX_USED: AtomicBool = AtomicBool::new(false);
+
 impl ImageManifest {
     /// Make a new image manifest. The shard_prefix is INVOCATION_ID which is picked at random.
     /// This can make it easier to tie metrics and log files to a specific checkpoint command.
+    /// A process serving the control API makes many checkpoints, and they must not
+    /// overwrite each other's files, so the following manifests get a new random prefix.
     pub fn new(num_shards: u32, encrypt: bool, compression: Option<Compression>) -> Self {
+        let shard_prefix = match INVOCATION_ID_PREFIX_USED.swap(true, Ordering::SeqCst) {
+            false => INVOCATION_ID.clone(),
+            true => gen_random_alphanum_string(INVOCATION_ID.len()),
+        };
         Self {
             version: String::from(CURRENT_IMG_VERSION),
-            shard_prefix: INVOCATION_ID.clone(),
+            shard_prefix,
             encryption: if encrypt { Some(Encryption::default()) } else { None },
             compression,
             num_shards,
+            fs_layers: Vec::new(),
+            log: None,
+            created_at: Some(SystemTime::now()),
+            completed_at: None,
+            duration_sec: None,
+            stats: None,
         }
     }
 
@@ -87,6 +127,32 @@ impl ImageManifest {
             None => ManifestFetchResult::NotFound,
         })
     }
+
+    /// Deletes the image from the store. The manifest goes first, so that a
+    /// partially deleted image is never restored. Returns false when there was
+    /// no image to delete.
+    pub fn delete_from_store(store: &dyn Store) -> Result<bool> {
+        let img_manifest = match Self::fetch_from_store(store, false)? {
+            ManifestFetchResult::Some(img_manifest) => Some(img_manifest),
+            // We don't know how to interpret other versions, only the
+            // manifest is deleted.
+            ManifestFetchResult::VersionMismatch { .. } => None,
+            ManifestFetchResult::NotFound => return Ok(false),
+        };
+
+        store.file(MANIFEST_FILE_NAME).delete("delete manifest")?;
+
+        if let Some(img_manifest) = img_manifest {
+            let shards = (0..img_manifest.num_shards)
+                .map(|i| shard_filename(&img_manifest.shard_prefix, i));
+            let others = img_manifest.fs_layers.iter().chain(img_manifest.log.iter()).cloned();
+            for filename in shards.chain(others) {
+                store.file(&filename).delete("delete image")?;
+            }
+        }
+
+        Ok(true)
+    }
 }
 
 impl fmt::Display for ImageManifest {
@@ -95,6 +161,33 @@ impl fmt::Display for ImageManifest {
             self.version, self.num_shards,
             self.compression.as_ref().map_or_else(|| "none".to_string(), |d| format!("{}", d)),
             self.encryption.as_ref().map_or_else(|| "none".to_string(), |d| format!("{}", d)),
-            self.shard_prefix)
+            self.shard_prefix)?;
+        if !self.fs_layers.is_empty() {
+            write!(f, " fs_layers={}", self.fs_layers.len())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::image::shard;
+
+    #[test]
+    fn test_checkpoints_in_one_process_dont_share_files() {
+        // e.g., two POST /checkpoint requests served by the control API.
+        let first = ImageManifest::new(2, false, None);
+        let second = ImageManifest::new(2, false, None);
+        assert_ne!(first.shard_prefix, second.shard_prefix);
+
+        let filenames = |m: &ImageManifest| vec![
+            shard::shard_filename(&m.shard_prefix, 0),
+            shard::shard_filename(&m.shard_prefix, 1),
+            shard::fs_layer_filename(&m.shard_prefix),
+            shard::log_filename(&m.shard_prefix),
+        ];
+        let first_filenames = filenames(&first);
+        assert!(filenames(&second).iter().all(|f| !first_filenames.contains(f)));
     }
 }
@@ -17,37 +17,107 @@ use std::path::PathBuf;
 use super::ImageManifest;
 use crate::store::Store;
 
-fn shard_filename(shard_prefix: &str, shard_index: u32) -> String {
+pub fn shard_filename(shard_prefix: &str, shard_index: u32) -> String {
     // .ffs stands for fastfreeze shard
     format!("{}-{}.ffs", shard_prefix, shard_index+1)
 }
 
+/// Filename of a file system layer, see `filesystem::FsLayers`.
+pub fn fs_layer_filename(shard_prefix: &str) -> String {
+    // .ffl stands for fastfreeze layer
+    format!("{}-fs.ffl", shard_prefix)
+}
+
+/// Filename of the `index`th file system layer of an image rewritten by
+/// `image convert`, which re-encodes all the layers under its own prefix.
+pub fn converted_fs_layer_filename(shard_prefix: &str, index: usize) -> String {
+    format!("{}-fs-{}.ffl", shard_prefix, index+1)
+}
+
+/// Filename of the log of the checkpoint, uploaded with --upload-log.
+pub fn log_filename(shard_prefix: &str) -> String {
+    format!("{}.log", shard_prefix)
+}
+
+/// Returns the compression and encryption stages of an upload pipeline.
+fn upload_stages(img_manifest: &ImageManifest, passphrase_file: Option<&PathBuf>) -> Result<Vec<String>> {
+    let mut cmd = Vec::new();
+
+    if let Some(ref compression) = img_manifest.compression {
+        cmd.push(compression.compress_cmd().to_string());
+    }
+
+    if let Some(ref encryption) = img_manifest.encryption {
+        let passphrase_file = passphrase_file.ok_or_else(|| anyhow!(
+            "The image must be encrypted. Use --passphrase-file to provide an encryption passphrase"))?;
+        cmd.push(encryption.encrypt_cmd(passphrase_file.as_path()));
+    }
+    Ok(cmd)
+}
+
+/// Returns the decryption and decompression stages of a download pipeline.
+fn download_stages(img_manifest: &ImageManifest, passphrase_file: Option<&PathBuf>) -> Result<Vec<String>> {
+    let mut cmd = Vec::new();
+
+    if let Some(ref encryption) = img_manifest.encryption {
+        let passphrase_file = passphrase_file.ok_or_else(|| anyhow!(
+            "The image is encrypted. Use --passphrase-file to provide an encryption passphrase"))?;
+        cmd.push(encryption.decrypt_cmd(passphrase_file.as_path()));
+    }
+
+    if let Some(ref compression) = img_manifest.compression {
+        cmd.push(compression.decompress_cmd().to_string());
+    }
+    Ok(cmd)
+}
+
+/// Returns the command uploading `filename` of the image from its stdin.
+pub fn upload_cmd(
+    img_manifest: &ImageManifest,
+    passphrase_file: Option<&PathBuf>,
+    store: &dyn Store,
+    filename: &str,
+) -> Result<String> {
+    let mut cmd = upload_stages(img_manifest, passphrase_file)?;
+    cmd.push(store.file(filename).upload_shell_cmd());
+    Ok(cmd.join(" | "))
+}
+
+/// Same as `upload_cmd()`, but the upload is retried on transient errors.
+/// The data is spooled to a temporary file, so this is not meant for shards.
+pub fn retried_upload_cmd(
+    img_manifest: &ImageManifest,
+    passphrase_file: Option<&PathBuf>,
+    store: &dyn Store,
+    filename: &str,
+) -> Result<String> {
+    let file = store.file(filename);
+    let mut cmd = upload_stages(img_manifest, passphrase_file)?;
+    cmd.push(file.retry_policy().upload_shell_cmd(filename, file.upload_shell_cmd()));
+    Ok(cmd.join(" | "))
+}
+
+/// Returns the command downloading `filename` of the image to its stdout.
+pub fn download_cmd(
+    img_manifest: &ImageManifest,
+    passphrase_file: Option<&PathBuf>,
+    store: &dyn Store,
+    filename: &str,
+) -> Result<String> {
+    let mut cmd = vec![store.file(filename).download_shell_cmd()];
+    cmd.append(&mut download_stages(img_manifest, passphrase_file)?);
+    Ok(cmd.join(" | "))
+}
+
 pub fn upload_cmds(
     img_manifest: &ImageManifest,
     passphrase_file: Option<&PathBuf>,
     store: &dyn Store,
 ) -> Result<Vec<String>> {
-    let cmd_common = {
-        let mut cmd = Vec::new();
-
-        if let Some(ref compression) = img_manifest.compression {
-            cmd.push(compression.compress_cmd().to_string());
-        }
-
-        if let Some(ref encryption) = img_manifest.encryption {
-            let passphrase_file = passphrase_file.ok_or_else(|| anyhow!(
-                "The image must be encrypted. Use --passphrase-file to provide an encryption passphrase"))?;
-            cmd.push(encryption.encrypt_cmd(passphrase_file.as_path()));
-        }
-        cmd
-    };
-
-    Ok((0..img_manifest.num_shards).map(|shard_index| {
-        let file = store.file(&shard_filename(&img_manifest.shard_prefix, shard_index));
-        let mut cmd = cmd_common.clone();
-        cmd.push(file.upload_shell_cmd());
-        cmd.join(" | ")
-    }).collect())
+    (0..img_manifest.num_shards).map(|shard_index| {
+        upload_cmd(img_manifest, passphrase_file, store,
+                   &shard_filename(&img_manifest.shard_prefix, shard_index))
+    }).collect()
 }
 
 pub fn download_cmds(
@@ -55,26 +125,12 @@ pub fn download_cmds(
     passphrase_file: Option<&PathBuf>,
     store: &dyn Store
 ) -> Result<Vec<String>> {
-    let cmd_common = {
-        let mut cmd = Vec::new();
-
-        if let Some(ref encryption) = img_manifest.encryption {
-            let passphrase_file = passphrase_file.ok_or_else(|| anyhow!(
-                "The image is encrypted. Use --passphrase-file to provide an encryption passphrase"))?;
-            cmd.push(encryption.decrypt_cmd(passphrase_file.as_path()));
-            info!("Decrypting image with passphrase from file {}", passphrase_file.display());
-        }
-
-        if let Some(ref compression) = img_manifest.compression {
-            cmd.push(compression.decompress_cmd().to_string());
-        }
-        cmd
-    };
-
-    Ok((0..img_manifest.num_shards).map(|shard_index| {
-        let file = store.file(&shard_filename(&img_manifest.shard_prefix, shard_index));
-        let mut cmd: Vec<String> = vec![file.download_shell_cmd()];
-        cmd.append(&mut cmd_common.clone());
-        cmd.join(" | ")
-    }).collect())
+    if let (Some(_), Some(passphrase_file)) = (&img_manifest.encryption, passphrase_file) {
+        info!("Decrypting image with passphrase from file {}", passphrase_file.display());
+    }
+
+    (0..img_manifest.num_shards).map(|shard_index| {
+        download_cmd(img_manifest, passphrase_file, store,
+                     &shard_filename(&img_manifest.shard_prefix, shard_index))
+    }).collect()
 }
@@ -14,29 +14,52 @@
 
 use anyhow::{Result, Context};
 use std::{
-    os::unix::io::{RawFd, AsRawFd},
+    os::unix::{
+        io::{RawFd, AsRawFd},
+        net::UnixStream,
+    },
     fs, io::BufReader,
-    io::BufRead,
-    io::Lines, path::Path,
+    io::{self, BufRead, Write},
+    path::Path,
+};
+use nix::{
+    fcntl::OFlag,
+    sys::{
+        socket::{sendmsg, ControlMessage, MsgFlags},
+        uio::IoVec,
+    },
 };
 use serde::{Serialize, Deserialize};
 use crate::{
     consts::*,
     util::Pipe,
+    signal::wait_until_readable,
     process::{Command, Process, PipeCommandExt},
+    filesystem::ArchiveStats,
 };
 
 
 pub struct Progress {
     pub fd: RawFd,
-    pub lines: Lines<BufReader<fs::File>>,
+    pub reader: BufReader<fs::File>,
 }
 
 impl Progress {
     fn get_next_progress_line(&mut self) -> Result<String> {
-        Ok(self.lines.next()
-            .ok_or_else(|| anyhow!("EOF unexpectedly reached"))
-            .context("Failed to read progress from the streamer")??)
+        // The streamer can take a long time to send its next line (e.g., the
+        // stats once all shards are downloaded). We wait in poll() rather than
+        // in read(), so that a termination request interrupts the wait.
+        if self.reader.buffer().is_empty() {
+            wait_until_readable(self.fd)?;
+        }
+        let mut line = String::new();
+        let len = self.reader.read_line(&mut line)
+            .context("Failed to read progress from the streamer")?;
+        if len == 0 {
+            return Err(anyhow!("EOF unexpectedly reached"))
+                .context("Failed to read progress from the streamer");
+        }
+        Ok(line.trim_end_matches('\n').to_string())
     }
 
     pub fn wait_for_socket_init(&mut self) -> Result<()> {
@@ -65,7 +88,9 @@ pub struct ImageStreamer {
 }
 
 impl ImageStreamer {
-    pub fn spawn_capture(num_shards: usize) -> Result<Self> {
+    /// Spawns the streamer in capture mode. It listens for CRIU on a socket
+    /// in `images_dir`, which is CRIU_SOCKET_DIR for checkpoints.
+    pub fn spawn_capture(num_shards: usize, images_dir: &Path) -> Result<Self> {
         let progress = Pipe::new_output()?;
         let fs_tar = Pipe::new_input()?;
 
@@ -82,7 +107,7 @@ impl ImageStreamer {
                 .collect::<Vec<_>>().join(","),
         ]);
         cmd
-            .arg("--images-dir").arg(&*CRIU_SOCKET_DIR)
+            .arg("--images-dir").arg(images_dir)
             .arg("capture")
             .enable_stderr_logging("streamer");
 
@@ -90,7 +115,7 @@ impl ImageStreamer {
             process: cmd.spawn()?,
             progress: Progress {
                 fd: progress.read.as_raw_fd(),
-                lines: BufReader::new(progress.read).lines(),
+                reader: BufReader::new(progress.read),
             },
             tar_fs_pipe: Some(fs_tar.write),
             shard_pipes: shards.into_iter().map(|o| o.read).collect(),
@@ -125,7 +150,7 @@ impl ImageStreamer {
             process: cmd.spawn()?,
             progress: Progress {
                 fd: progress.read.as_raw_fd(),
-                lines: BufReader::new(progress.read).lines(),
+                reader: BufReader::new(progress.read),
             },
             tar_fs_pipe: Some(fs_tar.read),
             shard_pipes: shards.into_iter().map(|o| o.write).collect(),
@@ -155,7 +180,7 @@ impl ImageStreamer {
             process: cmd.spawn()?,
             progress: Progress {
                 fd: progress.read.as_raw_fd(),
-                lines: BufReader::new(progress.read).lines(),
+                reader: BufReader::new(progress.read),
             },
             tar_fs_pipe: None,
             shard_pipes: shards.into_iter().map(|o| o.write).collect(),
@@ -163,6 +188,82 @@ impl ImageStreamer {
     }
 }
 
+/// Name of the socket that a streamer in capture mode listens on for CRIU.
+const CAPTURE_SOCKET_NAME: &str = "streamer-capture.sock";
+/// Name of the file system archive, an external file of the image.
+const FS_TAR_FILE_NAME: &str = "fs.tar";
+
+/// Sends the image files of `files_dir`, as written by a streamer in extract
+/// mode, to a streamer in capture mode listening in `images_dir`. We play the
+/// part of CRIU, and fs.tar goes to the external file pipe as tar would.
+/// This is how images are re-sharded offline.
+pub fn send_image_files(images_dir: &Path, files_dir: &Path, tar_fs_pipe: fs::File) -> Result<()> {
+    let socket_path = images_dir.join(CAPTURE_SOCKET_NAME);
+    let mut socket = UnixStream::connect(&socket_path)
+        .with_context(|| format!("Failed to connect to {}", socket_path.display()))?;
+
+    // The file system archive is read concurrently, as the streamer may not
+    // drain the CRIU files and the archive in the order we write them.
+    let fs_tar_path = files_dir.join(FS_TAR_FILE_NAME);
+    let fs_tar_thread = std::thread::spawn(move || -> Result<()> {
+        let mut tar_fs_pipe = tar_fs_pipe;
+        if fs_tar_path.exists() {
+            let mut file = fs::File::open(&fs_tar_path)
+                .with_context(|| format!("Failed to open {}", fs_tar_path.display()))?;
+            io::copy(&mut file, &mut tar_fs_pipe)
+                .with_context(|| format!("Failed to send {}", fs_tar_path.display()))?;
+        }
+        Ok(())
+    });
+
+    let mut filenames = vec![];
+    for entry in fs::read_dir(files_dir).with_context(|| format!("Failed to readdir {}", files_dir.display()))? {
+        let entry = entry?;
+        let filename = entry.file_name().to_string_lossy().into_owned();
+        if entry.file_type()?.is_file() && filename != FS_TAR_FILE_NAME {
+            filenames.push(filename);
+        }
+    }
+    filenames.sort();
+
+    for filename in filenames {
+        let path = files_dir.join(&filename);
+        let mut file = fs::File::open(&path)
+            .with_context(|| format!("Failed to open {}", path.display()))?;
+        // Like CRIU, each file is announced with a request holding its name,
+        // followed by the read end of a pipe carrying its content.
+        let pipe = Pipe::new(OFlag::O_CLOEXEC)?;
+        let request = file_request(&filename);
+        socket.write_all(&(request.len() as u32).to_ne_bytes())?;
+        socket.write_all(&request)?;
+        sendmsg(socket.as_raw_fd(), &[IoVec::from_slice(&[0])],
+                &[ControlMessage::ScmRights(&[pipe.read.as_raw_fd()])], MsgFlags::empty(), None)
+            .with_context(|| format!("Failed to send the pipe of {}", filename))?;
+        drop(pipe.read);
+        let mut pipe_w = pipe.write;
+        io::copy(&mut file, &mut pipe_w)
+            .with_context(|| format!("Failed to send {}", path.display()))?;
+    }
+
+    // Closing the socket tells the streamer that all the files are sent.
+    drop(socket);
+    fs_tar_thread.join().map_err(|_| anyhow!("fs.tar sender panicked"))?
+}
+
+/// Returns the protobuf encoding of CRIU's `img_streamer_request_entry`,
+/// which has a single field: `required string filename = 1`.
+fn file_request(filename: &str) -> Vec<u8> {
+    let mut buf = vec![0x0a]; // field 1, length delimited
+    let mut len = filename.len();
+    while len >= 0x80 {
+        buf.push((len as u8 & 0x7f) | 0x80);
+        len >>= 7;
+    }
+    buf.push(len as u8);
+    buf.extend_from_slice(filename.as_bytes());
+    buf
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct ImageStreamerStats {
     pub shards: Vec<ImageStreamerShardStat>,
@@ -174,14 +275,20 @@ pub struct ImageStreamerShardStat {
 }
 
 // These are emitted for metrics
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct Stats {
     pub total_size_mb: f64,
     pub total_duration_sec: f64,
     pub rate_mb_per_sec: f64,
     pub shards: Vec<ShardStat>,
+    /// Only present for checkpoints
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filesystem: Option<ArchiveStats>,
+    /// Only present for checkpoints. Time during which the application was frozen
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub time_frozen_sec: Option<f64>,
 }
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct ShardStat {
     pub size_mb: f64,
     pub duration_sec: f64,
@@ -222,6 +329,6 @@ impl From<ImageStreamerStats> for Stats {
             ShardStat { size_mb, duration_sec, rate_mb_per_sec }
         }).collect::<Vec<_>>();
 
-        Self { total_size_mb, total_duration_sec, rate_mb_per_sec, shards }
+        Self { total_size_mb, total_duration_sec, rate_mb_per_sec, shards, filesystem: None, time_frozen_sec: None }
     }
 }
@@ -87,6 +87,20 @@ pub fn checkpoint_restore_lock(timeout: Option<Instant>, exclusive: bool)
     )
 }
 
+/// Returns whether the lock is held exclusively by someone else.
+pub fn is_file_lock_held(path: &Path) -> Result<bool> {
+    match file_lock(path, Some(Instant::now()), false) {
+        Ok(_) => Ok(false),
+        Err(e) if e.is::<LockTimeoutError>() => Ok(true),
+        Err(e) => Err(e),
+    }
+}
+
+/// Returns whether a checkpoint or restore operation is in progress.
+pub fn is_checkpoint_restore_lock_held() -> Result<bool> {
+    is_file_lock_held(&LOCK_FILE_PATH)
+}
+
 pub fn with_checkpoint_restore_lock<F,R>(f: F) -> Result<R>
     where F: FnOnce() -> Result<R>,
 {
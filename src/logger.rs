@@ -20,33 +20,143 @@ use std::{
     io::stderr,
     sync::Mutex,
     fs,
+    os::unix::io::AsRawFd,
     path::{Path, PathBuf},
+    str::FromStr,
 };
+use nix::fcntl::{flock, FlockArg};
 use log::{Record, Metadata};
+use serde::Serialize;
 pub use log::LevelFilter;
 use chrono::prelude::*;
 use crate::{
     consts::*,
-    util::{create_dir_all, set_tmp_like_permissions},
+    util::{create_dir_all, set_tmp_like_permissions, parse_env_var},
 };
 
+/// The target of the log records of the stderr lines of subprocesses.
+/// Their message is of the form "<log prefix>> <line>".
+pub const SUBPROCESS_LOG_TARGET: &str = "ff::subprocess";
+
+#[derive(Debug, PartialEq, Copy, Clone, Serialize)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "text" => LogFormat::Text,
+            "json" => LogFormat::Json,
+            _ => bail!("Possible values are [text, json], not `{}`", s)
+        })
+    }
+}
+
+/// Log rotation and retention settings.
+#[derive(Clone, Copy)]
+struct Retention {
+    rotate_size: u64,
+    max_files: usize,
+    max_size: u64,
+}
+
+impl Retention {
+    /// Reads FF_LOG_ROTATE_SIZE_MB, FF_LOG_MAX_FILES and FF_LOG_MAX_SIZE_MB.
+    /// This is done once, when the logger is initialized, as we can't fail
+    /// while logging.
+    fn from_env() -> Result<Self> {
+        Ok(Self {
+            rotate_size: parse_env_var("FF_LOG_ROTATE_SIZE_MB")?
+                .unwrap_or(DEFAULT_LOG_ROTATE_SIZE_MB) * MB as u64,
+            max_files: parse_env_var("FF_LOG_MAX_FILES")?
+                .unwrap_or(DEFAULT_LOG_MAX_FILES),
+            max_size: parse_env_var("FF_LOG_MAX_SIZE_MB")?
+                .unwrap_or(DEFAULT_LOG_MAX_SIZE_MB) * MB as u64,
+        })
+    }
+
+    fn prune(&self, current: &Path) -> Result<()> {
+        prune_log_files(current, self.max_files, self.max_size)
+    }
+}
+
 pub struct Logger {
     cmd_name: &'static str,
+    format: LogFormat,
+    app_name: Option<String>,
     log_file: Option<(fs::File, PathBuf)>,
+    /// Number of bytes in the log file, to know when to rotate it
+    log_file_size: u64,
+    retention: Retention,
     stdout_enabled: bool,
 }
 
 impl Logger {
+    fn format_json(&self, record: &Record) -> String {
+        let msg = record.args().to_string();
+        let (subprocess, msg) = match msg.split_once("> ") {
+            Some((prefix, line)) if record.target() == SUBPROCESS_LOG_TARGET => (Some(prefix), line),
+            _ => (None, msg.as_str()),
+        };
+
+        let mut json = json!({
+            "time": Utc::now().to_rfc3339_opts(SecondsFormat::Micros, true),
+            "level": record.level().to_string().to_lowercase(),
+            "command": self.cmd_name,
+            "invocation_id": *INVOCATION_ID,
+            "elapsed": START_TIME.elapsed().as_secs_f64(),
+            "msg": msg,
+        });
+        if let Some(ref app_name) = self.app_name {
+            json["app_name"] = json!(app_name);
+        }
+        if let Some(subprocess) = subprocess {
+            json["subprocess"] = json!(subprocess);
+        }
+        json.to_string() + "\n"
+    }
+
     fn log(&mut self, record: &Record) {
-        let msg = format!("[ff.{}] ({:.3}s) {}\n",
-            self.cmd_name, START_TIME.elapsed().as_secs_f64(), record.args());
+        let msg = match self.format {
+            LogFormat::Text => format!("[ff.{}] ({:.3}s) {}\n",
+                self.cmd_name, START_TIME.elapsed().as_secs_f64(), record.args()),
+            LogFormat::Json => self.format_json(record),
+        };
 
         // When we fail to write to the outputs, we dismiss the errors.
         // Maybe there's something better to do.
         if self.stdout_enabled {
             let _ = stderr().write_all(msg.as_bytes());
         }
-        let _ = self.log_file.as_mut().map(|f| f.0.write_all(msg.as_bytes()));
+        if let Some((file, _)) = self.log_file.as_mut() {
+            if file.write_all(msg.as_bytes()).is_ok() {
+                self.log_file_size += msg.len() as u64;
+            }
+        }
+        if self.log_file_size > self.retention.rotate_size {
+            let _ = self.rotate_file();
+        }
+    }
+
+    /// Renames the log file with the next free numbered suffix (e.g., .log.1),
+    /// and continues with a new log file. We can't log errors from here.
+    fn rotate_file(&mut self) -> Result<()> {
+        let path = match self.log_file {
+            Some((_, ref path)) => path.clone(),
+            None => return Ok(()),
+        };
+        let rotated_path = (1..)
+            .map(|i| PathBuf::from(format!("{}.{}", path.display(), i)))
+            .find(|p| !p.exists())
+            .expect("no free log suffix");
+        fs::rename(&path, &rotated_path)?;
+        self.log_file = Some((open_file(&path)?, path.clone()));
+        self.log_file_size = 0;
+        self.retention.prune(&path)
     }
 
     fn flush(&mut self) {
@@ -67,9 +177,7 @@ impl Logger {
                     // copy, followed by re-opening the file.
                     fs::copy(&old_path, &new_path).with_context(|| format!(
                         "Failed to copy {} to {}", old_path.display(), new_path.display()))?;
-                    let new_file = fs::OpenOptions::new()
-                        .append(true)
-                        .open(&new_path)
+                    let new_file = open_file(&new_path)
                         .with_context(|| format!("Failed to re-open log file at {}", new_path.display()))?;
                     fs::remove_file(&old_path)
                         .with_context(|| format!("Failed to unlink {}", old_path.display()))?;
@@ -78,12 +186,82 @@ impl Logger {
                 Err(e) => Err(e).with_context(|| format!(
                     "Failed to rename {} to {}", old_path.display(), new_path.display()))?,
                 Ok(()) => (old_file, new_path),
-            })
+            });
+            // The log files of the destination directory are subject to retention as well.
+            // Retention is best effort.
+            if let Some((_, ref path)) = self.log_file {
+                let _ = self.retention.prune(path);
+            }
         }
         Ok(())
     }
 }
 
+/// Opens a log file for appending. The file is locked (shared) for as long as
+/// it is open, so that other commands don't prune it, see `is_in_use()`.
+fn open_file(path: &Path) -> Result<fs::File> {
+    let file = fs::OpenOptions::new().create(true).append(true).open(path)?;
+    // Failing to lock only exposes the file to pruning.
+    let _ = flock(file.as_raw_fd(), FlockArg::LockSharedNonblock);
+    Ok(file)
+}
+
+/// Returns whether the log file is being written to by a running command.
+fn is_in_use(path: &Path) -> bool {
+    fs::File::open(path)
+        .map(|file| flock(file.as_raw_fd(), FlockArg::LockExclusiveNonblock).is_err())
+        .unwrap_or(false)
+}
+
+/// Returns the rotated segments of the log file `current`, oldest first.
+fn rotated_log_files(current: &Path) -> Vec<PathBuf> {
+    // unwrap() is safe, log files are in a directory, and have a name.
+    let dir = current.parent().unwrap();
+    let prefix = format!("{}.", current.file_name().unwrap().to_string_lossy());
+    let mut files: Vec<_> = fs::read_dir(dir).into_iter().flatten().flatten()
+        .filter(|entry| entry.file_name().to_string_lossy().strip_prefix(&prefix)
+            .is_some_and(|suffix| suffix.parse::<u32>().is_ok()))
+        .filter_map(|entry| Some((entry.metadata().ok()?.modified().ok()?, entry.path())))
+        .collect();
+    // Suffixes are reused once pruned, so they don't give the order.
+    files.sort();
+    files.into_iter().map(|(_, path)| path).collect()
+}
+
+/// Deletes the oldest log files next to `current`, beyond `max_files` files or
+/// `max_size` bytes in total, including rotated files. `current`, and the log
+/// files of the other running commands, are kept.
+fn prune_log_files(current: &Path, max_files: usize, max_size: u64) -> Result<()> {
+    // unwrap() is safe, log files are in a directory.
+    let dir = current.parent().unwrap();
+    let mut files = vec![];
+    for entry in fs::read_dir(dir).with_context(|| format!("Failed to readdir {}", dir.display()))? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if !name.starts_with("ff-") || !name.contains(".log") || entry.path() == current ||
+           is_in_use(&entry.path()) {
+            continue;
+        }
+        let metadata = entry.metadata()?;
+        files.push((metadata.modified()?, metadata.len(), entry.path()));
+    }
+
+    // Most recent first. Once we go over a limit, all older files are deleted.
+    files.sort_by_key(|f| std::cmp::Reverse(f.0));
+    let mut num_files = 1;
+    let mut total_size = fs::metadata(current).map_or(0, |m| m.len());
+    for (_, size, path) in files {
+        num_files += 1;
+        total_size += size;
+        if num_files > max_files || total_size > max_size {
+            // Another FastFreeze command may be pruning as well, or the file
+            // may belong to another user. That's fine.
+            let _ = fs::remove_file(&path);
+        }
+    }
+    Ok(())
+}
+
 lazy_static! {
     static ref LOGGER: Mutex<Option<Logger>> = Mutex::new(None);
 }
@@ -92,6 +270,26 @@ pub fn is_logger_ready() -> bool {
     LOGGER.lock().unwrap().is_some()
 }
 
+/// Sets the application name, reported in JSON logs.
+pub fn set_app_name(app_name: &str) {
+    if let Some(logger) = LOGGER.lock().unwrap().as_mut() {
+        logger.app_name = Some(app_name.to_string());
+    }
+}
+
+/// Returns the paths of the log file, oldest segment first, once all log
+/// records are written to it. The segments that were pruned are missing.
+pub fn flushed_log_file_paths() -> Option<Vec<PathBuf>> {
+    LOGGER.lock().unwrap().as_mut().and_then(|l| {
+        l.flush();
+        l.log_file.as_ref().map(|f| {
+            let mut paths = rotated_log_files(&f.1);
+            paths.push(f.1.clone());
+            paths
+        })
+    })
+}
+
 pub fn move_log_file(directory: &Path) -> Result<()> {
     if let Some(logger) = LOGGER.lock().unwrap().as_mut() {
         create_dir_all(directory)?;
@@ -115,7 +313,7 @@ impl log::Log for LoggerRef {
     }
 }
 
-fn open_log_file(cmd_name: &str) -> Result<(fs::File, PathBuf)> {
+fn open_log_file(cmd_name: &str, retention: &Retention) -> Result<(fs::File, PathBuf)> {
     create_dir_all(&*FF_LOG_DIR)?;
     // When using FastFreeze in container mode, logs are opened in this directory,
     // which can be shared with other users. So we make it /tmp like
@@ -130,27 +328,30 @@ fn open_log_file(cmd_name: &str) -> Result<(fs::File, PathBuf)> {
                 cmd_name,
                 &*INVOCATION_ID));
 
-    let log_file = fs::OpenOptions::new()
-        .create(true)
-        .append(true)
-        .open(&log_file_path)
+    let log_file = open_file(&log_file_path)
         .with_context(|| format!("Failed to create log file at {}", log_file_path.display()))?;
 
+    // Retention is best effort.
+    let _ = retention.prune(&log_file_path);
+
     Ok((log_file, log_file_path))
 }
 
-pub fn init(level: LevelFilter, cmd_name: &'static str, use_log_file: bool) -> Result<()> {
+pub fn init(level: LevelFilter, cmd_name: &'static str, use_log_file: bool, format: LogFormat) -> Result<()> {
+    let retention = Retention::from_env()?;
+
     // Initializing the logger twice would be a logic error, so it's safe to unwrap().
     log::set_boxed_logger(Box::new(LoggerRef(&LOGGER))).unwrap();
     log::set_max_level(level);
 
     let log_file = if use_log_file {
-        Some(open_log_file(cmd_name)?)
+        Some(open_log_file(cmd_name, &retention)?)
     } else {
         None
     };
 
-    let logger = Logger { cmd_name, log_file, stdout_enabled: false };
+    let logger = Logger { cmd_name, format, app_name: None, log_file, log_file_size: 0,
+                          retention, stdout_enabled: false };
     LOGGER.lock().unwrap().replace(logger);
 
     if use_log_file {
@@ -168,3 +369,60 @@ pub fn init(level: LevelFilter, cmd_name: &'static str, use_log_file: bool) -> R
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_format_json() -> Result<()> {
+        let logger = Logger {
+            cmd_name: "run", format: LogFormat::Json, app_name: Some("app".to_string()),
+            log_file: None, log_file_size: 0, retention: Retention::from_env()?, stdout_enabled: false,
+        };
+        let format = |target: &str, msg: &str| logger.format_json(&Record::builder()
+            .level(log::Level::Info).target(target).args(format_args!("{}", msg)).build());
+
+        let line: serde_json::Value = serde_json::from_str(
+            &format(SUBPROCESS_LOG_TARGET, "criu> dumping"))?;
+        assert_eq!(line["level"], "info");
+        assert_eq!(line["command"], "run");
+        assert_eq!(line["app_name"], "app");
+        assert_eq!(line["subprocess"], "criu");
+        assert_eq!(line["msg"], "dumping");
+
+        let line: serde_json::Value = serde_json::from_str(
+            &format("fastfreeze", "a> b"))?;
+        assert_eq!(line["msg"], "a> b");
+        assert!(line.get("subprocess").is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_prune_log_files() -> Result<()> {
+        let dir = PathBuf::from("/tmp/ff-test-prune-logs");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir)?;
+        // Files are written oldest first, 100 bytes each.
+        let names = ["ff-1-run.log", "ff-2-run.log.1", "ff-2-run.log", "other.txt", "ff-3-run.log"];
+        for name in &names {
+            fs::write(dir.join(name), [b'x'; 100])?;
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        let exists = |name: &str| dir.join(name).exists();
+        assert_eq!(rotated_log_files(&dir.join("ff-2-run.log")), vec![dir.join("ff-2-run.log.1")]);
+
+        prune_log_files(&dir.join("ff-3-run.log"), 3, 1000)?;
+        assert!(!exists("ff-1-run.log"));
+        assert!(exists("ff-2-run.log.1") && exists("ff-2-run.log") && exists("other.txt"));
+
+        // Files of running commands are kept.
+        let _live = open_file(&dir.join("ff-2-run.log"))?;
+        prune_log_files(&dir.join("ff-3-run.log"), 3, 150)?;
+        assert!(!exists("ff-2-run.log.1") && exists("ff-2-run.log"));
+        assert!(exists("ff-3-run.log") && exists("other.txt"));
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
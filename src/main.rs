@@ -20,6 +20,8 @@ pub mod store;
 pub mod image;
 pub mod virt;
 pub mod metrics;
+pub mod metrics_sink;
+pub mod trace;
 pub mod consts;
 pub mod criu;
 pub mod filesystem;
@@ -28,7 +30,10 @@ pub mod lock;
 pub mod signal;
 pub mod container;
 pub mod ff_socket;
+pub mod control;
+pub mod prometheus;
 pub mod poller;
+pub mod open_files;
 
 #[macro_use]
 extern crate anyhow;
@@ -40,6 +45,7 @@ extern crate lazy_static;
 extern crate serde_json;
 
 use anyhow::Result;
+use std::time::Duration;
 use logger::is_logger_ready;
 use structopt::StructOpt;
 
@@ -68,7 +74,11 @@ fn main() {
         opts.run()
     }
 
-    if let Err(e) = do_main() {
+    let result = do_main();
+    metrics_sink::flush(Duration::from_secs(METRICS_FLUSH_TIMEOUT_SECS));
+    trace::finish(&result);
+
+    if let Err(e) = result {
         if is_logger_ready() {
             error!("{:#}", e);
         } else {
@@ -14,12 +14,18 @@
 
 use anyhow::{Result, Context};
 use std::{
+    collections::BTreeMap,
     ffi::OsString,
-    time::Instant,
+    sync::Mutex,
+    time::{Duration, Instant},
 };
 use crate::{
     consts::*,
+    prometheus,
+    metrics_sink,
     process::{Process, Command, ProcessError, ProcessGroupError},
+    store::take_retry_metrics,
+    trace::ActionSpan,
     util::JsonMerge,
 };
 use serde_json::Value;
@@ -31,20 +37,91 @@ lazy_static! {
     static ref ARGS_JSON: Value =
         serde_json::to_value(std::env::args().collect::<Vec<String>>())
             .expect("Failed to serialize CLI arguments into json");
+
+    // The last event of each action, served by the control API of the run command.
+    static ref LAST_EVENTS: Mutex<BTreeMap<String, Value>> = Mutex::new(BTreeMap::new());
+
+    // The phases timed for each action, in order, until the action's event is emitted.
+    static ref PHASES: Mutex<BTreeMap<String, Vec<(&'static str, Duration)>>> =
+        Mutex::new(BTreeMap::new());
 }
 
-pub fn emit_metrics(event: Value) -> Result<Option<Process>> {
-    let metrics_recorder_path = match METRICS_RECORDER_PATH.as_ref() {
-        Some(path) => path,
-        None => return Ok(None),
+/// Adds `duration` to the phase `name` of `action`. The phases are reported
+/// in the `phases` object of the action's event.
+pub fn record_phase(action: &str, name: &'static str, duration: Duration) {
+    let mut phases = PHASES.lock().unwrap();
+    let phases = phases.entry(action.to_string()).or_default();
+    match phases.iter_mut().find(|(n, _)| *n == name) {
+        Some((_, total)) => *total += duration,
+        None => phases.push((name, duration)),
+    }
+}
+
+/// Times consecutive phases of an action. Starting a phase ends the previous
+/// one. The current phase ends when the timer is dropped, so that failed
+/// actions report how far they went.
+pub struct PhaseTimer {
+    action: &'static str,
+    current: Option<(&'static str, Instant)>,
+}
+
+impl PhaseTimer {
+    pub fn new(action: &'static str) -> Self {
+        Self { action, current: None }
+    }
+
+    pub fn start(&mut self, name: &'static str) {
+        self.end();
+        self.current = Some((name, Instant::now()));
+    }
+
+    pub fn end(&mut self) {
+        if let Some((name, started_at)) = self.current.take() {
+            record_phase(self.action, name, started_at.elapsed());
+        }
+    }
+}
+
+impl Drop for PhaseTimer {
+    fn drop(&mut self) {
+        self.end();
+    }
+}
+
+/// Returns the phases of `action` timed since the last call, as a metrics
+/// fragment. Returns an empty object if there were none.
+fn take_phase_metrics(action: &str) -> Value {
+    let phases = match PHASES.lock().unwrap().remove(action) {
+        Some(phases) => phases,
+        None => return json!({}),
     };
 
+    debug!("Phases of {}: {}", action, phases.iter()
+        .map(|(name, duration)| format!("{}={:.3}s", name, duration.as_secs_f64()))
+        .collect::<Vec<_>>().join(" "));
+
+    let phases = phases.into_iter()
+        .map(|(name, duration)| (name.to_string(), json!(duration.as_secs_f64())))
+        .collect::<serde_json::Map<_,_>>();
+    json!({"phases": phases})
+}
+
+/// Sends the event to the sinks of FF_METRICS_SINK, and spawns the
+/// FF_METRICS_RECORDER program, if any.
+pub fn emit_metrics(event: Value) -> Result<Option<Process>> {
     let payload = json!({
         "invocation_id": *INVOCATION_ID,
         "elapsed_time": START_TIME.elapsed().as_secs_f64(),
         "cli_args": *ARGS_JSON,
     }).merge(event);
 
+    metrics_sink::send(&payload);
+
+    let metrics_recorder_path = match METRICS_RECORDER_PATH.as_ref() {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+
     let p = Command::new_shell(&metrics_recorder_path)
         .arg(&serde_json::to_string(&payload)?)
         .show_cmd_on_spawn(log_enabled!(log::Level::Trace))
@@ -58,16 +135,19 @@ pub fn with_metrics_raw<F,M,R>(action: &str, f: F, metrics_f: M) -> Result<R>
     where F: FnOnce() -> Result<R>,
           M: Fn(&Result<R>) -> Value
 {
-    if METRICS_RECORDER_PATH.is_none() {
-        return f();
-    }
-
     let start_time = Instant::now();
+    let span = ActionSpan::enter(action);
     let result = f();
     let event = json!({
         "action": action,
         "duration": start_time.elapsed().as_secs_f64(),
-    }).merge(metrics_f(&result));
+    }).merge(metrics_f(&result))
+      .merge(take_retry_metrics())
+      .merge(take_phase_metrics(action));
+
+    span.exit(&event);
+    LAST_EVENTS.lock().unwrap().insert(action.to_string(), event.clone());
+    prometheus::record(&event);
 
     // If the metrics CLI fails, we don't return the error to the caller.
     // Instead, we log the error and move on.
@@ -76,6 +156,11 @@ pub fn with_metrics_raw<F,M,R>(action: &str, f: F, metrics_f: M) -> Result<R>
     result
 }
 
+/// Returns the last event of each action performed by this process.
+pub fn last_events() -> Value {
+    json!(*LAST_EVENTS.lock().unwrap())
+}
+
 pub fn with_metrics<F,M,R>(action: &str, f: F, metrics_f: M) -> Result<R>
     where F: FnOnce() -> Result<R>,
           M: Fn(&R) -> Value
@@ -104,3 +189,27 @@ pub fn metrics_error_json(e: &anyhow::Error) -> Value {
         json!({})
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_phases() {
+        assert_eq!(take_phase_metrics("test"), json!({}));
+
+        record_phase("test", "download", Duration::from_millis(500));
+        {
+            let mut timer = PhaseTimer::new("test");
+            timer.start("untar");
+            timer.start("criu_restore");
+        }
+        record_phase("test", "download", Duration::from_millis(250));
+
+        let metrics = take_phase_metrics("test");
+        let phases = metrics["phases"].as_object().unwrap();
+        assert_eq!(phases.keys().collect::<Vec<_>>(), vec!["criu_restore", "download", "untar"]);
+        assert_eq!(phases["download"], json!(0.75));
+        assert_eq!(take_phase_metrics("test"), json!({}));
+    }
+}
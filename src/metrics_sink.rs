@@ -0,0 +1,267 @@
+//  Copyright 2020 Two Sigma Investments, LP.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+use anyhow::{Result, Context};
+use std::{
+    fs,
+    io::Write,
+    net::{ToSocketAddrs, UdpSocket},
+    os::unix::net::UnixStream,
+    path::PathBuf,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{sync_channel, SyncSender, TrySendError},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use serde_json::Value;
+
+// Metrics sinks are an alternative to FF_METRICS_RECORDER that doesn't fork a
+// program per event. They are selected with FF_METRICS_SINK, a comma separated
+// list of URLs:
+// * udp://host:port   StatsD. Each event becomes a counter per outcome, a timer
+//                     for the duration, and gauges for the other numbers.
+// * file:/path        JSON lines, appended to the file
+// * unix:/path        JSON lines, sent to a unix stream socket
+// Events are queued and sent by a background thread, so that emitting metrics
+// never blocks. When the queue is full, events are dropped.
+
+const QUEUE_SIZE: usize = 256;
+// StatsD servers typically expect datagrams to fit in an Ethernet frame
+const MAX_UDP_PAYLOAD: usize = 1400;
+
+lazy_static! {
+    static ref QUEUE: Option<Queue> = std::env::var("FF_METRICS_SINK").ok()
+        .and_then(|urls| Queue::spawn(&urls)
+            .map_err(|e| warn!("Metrics are not sent to FF_METRICS_SINK: {:#}", e))
+            .ok());
+}
+
+enum Sink {
+    StatsD { socket: UdpSocket },
+    File { path: PathBuf },
+    Unix { path: PathBuf, stream: Option<UnixStream> },
+}
+
+impl FromStr for Sink {
+    type Err = anyhow::Error;
+
+    fn from_str(url: &str) -> Result<Self> {
+        if let Some(addr) = url.strip_prefix("udp://") {
+            let addr = addr.to_socket_addrs()
+                .with_context(|| format!("Invalid address in {}", url))?
+                .next()
+                .ok_or_else(|| anyhow!("{} does not resolve to any address", url))?;
+            let socket = UdpSocket::bind(if addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" })?;
+            socket.connect(addr)?;
+            Ok(Sink::StatsD { socket })
+        } else if let Some(path) = url.strip_prefix("file:") {
+            Ok(Sink::File { path: PathBuf::from(path) })
+        } else if let Some(path) = url.strip_prefix("unix:") {
+            Ok(Sink::Unix { path: PathBuf::from(path), stream: None })
+        } else {
+            bail!("Unknown metrics sink `{}`. Expected udp://host:port, file:/path, or unix:/path", url)
+        }
+    }
+}
+
+impl Sink {
+    fn send(&mut self, event: &Value) -> Result<()> {
+        match self {
+            Sink::StatsD { socket } => {
+                for datagram in pack_datagrams(statsd_lines(event)) {
+                    socket.send(datagram.as_bytes())?;
+                }
+            }
+            Sink::File { path } => {
+                let mut file = fs::OpenOptions::new().create(true).append(true).open(&path)
+                    .with_context(|| format!("Failed to open {}", path.display()))?;
+                // A single write keeps lines whole when several processes append.
+                file.write_all(json_line(event)?.as_bytes())
+                    .with_context(|| format!("Failed to write to {}", path.display()))?;
+            }
+            Sink::Unix { path, stream } => {
+                // We connect lazily, and reconnect on the next event after an error.
+                if stream.is_none() {
+                    *stream = Some(UnixStream::connect(&path)
+                        .with_context(|| format!("Failed to connect to {}", path.display()))?);
+                }
+                // unwrap() is safe, the stream was just set.
+                if let Err(e) = stream.as_mut().unwrap().write_all(json_line(event)?.as_bytes()) {
+                    *stream = None;
+                    return Err(e).with_context(|| format!("Failed to write to {}", path.display()));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn json_line(event: &Value) -> Result<String> {
+    Ok(serde_json::to_string(event)? + "\n")
+}
+
+/// Adds the numbers of `value` as gauges. Nested keys are joined with dots.
+/// Arrays (e.g., per-shard stats) are skipped.
+fn statsd_gauges(lines: &mut Vec<String>, prefix: &str, value: &Value) {
+    match value {
+        Value::Object(map) => for (key, value) in map {
+            statsd_gauges(lines, &format!("{}.{}", prefix, key), value);
+        },
+        Value::Number(n) => if let Some(n) = n.as_f64() {
+            lines.push(format!("{}:{}|g", prefix, n));
+        },
+        _ => {}
+    }
+}
+
+fn statsd_lines(event: &Value) -> Vec<String> {
+    let prefix = format!("fastfreeze.{}", event["action"].as_str().unwrap_or("unknown"));
+    let mut lines = vec![];
+
+    if let Some(outcome) = event["outcome"].as_str() {
+        lines.push(format!("{}.{}:1|c", prefix, outcome));
+    }
+
+    if let Value::Object(map) = event {
+        for (key, value) in map {
+            match key.as_str() {
+                "duration" => if let Some(duration) = value.as_f64() {
+                    lines.push(format!("{}.duration:{}|ms", prefix, (duration * 1000.0).round()));
+                },
+                // The time since the FastFreeze process started is not about the action
+                "elapsed_time" => {}
+                _ => statsd_gauges(&mut lines, &format!("{}.{}", prefix, key), value),
+            }
+        }
+    }
+
+    lines
+}
+
+/// StatsD accepts multiple metrics per datagram, separated by new lines.
+fn pack_datagrams(lines: Vec<String>) -> Vec<String> {
+    let mut datagrams: Vec<String> = vec![];
+    for line in lines {
+        match datagrams.last_mut() {
+            Some(datagram) if datagram.len() + 1 + line.len() <= MAX_UDP_PAYLOAD => {
+                datagram.push('\n');
+                datagram.push_str(&line);
+            }
+            _ => datagrams.push(line),
+        }
+    }
+    datagrams
+}
+
+struct Queue {
+    sender: Mutex<SyncSender<Value>>,
+    /// Number of events queued, or being sent
+    pending: Arc<AtomicUsize>,
+}
+
+impl Queue {
+    fn spawn(urls: &str) -> Result<Self> {
+        let mut sinks = urls.split(',')
+            .map(|url| url.trim().parse())
+            .collect::<Result<Vec<Sink>>>()?;
+
+        let (sender, receiver) = sync_channel::<Value>(QUEUE_SIZE);
+        let pending = Arc::new(AtomicUsize::new(0));
+        let worker_pending = pending.clone();
+        std::thread::spawn(move || {
+            for event in receiver {
+                for sink in &mut sinks {
+                    if let Err(e) = sink.send(&event) {
+                        warn!("Failed to send metrics: {:#}", e);
+                    }
+                }
+                worker_pending.fetch_sub(1, Ordering::SeqCst);
+            }
+        });
+
+        Ok(Self { sender: Mutex::new(sender), pending })
+    }
+
+    fn push(&self, event: Value) {
+        self.pending.fetch_add(1, Ordering::SeqCst);
+        match self.sender.lock().unwrap().try_send(event) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) | Err(TrySendError::Disconnected(_)) => {
+                self.pending.fetch_sub(1, Ordering::SeqCst);
+                debug!("Metrics queue is full, dropping event");
+            }
+        }
+    }
+}
+
+/// Queues the event for the sinks of FF_METRICS_SINK, if any. It does not block.
+pub fn send(event: &Value) {
+    if let Some(ref queue) = *QUEUE {
+        queue.push(event.clone());
+    }
+}
+
+/// Waits for the queued events to be sent, up to `timeout`. Called before exiting.
+pub fn flush(timeout: Duration) {
+    if let Some(ref queue) = *QUEUE {
+        let deadline = Instant::now() + timeout;
+        while queue.pending.load(Ordering::SeqCst) > 0 && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_statsd_lines() {
+        let event = json!({
+            "action": "checkpoint",
+            "outcome": "success",
+            "duration": 1.5,
+            "elapsed_time": 2.0,
+            "invocation_id": "abc",
+            "stats": {"total_size_mb": 12.5, "shards": [{"size_mb": 12.5}]},
+        });
+        let mut lines = statsd_lines(&event);
+        lines.sort();
+        assert_eq!(lines, vec![
+            "fastfreeze.checkpoint.duration:1500|ms",
+            "fastfreeze.checkpoint.stats.total_size_mb:12.5|g",
+            "fastfreeze.checkpoint.success:1|c",
+        ]);
+
+        let datagrams = pack_datagrams(vec!["a".repeat(1000), "b".repeat(300), "c".repeat(300)]);
+        assert_eq!(datagrams.len(), 2);
+        assert_eq!(datagrams[0].len(), 1301);
+    }
+
+    #[test]
+    fn test_file_sink() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("ff-metrics-test-{}.jsonl", std::process::id()));
+        let mut sink: Sink = format!("file:{}", path.display()).parse()?;
+        sink.send(&json!({"action": "a"}))?;
+        sink.send(&json!({"action": "b"}))?;
+        assert_eq!(fs::read_to_string(&path)?, "{\"action\":\"a\"}\n{\"action\":\"b\"}\n");
+        fs::remove_file(&path)?;
+
+        assert!("http://localhost".parse::<Sink>().is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,173 @@
+//  Copyright 2020 Two Sigma Investments, LP.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+use anyhow::Result;
+use std::{
+    collections::HashSet,
+    ffi::OsStr,
+    fs,
+    os::unix::{ffi::OsStrExt, io::RawFd},
+    path::{Path, PathBuf},
+};
+use nix::unistd::Pid;
+use crate::signal::get_process_tree;
+
+// We discover the files of the application by looking at /proc/<pid>/fd and
+// /proc/<pid>/maps of each process of the application tree.
+// Only regular files are reported. Sockets, pipes, devices, and anonymous
+// memory are of no interest to us.
+
+const DELETED_SUFFIX: &[u8] = b" (deleted)";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FileUsage {
+    Fd(RawFd),
+    Mapped,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct OpenFile {
+    pub pid: Pid,
+    pub usage: FileUsage,
+    /// When the file is deleted, this is the path it had.
+    pub path: PathBuf,
+    pub deleted: bool,
+    /// None when the file cannot be stat'ed. This is the case of deleted
+    /// mapped files when we lack the privileges to read /proc/<pid>/map_files.
+    pub size: Option<u64>,
+}
+
+/// Returns the regular files opened or memory mapped by the process tree
+/// rooted at `root_pid`. Processes may disappear while we look at them, so
+/// errors are ignored.
+pub fn list_open_files(root_pid: Pid) -> Result<Vec<OpenFile>> {
+    let mut files = Vec::new();
+    for pid in get_process_tree(root_pid)? {
+        list_fds(pid, &mut files);
+        list_mappings(pid, &mut files);
+    }
+    Ok(files)
+}
+
+/// Splits the " (deleted)" suffix that the kernel appends to the path of unlinked files.
+fn split_deleted(path: &[u8]) -> (PathBuf, bool) {
+    match path.strip_suffix(DELETED_SUFFIX) {
+        Some(path) => (PathBuf::from(OsStr::from_bytes(path)), true),
+        None => (PathBuf::from(OsStr::from_bytes(path)), false),
+    }
+}
+
+fn list_fds(pid: Pid, files: &mut Vec<OpenFile>) {
+    let fd_dir = Path::new("/proc").join(pid.to_string()).join("fd");
+    let entries = match fs::read_dir(&fd_dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+
+    for entry in entries.filter_map(|e| e.ok()) {
+        let fd = match entry.file_name().to_str().and_then(|s| s.parse().ok()) {
+            Some(fd) => fd,
+            None => continue,
+        };
+        // stat() follows the magic link, even when the file is deleted.
+        let size = match fs::metadata(entry.path()) {
+            Ok(meta) if meta.is_file() => meta.len(),
+            _ => continue,
+        };
+        let target = match fs::read_link(entry.path()) {
+            Ok(target) => target,
+            Err(_) => continue,
+        };
+        let (path, deleted) = split_deleted(target.as_os_str().as_bytes());
+        files.push(OpenFile { pid, usage: FileUsage::Fd(fd), path, deleted, size: Some(size) });
+    }
+}
+
+fn list_mappings(pid: Pid, files: &mut Vec<OpenFile>) {
+    let proc_dir = Path::new("/proc").join(pid.to_string());
+    let maps = match fs::read(proc_dir.join("maps")) {
+        Ok(maps) => maps,
+        Err(_) => return,
+    };
+
+    // A file is typically mapped multiple times (text, data, ...)
+    let mut seen = HashSet::new();
+    for line in maps.split(|c| *c == b'\n') {
+        if let Some((range, path, deleted)) = parse_mapping(line) {
+            if seen.insert(path.clone()) {
+                // map_files gives access to deleted files, but requires CAP_SYS_ADMIN.
+                let stat_path = if deleted { proc_dir.join("map_files").join(range) } else { path.clone() };
+                let size = fs::metadata(stat_path).ok().map(|m| m.len());
+                files.push(OpenFile { pid, usage: FileUsage::Mapped, path, deleted, size });
+            }
+        }
+    }
+}
+
+/// Parses a line of /proc/<pid>/maps, of the form:
+/// "7f2c8e5c1000-7f2c8e5e3000 r--p 00000000 fd:01 1835099    /usr/lib/libc.so.6"
+/// Returns the address range and the path of the mapped file, if it's a regular file.
+fn parse_mapping(line: &[u8]) -> Option<(&OsStr, PathBuf, bool)> {
+    // The first five fields are separated by a single space, the path is padded.
+    let mut fields = line.splitn(6, |c| *c == b' ');
+    let range = OsStr::from_bytes(fields.next()?);
+    let inode = fields.nth(3)?;
+    let path = fields.next()?;
+    let path = &path[path.iter().position(|c| *c != b' ')?..];
+
+    // Anonymous memory has an inode of 0, and [heap], [stack], ... are not files.
+    if inode == b"0" || !path.starts_with(b"/") {
+        return None;
+    }
+    // Shared memory (SysV and memfd) is reported as deleted files, but it is
+    // not on the file system.
+    if path.starts_with(b"/SYSV") || path.starts_with(b"/memfd:") || path.starts_with(b"/dev/") {
+        return None;
+    }
+
+    let (path, deleted) = split_deleted(path);
+    if !deleted && !fs::metadata(&path).is_ok_and(|m| m.is_file()) {
+        return None;
+    }
+    Some((range, path, deleted))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn test_parse_mapping() {
+        assert_eq!(parse_mapping(b"7f2c8e5c1000-7f2c8e5e3000 rw-p 00000000 00:00 0    [heap]"), None);
+        assert_eq!(parse_mapping(b"7f2c8e5c1000-7f2c8e5e3000 rw-p 00000000 00:00 0 "), None);
+        assert_eq!(parse_mapping(b"7f2c8e5c1000-7f2c8e5e3000 rw-s 00000000 00:01 1024    /SYSV00000000 (deleted)"), None);
+        assert_eq!(parse_mapping(b"7f2c8e5c1000-7f2c8e5e3000 rw-s 00000000 fd:01 1835099    /tmp/my file (deleted)"),
+                   Some((OsStr::new("7f2c8e5c1000-7f2c8e5e3000"), PathBuf::from("/tmp/my file"), true)));
+    }
+
+    #[test]
+    fn test_list_open_files() -> Result<()> {
+        let path = PathBuf::from("/tmp/ff-test-open-files");
+        let mut file = fs::File::create(&path)?;
+        file.write_all(b"data")?;
+        let files = list_open_files(nix::unistd::getpid())?;
+        assert!(files.iter().any(|f| f.path == path && !f.deleted && matches!(f.usage, FileUsage::Fd(_))));
+
+        fs::remove_file(&path)?;
+        let files = list_open_files(nix::unistd::getpid())?;
+        assert!(files.iter().any(|f| f.path == path && f.deleted && f.size == Some(4)));
+        Ok(())
+    }
+}
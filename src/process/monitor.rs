@@ -15,7 +15,7 @@
 use anyhow::Result;
 use nix::{
     sys::signal::{kill, pthread_sigmask, Signal, SigmaskHow, SigSet},
-    sys::wait::{wait, WaitStatus},
+    sys::wait::{waitpid, WaitPidFlag, WaitStatus},
     unistd::Pid
 };
 use crate::cli::ExitCode;
@@ -67,7 +67,10 @@ pub fn monitor_child(pid_child: Pid) -> Result<()> {
     pthread_sigmask(SigmaskHow::SIG_UNBLOCK, Some(&SigSet::all()), None)?;
 
     loop {
-        match wait()? {
+        // __WNOTHREAD leaves alone the children of our other threads. The
+        // FastFreeze daemon thread waits on the processes of the checkpoints it
+        // performs (see control.rs). Orphans still get reparented to us.
+        match waitpid(None, Some(WaitPidFlag::__WNOTHREAD))? {
             WaitStatus::Exited(pid, 0) if pid == pid_child => {
                 return Ok(());
             }
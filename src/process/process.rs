@@ -16,7 +16,7 @@ use anyhow::{Result, Context};
 use std::{
     borrow::Cow,
     os::unix::io::RawFd,
-    time::{Duration, Instant},
+    time::{Duration, Instant, SystemTime},
 };
 use nix::{
     sys::signal::{self, Signal},
@@ -32,7 +32,10 @@ pub use std::process::{
     Output as StdOutput,
     Child
 };
-use crate::signal::{check_for_pending_sigterm, retry_on_interrupt};
+use crate::{
+    signal::{check_for_pending_sigterm, retry_on_interrupt},
+    trace,
+};
 use super::{
     stderr_logger::{StderrReader, StderrTail},
     ProcessError,
@@ -47,6 +50,7 @@ pub struct Process {
     display_cmd: String,
     stderr_reader: Option<StderrReader>,
     stderr_tail: Option<StderrTail>,
+    spawned_at: SystemTime,
 }
 
 impl Process {
@@ -56,7 +60,7 @@ impl Process {
         );
         let stderr_tail = stderr_log_prefix.map(StderrTail::new);
 
-        Self { inner, display_cmd, stderr_reader, stderr_tail }
+        Self { inner, display_cmd, stderr_reader, stderr_tail, spawned_at: SystemTime::now() }
     }
 
     pub fn pid(&self) -> i32 { self.inner.id() as i32 }
@@ -130,7 +134,7 @@ impl Process {
     }
 
     pub fn wait_with_output(self) -> Result<Output> {
-        let Process { display_cmd, inner, stderr_reader, stderr_tail: _ } = self;
+        let Process { display_cmd, inner, stderr_reader, .. } = self;
 
         assert!(stderr_reader.is_none(),
                 "stderr logging is not supported when using wait_with_output()");
@@ -150,6 +154,18 @@ impl Process {
         })
     }
 
+    /// Records the trace span of the process, once it has exited with `exit_status`.
+    /// The span is named after the stderr log prefix, or the program name.
+    pub fn record_trace_span(&self, exit_status: ExitStatus) {
+        let name = match self.stderr_tail {
+            Some(ref stderr_tail) => stderr_tail.log_prefix.to_string(),
+            None => self.display_cmd.split_whitespace().next().unwrap_or_default().to_string(),
+        };
+        let stderr_tail = self.stderr_tail.as_ref()
+            .map(|st| st.tail.iter().map(|line| line.to_string()).collect());
+        trace::record_process(&name, &self.display_cmd, self.spawned_at, exit_status, stderr_tail);
+    }
+
     pub fn stderr_logger_fd(&self) -> Option<RawFd> {
         self.stderr_reader.as_ref().map(|r| r.fd)
     }
@@ -16,6 +16,7 @@ use anyhow::{Result, Context};
 use std::{
     os::unix::io::AsRawFd,
     io::{ErrorKind, Read},
+    process::ExitStatus,
     time::{Duration, Instant},
     fs, iter,
 };
@@ -27,6 +28,7 @@ use nix::{
 use crate::{
     consts::*,
     util::{poll_nointr, Pipe},
+    signal::{check_for_pending_sigterm, sigterm_fd},
 };
 use super::{Process, ProcessError, ProcessGroupError};
 
@@ -73,6 +75,14 @@ impl From<Process> for ProcessMembership {
 }
 
 impl ProcessMembership {
+    /// Marks the process as exited, and records its trace span the first time.
+    fn set_exited(&mut self, exit_status: ExitStatus) {
+        if !self.exited {
+            self.exited = true;
+            self.inner.record_trace_span(exit_status);
+        }
+    }
+
     pub fn non_killable(self) -> Self {
         Self { killable: false, ..self }
     }
@@ -121,6 +131,7 @@ impl ProcessGroup {
     /// Returns an error if a process has exited with a failure.
     /// Return Ok(true) if some children are remaining, Ok(false) otherwise.
     pub fn try_wait_for_success(&mut self) -> Result<bool> {
+        check_for_pending_sigterm()?;
         self.drain_sigchld_pipe();
 
         // We join the error messages of all errored children.
@@ -130,9 +141,11 @@ impl ProcessGroup {
         // one died first. So we report both errors.
         let mut errors = Vec::new();
         for child in &mut self.children {
-            if child.inner.try_wait()?.is_some() { // has child exited ?
-                child.exited = true;
-                if let Err(err) = child.inner.wait_for_success() { // has child errored ?
+            if let Some(exit_status) = child.inner.try_wait()? { // has child exited ?
+                // wait_for_success() drains stderr, which goes in the trace span.
+                let result = child.inner.wait_for_success();
+                child.set_exited(exit_status);
+                if let Err(err) = result { // has child errored ?
                     errors.push(err.downcast::<ProcessError>()?);
                 }
             }
@@ -147,11 +160,12 @@ impl ProcessGroup {
 
     pub fn poll_fds(&self) -> Vec<PollFd> {
         // Collect all the fd of the stderr that we should be monitoring
-        // with the fd of the sigchld. Drainage of stderrs happens in
-        // child.inner.try_wait() within try_wait_for_success().
+        // with the fd of the sigchld, and the one of SIGTERM. Drainage of stderrs
+        // happens in child.inner.try_wait() within try_wait_for_success().
         self.children.iter()
             .filter_map(|c| c.inner.stderr_logger_fd())
             .chain(iter::once(self.sigchld_pipe.as_raw_fd()))
+            .chain(iter::once(sigterm_fd()))
             .map(|fd| PollFd::new(fd, PollFlags::POLLIN))
             .collect()
     }
@@ -165,6 +179,17 @@ impl ProcessGroup {
         Ok(())
     }
 
+    /// Waits for the child `id` to exit, while watching the other children
+    /// for failures, and for termination requests, like `wait_for_success()`.
+    pub fn wait_for_exit(&mut self, id: ProcessHandle) -> Result<()> {
+        while self.try_wait_for_success()? && !self.children[id.0].exited {
+            let timeout = -1;
+            poll_nointr(&mut self.poll_fds(), timeout)
+                .context("Failed to poll()")?;
+        }
+        Ok(())
+    }
+
     pub fn terminate(&mut self) -> Result<()> {
         // Step 1: SIGTERM all killable children
         let mut killables = self.children.iter_mut()
@@ -192,8 +217,8 @@ impl ProcessGroup {
         // Step 3: wait for all children to exit, including non-killable
         // children.
         for child in &mut self.children {
-            child.inner.wait()?;
-            child.exited = true;
+            let exit_status = child.inner.wait()?;
+            child.set_exited(exit_status);
         }
 
         // Cleanup the SIGCHLD hook.
@@ -35,6 +35,7 @@ pub use std::process::{
 };
 use crate::{
     consts::*,
+    logger::SUBPROCESS_LOG_TARGET,
 };
 
 // We create our own `Child` wrapper to provide better error context.
@@ -113,7 +114,7 @@ impl StderrTail {
             return;
         }
 
-        info!("{}> {}", self.log_prefix, line);
+        info!(target: SUBPROCESS_LOG_TARGET, "{}> {}", self.log_prefix, line);
 
         if self.tail.len() == self.tail.capacity() {
             self.tail.pop_front();
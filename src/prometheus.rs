@@ -0,0 +1,360 @@
+//  Copyright 2020 Two Sigma Investments, LP.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+use anyhow::{Result, Context};
+use std::{
+    collections::BTreeMap,
+    fs,
+    os::unix::{
+        io::{AsRawFd, RawFd},
+        net::UnixDatagram,
+    },
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::SystemTime,
+};
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+use crate::consts::*;
+
+// The run command (the supervisor) keeps counters and histograms of the
+// checkpoints and restores of the application, and exposes them in the
+// Prometheus text format. The checkpoint command runs in another process, so it
+// sends its samples to the run command via a unix socket (METRICS_SOCKET_PATH).
+// The metrics are served by the control API (see control.rs), and can be
+// written to a file for the node-exporter textfile collector.
+
+const DURATION_BUCKETS: &[f64] = &[0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0, 600.0];
+const SINCE_CHECKPOINT_BUCKETS: &[f64] = &[1.0, 10.0, 60.0, 300.0, 900.0, 3600.0, 6.0*3600.0, 24.0*3600.0, 7.0*24.0*3600.0];
+// In MiB, converted to bytes when exposed
+const SIZE_BUCKETS_MB: &[f64] = &[1.0, 4.0, 16.0, 64.0, 256.0, 1024.0, 4096.0, 16384.0, 65536.0];
+const RATE_BUCKETS_MB: &[f64] = &[10.0, 50.0, 100.0, 250.0, 500.0, 1000.0, 2500.0];
+
+lazy_static! {
+    // Only the run command has a registry, see enable().
+    static ref REGISTRY: Mutex<Option<Registry>> = Mutex::new(None);
+}
+
+/// The part of a metrics event that we keep track of.
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+struct Sample {
+    action: String,
+    outcome: String,
+    duration_sec: f64,
+    image_size_mb: Option<f64>,
+    rate_mb_per_sec: Option<f64>,
+    time_frozen_sec: Option<f64>,
+    duration_since_checkpoint_sec: Option<f64>,
+}
+
+impl Sample {
+    /// Returns None for events other than checkpoints and restores.
+    fn from_event(event: &Value) -> Option<Self> {
+        let action = event["action"].as_str()?;
+        if action != "checkpoint" && action != "restore" {
+            return None;
+        }
+        let stats = &event["stats"];
+        Some(Self {
+            action: action.to_string(),
+            outcome: event["outcome"].as_str().unwrap_or("error").to_string(),
+            duration_sec: event["duration"].as_f64().unwrap_or_default(),
+            image_size_mb: stats["total_size_mb"].as_f64(),
+            rate_mb_per_sec: stats["rate_mb_per_sec"].as_f64(),
+            time_frozen_sec: stats["time_frozen_sec"].as_f64(),
+            duration_since_checkpoint_sec: event["duration_since_checkpoint_sec"].as_f64(),
+        })
+    }
+}
+
+struct Histogram {
+    bounds: &'static [f64],
+    /// Cumulative, as exposed: counts[i] is the number of values <= bounds[i]
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Self { bounds, counts: vec![0; bounds.len()], sum: 0.0, count: 0 }
+    }
+
+    fn observe(&mut self, value: f64) {
+        for (bound, count) in self.bounds.iter().zip(self.counts.iter_mut()) {
+            if value <= *bound {
+                *count += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+
+    /// `labels` is of the form `action="restore",`. `scale` converts the
+    /// bounds and the sum to the base unit.
+    fn render(&self, out: &mut Vec<String>, name: &str, labels: &str, scale: f64) {
+        for (bound, count) in self.bounds.iter().zip(&self.counts) {
+            out.push(format!("{}_bucket{{{}le=\"{}\"}} {}", name, labels, bound * scale, count));
+        }
+        out.push(format!("{}_bucket{{{}le=\"+Inf\"}} {}", name, labels, self.count));
+        let labels = match labels.trim_end_matches(',') {
+            "" => String::new(),
+            labels => format!("{{{}}}", labels),
+        };
+        out.push(format!("{}_sum{} {}", name, labels, self.sum * scale));
+        out.push(format!("{}_count{} {}", name, labels, self.count));
+    }
+}
+
+fn header(out: &mut Vec<String>, name: &str, kind: &str, help: &str) {
+    out.push(format!("# HELP {} {}", name, help));
+    out.push(format!("# TYPE {} {}", name, kind));
+}
+
+struct Registry {
+    /// By (action, outcome)
+    operations: BTreeMap<(String, String), u64>,
+    /// By action, in seconds since the epoch
+    last_success: BTreeMap<String, f64>,
+    /// By action
+    duration: BTreeMap<String, Histogram>,
+    image_size: BTreeMap<String, Histogram>,
+    rate: BTreeMap<String, Histogram>,
+    time_frozen: Histogram,
+    duration_since_checkpoint: Histogram,
+    textfile: Option<PathBuf>,
+}
+
+impl Registry {
+    fn new(textfile: Option<PathBuf>) -> Self {
+        Self {
+            operations: BTreeMap::new(),
+            last_success: BTreeMap::new(),
+            duration: BTreeMap::new(),
+            image_size: BTreeMap::new(),
+            rate: BTreeMap::new(),
+            time_frozen: Histogram::new(DURATION_BUCKETS),
+            duration_since_checkpoint: Histogram::new(SINCE_CHECKPOINT_BUCKETS),
+            textfile,
+        }
+    }
+
+    fn observe(&mut self, sample: &Sample) {
+        let action = &sample.action;
+        *self.operations.entry((action.clone(), sample.outcome.clone())).or_default() += 1;
+        self.duration.entry(action.clone())
+            .or_insert_with(|| Histogram::new(DURATION_BUCKETS))
+            .observe(sample.duration_sec);
+
+        if sample.outcome != "success" {
+            return;
+        }
+
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
+        self.last_success.insert(action.clone(), now.as_secs_f64());
+        if let Some(size) = sample.image_size_mb {
+            self.image_size.entry(action.clone())
+                .or_insert_with(|| Histogram::new(SIZE_BUCKETS_MB))
+                .observe(size);
+        }
+        if let Some(rate) = sample.rate_mb_per_sec {
+            self.rate.entry(action.clone())
+                .or_insert_with(|| Histogram::new(RATE_BUCKETS_MB))
+                .observe(rate);
+        }
+        if let Some(time_frozen) = sample.time_frozen_sec {
+            self.time_frozen.observe(time_frozen);
+        }
+        if let Some(duration) = sample.duration_since_checkpoint_sec {
+            self.duration_since_checkpoint.observe(duration);
+        }
+    }
+
+    fn render(&self) -> String {
+        let mut out = vec![];
+        let mb = MB as f64;
+
+        header(&mut out, "fastfreeze_operations_total", "counter",
+               "Number of checkpoints and restores, by outcome");
+        for ((action, outcome), count) in &self.operations {
+            out.push(format!("fastfreeze_operations_total{{action=\"{}\",outcome=\"{}\"}} {}",
+                             action, outcome, count));
+        }
+
+        header(&mut out, "fastfreeze_last_success_timestamp_seconds", "gauge",
+               "Time of the last successful checkpoint or restore");
+        for (action, time) in &self.last_success {
+            out.push(format!("fastfreeze_last_success_timestamp_seconds{{action=\"{}\"}} {}", action, time));
+        }
+
+        let histograms: &[(&str, &str, &BTreeMap<String, Histogram>, f64)] = &[
+            ("fastfreeze_duration_seconds", "Duration of checkpoints and restores", &self.duration, 1.0),
+            ("fastfreeze_image_size_bytes", "Uncompressed size of the image", &self.image_size, mb),
+            ("fastfreeze_transfer_rate_bytes_per_second", "Uncompressed transfer rate of the image", &self.rate, mb),
+        ];
+        for (name, help, histograms, scale) in histograms {
+            header(&mut out, name, "histogram", help);
+            for (action, histogram) in histograms.iter() {
+                histogram.render(&mut out, name, &format!("action=\"{}\",", action), *scale);
+            }
+        }
+
+        header(&mut out, "fastfreeze_checkpoint_time_frozen_seconds", "histogram",
+               "Time during which the application was frozen by a checkpoint");
+        self.time_frozen.render(&mut out, "fastfreeze_checkpoint_time_frozen_seconds", "", 1.0);
+
+        header(&mut out, "fastfreeze_restore_duration_since_checkpoint_seconds", "histogram",
+               "Time between a checkpoint and its restore");
+        self.duration_since_checkpoint.render(&mut out,
+            "fastfreeze_restore_duration_since_checkpoint_seconds", "", 1.0);
+
+        out.push(String::new());
+        out.join("\n")
+    }
+
+    /// The file is replaced atomically, so that the collector never reads a partial file.
+    fn write_textfile(&self, path: &Path) -> Result<()> {
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        fs::write(&tmp_path, self.render())
+            .with_context(|| format!("Failed to write {}", Path::new(&tmp_path).display()))?;
+        fs::rename(&tmp_path, path)
+            .with_context(|| format!("Failed to rename {} to {}",
+                                     Path::new(&tmp_path).display(), path.display()))
+    }
+
+    fn observe_and_publish(&mut self, sample: &Sample) {
+        self.observe(sample);
+        if let Some(ref path) = self.textfile {
+            if let Err(e) = self.write_textfile(path) {
+                warn!("{:#}", e);
+            }
+        }
+    }
+}
+
+/// Keeps track of the metrics in this process. Called by the run command.
+/// When `textfile` is given, the metrics are written to it on each update.
+pub fn enable(textfile: Option<PathBuf>) -> Result<()> {
+    let registry = Registry::new(textfile);
+    if let Some(ref path) = registry.textfile {
+        registry.write_textfile(path)?;
+    }
+    *REGISTRY.lock().unwrap() = Some(registry);
+    Ok(())
+}
+
+/// Returns the metrics in the Prometheus text format.
+pub fn render() -> String {
+    REGISTRY.lock().unwrap().as_ref()
+        .map(|r| r.render())
+        .unwrap_or_default()
+}
+
+/// Records a metrics event. Outside of the run command, the event goes to the
+/// run command, if any. Errors are logged, not returned.
+pub fn record(event: &Value) {
+    let sample = match Sample::from_event(event) {
+        Some(sample) => sample,
+        None => return,
+    };
+
+    if let Some(ref mut registry) = *REGISTRY.lock().unwrap() {
+        registry.observe_and_publish(&sample);
+        return;
+    }
+
+    if METRICS_SOCKET_PATH.exists() {
+        if let Err(e) = send_to_supervisor(&sample) {
+            warn!("Failed to send metrics to the run command: {:#}", e);
+        }
+    }
+}
+
+fn send_to_supervisor(sample: &Sample) -> Result<()> {
+    let socket = UnixDatagram::unbound()?;
+    // If the run command is not reading, we'd rather lose the sample than hang.
+    socket.set_nonblocking(true)?;
+    socket.send_to(&serde_json::to_vec(sample)?, &*METRICS_SOCKET_PATH)?;
+    Ok(())
+}
+
+/// Receives the samples of the checkpoint command. It's polled by the
+/// FastFreeze daemon thread.
+pub struct SampleReceiver {
+    socket: UnixDatagram,
+}
+
+impl SampleReceiver {
+    pub fn bind() -> Result<Self> {
+        let _ = fs::remove_file(&*METRICS_SOCKET_PATH);
+        let socket = UnixDatagram::bind(&*METRICS_SOCKET_PATH)
+            .with_context(|| format!("Failed to bind socket to {}", METRICS_SOCKET_PATH.display()))?;
+        Ok(Self { socket })
+    }
+
+    pub fn receive(&self) -> Result<()> {
+        let mut buf = [0u8; 4096];
+        let size = self.socket.recv(&mut buf)?;
+        let sample: Sample = serde_json::from_slice(&buf[..size])
+            .context("Malformed metrics sample")?;
+        if let Some(ref mut registry) = *REGISTRY.lock().unwrap() {
+            registry.observe_and_publish(&sample);
+        }
+        Ok(())
+    }
+}
+
+impl AsRawFd for SampleReceiver {
+    fn as_raw_fd(&self) -> RawFd {
+        self.socket.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let mut registry = Registry::new(None);
+        let events = [
+            json!({"action": "checkpoint", "outcome": "success", "duration": 3.0,
+                   "stats": {"total_size_mb": 100.0, "rate_mb_per_sec": 200.0, "time_frozen_sec": 0.4}}),
+            json!({"action": "checkpoint", "outcome": "error", "duration": 0.2, "error": "oops"}),
+            json!({"action": "restore", "outcome": "success", "duration": 2.0,
+                   "stats": {"total_size_mb": 100.0, "rate_mb_per_sec": 300.0},
+                   "duration_since_checkpoint_sec": 30.0}),
+            json!({"action": "fetch_manifest", "outcome": "success", "duration": 0.1}),
+        ];
+        for event in &events {
+            if let Some(sample) = Sample::from_event(event) {
+                registry.observe(&sample);
+            }
+        }
+
+        let text = registry.render();
+        let has = |line: &str| text.lines().any(|l| l == line);
+        assert!(has("fastfreeze_operations_total{action=\"checkpoint\",outcome=\"success\"} 1"));
+        assert!(has("fastfreeze_operations_total{action=\"checkpoint\",outcome=\"error\"} 1"));
+        assert!(has("fastfreeze_operations_total{action=\"restore\",outcome=\"success\"} 1"));
+        assert!(!text.contains("fetch_manifest"));
+        assert!(has("fastfreeze_duration_seconds_bucket{action=\"checkpoint\",le=\"0.25\"} 1"));
+        assert!(has("fastfreeze_duration_seconds_bucket{action=\"checkpoint\",le=\"+Inf\"} 2"));
+        assert!(has("fastfreeze_duration_seconds_count{action=\"checkpoint\"} 2"));
+        assert!(has("fastfreeze_image_size_bytes_sum{action=\"restore\"} 104857600"));
+        assert!(has("fastfreeze_checkpoint_time_frozen_seconds_bucket{le=\"0.5\"} 1"));
+        assert!(has("fastfreeze_restore_duration_since_checkpoint_seconds_count 1"));
+    }
+}
@@ -12,25 +12,33 @@
 //  See the License for the specific language governing permissions and
 //  limitations under the License.
 
-use anyhow::Result;
+use anyhow::{Result, Context};
 use std::{
     error::Error,
     fmt::Display,
     fs::File,
-    io::{BufReader, ErrorKind},
+    io::{self, BufReader, ErrorKind},
+    os::unix::io::{AsRawFd, RawFd},
     path::Path,
     result::Result as StdResult,
     sync::atomic::{AtomicBool, Ordering}
 };
 use std::io::prelude::*;
 use nix::{
+    fcntl::OFlag,
+    poll::{PollFd, PollFlags},
     sys::signal::{self, kill, Signal},
     errno::Errno,
     unistd::Pid
 };
+use crate::util::{poll_nointr, Pipe};
 
 lazy_static! {
     static ref SIGTERM_RECEIVED: AtomicBool = AtomicBool::new(false);
+    /// Written to on SIGTERM, so that poll() wakes up regardless of the thread
+    /// receiving the signal. Blocking syscalls are otherwise restarted (SA_RESTART).
+    static ref SIGTERM_PIPE: Pipe = Pipe::new(OFlag::O_CLOEXEC | OFlag::O_NONBLOCK)
+        .expect("Failed to create the SIGTERM pipe");
 }
 
 pub fn trap_sigterm_and_friends() -> Result<()> {
@@ -41,10 +49,19 @@ pub fn trap_sigterm_and_friends() -> Result<()> {
             signal_hook::low_level::register(*signal as i32, ||
                 SIGTERM_RECEIVED.store(true, Ordering::SeqCst))?;
         }
+        // Registered after the flag, so that the flag is set when poll() wakes up.
+        signal_hook::low_level::pipe::register(*signal as i32, SIGTERM_PIPE.write.try_clone()?)
+            .context("Failed to register signal")?;
     }
     Ok(())
 }
 
+/// Returns the fd to poll() along with other fds to wake up when a SIGTERM is
+/// received. `check_for_pending_sigterm()` must then be called.
+pub fn sigterm_fd() -> RawFd {
+    SIGTERM_PIPE.read.as_raw_fd()
+}
+
 #[derive(Debug)]
 pub struct TerminationRequestedError;
 impl Error for TerminationRequestedError {}
@@ -58,13 +75,71 @@ impl Display for TerminationRequestedError {
 /// consumed, meaning that a subsequent call to `check_for_pending_sigterm()`
 /// will succeed unless another SIGTERM is received.
 pub fn check_for_pending_sigterm() -> Result<()> {
-    if SIGTERM_RECEIVED.swap(false, Ordering::SeqCst) {
+    let received = SIGTERM_RECEIVED.swap(false, Ordering::SeqCst);
+    // We drain the pipe after consuming the flag. A signal arriving in between
+    // leaves the pipe readable, which causes a spurious wake up at worst.
+    let mut buf = Vec::new();
+    match (&SIGTERM_PIPE.read).read_to_end(&mut buf) {
+        Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+        result => { result.context("Failed to drain the SIGTERM pipe")?; }
+    }
+    if received {
         info!("Termination requested");
         bail!(TerminationRequestedError);
     }
     Ok(())
 }
 
+/// Returns true when the error comes from a termination request, including
+/// when it was turned into an I/O error by `TerminableReader`.
+pub fn is_termination_requested(e: &anyhow::Error) -> bool {
+    e.chain().any(|cause| cause.is::<TerminationRequestedError>() ||
+        cause.downcast_ref::<io::Error>()
+            .and_then(|e| e.get_ref())
+            .is_some_and(|e| e.is::<TerminationRequestedError>()))
+}
+
+/// Blocks until `fd` is readable. Returns an error when a SIGTERM is received.
+pub fn wait_until_readable(fd: RawFd) -> Result<()> {
+    loop {
+        check_for_pending_sigterm()?;
+        let mut poll_fds = [
+            PollFd::new(fd, PollFlags::POLLIN),
+            PollFd::new(sigterm_fd(), PollFlags::POLLIN),
+        ];
+        poll_nointr(&mut poll_fds, -1).context("Failed to poll()")?;
+        // unwrap() is safe: we assume the kernel returns valid bits in `revents`.
+        if !poll_fds[0].revents().expect("revents invalid").is_empty() {
+            return Ok(());
+        }
+    }
+}
+
+/// Reads from a pipe, but fails when a SIGTERM is received while waiting for data.
+/// Without it, reading from a stalled download would wait until it completes.
+pub struct TerminableReader<R> {
+    inner: R,
+    fd: RawFd,
+}
+
+impl<R: Read> TerminableReader<R> {
+    /// `fd` is the one `inner` reads from.
+    pub fn new(inner: R, fd: RawFd) -> Self {
+        Self { inner, fd }
+    }
+}
+
+impl<R: Read> Read for TerminableReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // The TerminationRequestedError is kept so that `is_termination_requested()` finds it.
+        wait_until_readable(self.fd).map_err(|e| match e.downcast::<TerminationRequestedError>() {
+            Ok(e) => io::Error::other(e),
+            Err(e) => io::Error::other(e),
+        })?;
+        self.inner.read(buf)
+    }
+}
+
 pub trait IsErrorInterrupt {
     fn is_interrupt(&self) -> bool;
 }
@@ -129,7 +204,7 @@ fn get_children(pid: Pid) -> Result<Vec<Pid>> {
         .collect())
 }
 
-fn get_process_tree(root_pid: Pid) -> Result<Vec<Pid>> {
+pub fn get_process_tree(root_pid: Pid) -> Result<Vec<Pid>> {
     fn get_process_tree_inner(pid: Pid, pids: &mut Vec<Pid>) -> Result<()> {
         pids.push(pid);
         for child in get_children(pid)? {
@@ -173,3 +248,28 @@ pub fn get_proc_state(pid: Pid) -> Result<char> {
 
     bail!("Failed to parse proc status file");
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_is_termination_requested() {
+        let e = anyhow!(TerminationRequestedError).context("Failed to poll()");
+        assert!(is_termination_requested(&e));
+
+        // As reported by TerminableReader, wrapped in the errors of the tar crate.
+        struct Reader;
+        impl Read for Reader {
+            fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+                Err(io::Error::other(TerminationRequestedError))
+            }
+        }
+        let e = tar::Archive::new(Reader).unpack("/tmp/ff-test-terminated").unwrap_err();
+        let e = anyhow::Error::from(e).context("Failed to extract the file system");
+        assert!(is_termination_requested(&e));
+
+        let e = anyhow::Error::from(io::Error::other("broken pipe"));
+        assert!(!is_termination_requested(&e));
+    }
+}
@@ -0,0 +1,358 @@
+//  Copyright 2020 Two Sigma Investments, LP.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+use anyhow::{Result, Context};
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    path::{Path, PathBuf},
+    time::SystemTime,
+    fs,
+};
+use crate::{
+    consts::*,
+    image::ImageManifest,
+    util::{create_dir_all, parse_env_var_or_warn},
+};
+
+// Local disk cache adapter. It wraps another store, and keeps a copy of the
+// shards on local disk. This is useful when restoring an application on the
+// same node it was checkpointed on (e.g., app crash and retry), where
+// re-downloading the image from S3 would be wasteful.
+//
+// * Uploads are write-through: the shard is tee'd into the cache while it is
+//   being uploaded to the inner store.
+// * Downloads are served from the cache when the shard is present. Otherwise,
+//   the shard is downloaded from the inner store and tee'd into the cache.
+// * The manifest is never cached. It is the source of truth of which image
+//   generation is current. Shard names contain the generation (the shard
+//   prefix), so a cached shard is never stale, but it can be useless.
+//   Cached shards of other generations are dropped in `on_generation_selected()`.
+// * The cache size is capped. Files are evicted in LRU order when the store
+//   is prepared, and after each file written in the cache, as a checkpoint or
+//   a restore can write more than the cap. The modification time of a file is
+//   used as its access time.
+
+lazy_static! {
+    static ref CACHE_DIR: Option<PathBuf> = std::env::var_os("FF_IMAGE_CACHE_DIR")
+        .filter(|d| !d.is_empty())
+        .map(PathBuf::from);
+
+    // The cache is optional, so a bad setting is not worth failing for.
+    static ref CACHE_MAX_SIZE: u64 = parse_env_var_or_warn("FF_IMAGE_CACHE_MAX_SIZE_MB")
+        .unwrap_or(DEFAULT_IMAGE_CACHE_MAX_SIZE_MB) * MB as u64;
+}
+
+/// Suffix of files being written in the cache. Once complete, they are renamed.
+const PARTIAL_SUFFIX: &str = ".partial";
+
+pub struct Store {
+    inner: Box<dyn super::Store>,
+    /// Directory holding the caches of all images
+    cache_root: PathBuf,
+    /// Directory holding the cached files of this image
+    cache_dir: PathBuf,
+    max_size: u64,
+}
+
+impl Store {
+    /// Wraps `inner` with a local cache when FF_IMAGE_CACHE_DIR is set.
+    pub fn maybe_wrap(inner: Box<dyn super::Store>, url: &url::Url, image_name: &str) -> Box<dyn super::Store> {
+        match CACHE_DIR.as_ref() {
+            Some(cache_root) => Box::new(Self::new(inner, cache_root, url, image_name, *CACHE_MAX_SIZE)),
+            None => inner,
+        }
+    }
+
+    pub fn new(inner: Box<dyn super::Store>, cache_root: &Path, url: &url::Url,
+               image_name: &str, max_size: u64) -> Self {
+        // Two images may have the same name, but different URLs. We use a hash
+        // of the URL to distinguish them, similarly to default_image_name().
+        let hash = {
+            let mut hasher = DefaultHasher::new();
+            url.as_str().hash(&mut hasher);
+            hasher.finish()
+        };
+        let cache_dir = cache_root.join(format!("{}-{:016x}", image_name, hash));
+
+        Self { inner, cache_root: cache_root.to_path_buf(), cache_dir, max_size }
+    }
+
+    fn evict(&self) -> Result<()> {
+        let mut files = list_cached_files(&self.cache_root)?;
+        let mut total_size: u64 = files.iter().map(|f| f.size).sum();
+        if total_size <= self.max_size {
+            return Ok(());
+        }
+
+        // Least recently used first
+        files.sort_by_key(|f| f.mtime);
+        for file in files {
+            if total_size <= self.max_size {
+                break;
+            }
+            debug!("Evicting {} from the image cache", file.path.display());
+            fs::remove_file(&file.path)
+                .with_context(|| format!("Failed to remove {}", file.path.display()))?;
+            total_size -= file.size;
+        }
+
+        Ok(())
+    }
+}
+
+impl super::Store for Store {
+    fn prepare(&self, write: bool) -> Result<()> {
+        self.inner.prepare(write)?;
+        create_dir_all(&self.cache_dir)?;
+        self.evict()
+    }
+
+    fn file(&self, filename: &str) -> Box<dyn super::File> {
+        let inner = self.inner.file(filename);
+        // The manifest and config files are mutable, they are never cached.
+        if filename == MANIFEST_FILE_NAME || CONFIG_FILE_NAMES.contains(&filename) {
+            inner
+        } else {
+            Box::new(File {
+                inner,
+                path: self.cache_dir.join(filename),
+                cache_root: self.cache_root.clone(),
+                max_size: self.max_size,
+            })
+        }
+    }
+
+    fn on_generation_selected(&self, img_manifest: &ImageManifest) -> Result<()> {
+        self.inner.on_generation_selected(img_manifest)?;
+
+        let shard_prefix = format!("{}-", img_manifest.shard_prefix);
+        let mut num_cached_shards = 0;
+        for file in list_cached_files(&self.cache_dir)? {
+            let filename = file.path.file_name().map(|f| f.to_string_lossy().into_owned());
+            // File system layers of previous generations are still in use.
+            let is_layer = filename.as_ref().is_some_and(|f|
+                img_manifest.fs_layers.iter().any(|l| f.starts_with(l.as_str())));
+            let is_current = filename.as_ref().is_some_and(|f| f.starts_with(&shard_prefix));
+            if is_layer {
+                continue;
+            } else if !is_current {
+                trace!("Dropping {} from the image cache", file.path.display());
+                fs::remove_file(&file.path)
+                    .with_context(|| format!("Failed to remove {}", file.path.display()))?;
+            } else if !file.path.to_string_lossy().ends_with(PARTIAL_SUFFIX) {
+                num_cached_shards += 1;
+            }
+        }
+
+        if num_cached_shards == img_manifest.num_shards {
+            info!("Image generation {} found in the local cache at {}",
+                  img_manifest.shard_prefix, self.cache_dir.display());
+        }
+
+        Ok(())
+    }
+}
+
+pub struct File {
+    inner: Box<dyn super::File>,
+    path: PathBuf,
+    cache_root: PathBuf,
+    max_size: u64,
+}
+
+impl File {
+    /// Returns the mktemp template of the partial files. Each writer gets its
+    /// own, as other FastFreeze instances may share the cache, and transfer the
+    /// same file concurrently.
+    fn partial_template(&self) -> String {
+        // We can unwrap() because the path is valid UTF8, as path comes from a String
+        format!("{}.XXXXXX{}", self.path.to_str().unwrap(), PARTIAL_SUFFIX)
+    }
+
+    /// Returns the shell command writing its stdin to the cache, and to the
+    /// stdout of `transfer_cmd` through `tee`. The file is only promoted in
+    /// the cache when the transfer succeeds. Failing to cache the file does not
+    /// fail the transfer.
+    fn tee_shell_cmd(&self, transfer_cmd: &str) -> String {
+        format!("if ff_partial=$(mktemp \"{template}\" 2>/dev/null); then \
+                     {{ tee \"$ff_partial\" | {transfer}; }} && \
+                         {{ mv \"$ff_partial\" \"{path}\" || rm -f \"$ff_partial\"; {evict}; }} || \
+                         {{ rm -f \"$ff_partial\"; false; }}; \
+                 else {transfer}; fi",
+            template = self.partial_template(),
+            transfer = transfer_cmd,
+            path = self.path.display(),
+            evict = self.evict_shell_cmd())
+    }
+
+    /// Returns the shell command evicting files in LRU order, like `Store::evict()`.
+    /// It runs once a file is written in the cache. Files being written are skipped.
+    /// It does not fail, as the file is already transferred.
+    fn evict_shell_cmd(&self) -> String {
+        evict_shell_cmd(&self.cache_root, self.max_size)
+    }
+}
+
+fn evict_shell_cmd(cache_root: &Path, max_size: u64) -> String {
+    // Most recently used first. Files are deleted once the total goes over the cap.
+    // Records are NUL separated, and the path is the rest of the record, so that
+    // any file name is handled.
+    format!("{{ find \"{root}\" -type f ! -name '*{partial}' -printf '%T@ %s %p\\0' | sort -zrn | \
+             {{ total=0; while IFS=' ' read -r -d '' mtime size f; do \
+                 total=$((total + size)); [ $total -le {max} ] || rm -f -- \"$f\"; done; }}; true; }}",
+        root = cache_root.display(),
+        partial = PARTIAL_SUFFIX,
+        max = max_size)
+}
+
+impl super::File for File {
+    fn upload_shell_cmd(&self) -> String {
+        // The commands are grouped as the caller may pipe them with others.
+        format!("{{ {}; }}", self.tee_shell_cmd(&self.inner.upload_shell_cmd()))
+    }
+
+    fn download_shell_cmd(&self) -> String {
+        // The cache lookup is done when the command runs, not when it is
+        // generated. Touching the file records the access for LRU eviction.
+        format!("if [ -e \"{path}\" ]; then touch \"{path}\" && pv -q \"{path}\"; \
+                 else {download} | {{ {tee}; }}; fi",
+            path = self.path.display(),
+            download = self.inner.download_shell_cmd(),
+            tee = self.tee_shell_cmd("cat"))
+    }
+
+    fn delete_shell_cmd(&self) -> String {
+        format!("{{ rm -f \"{path}\" \"{path}\".*{partial} && {delete}; }}",
+            path = self.path.display(),
+            partial = PARTIAL_SUFFIX,
+            delete = self.inner.delete_shell_cmd())
+    }
+
+    fn has_not_found_error(&self, stderr: &str) -> bool {
+        self.inner.has_not_found_error(stderr)
+    }
+
+    fn retry_policy(&self) -> super::RetryPolicy {
+        self.inner.retry_policy()
+    }
+}
+
+struct CachedFile {
+    path: PathBuf,
+    size: u64,
+    mtime: SystemTime,
+}
+
+/// Returns all the files located under `dir`, recursively.
+fn list_cached_files(dir: &Path) -> Result<Vec<CachedFile>> {
+    fn visit(dir: &Path, files: &mut Vec<CachedFile>) -> Result<()> {
+        let entries = match fs::read_dir(dir) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            result => result.with_context(|| format!("Failed to readdir {}", dir.display()))?,
+        };
+
+        for entry in entries {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            if metadata.is_dir() {
+                visit(&entry.path(), files)?;
+            } else {
+                files.push(CachedFile {
+                    path: entry.path(),
+                    size: metadata.len(),
+                    mtime: metadata.modified()?,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    let mut files = vec![];
+    visit(dir, &mut files)?;
+    Ok(files)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_lru_eviction() -> Result<()> {
+        let cache_root = Path::new("/tmp/ff-test-cache");
+        let _ = fs::remove_dir_all(cache_root);
+
+        let url = url::Url::parse("s3://bucket/img")?;
+        let inner = super::super::ImageUrl::parse("file:/tmp/ff-test-cache-inner")?.store();
+        let store = Store::new(inner, cache_root, &url, "img", 2*KB as u64);
+        create_dir_all(&store.cache_dir)?;
+
+        for name in &["a-1.ffs", "a-2.ffs", "a-3.ffs"] {
+            fs::write(store.cache_dir.join(name), vec![0; KB])?;
+            // mtime granularity can be coarse
+            std::thread::sleep(Duration::from_millis(20));
+        }
+
+        store.evict()?;
+        assert!(!store.cache_dir.join("a-1.ffs").exists());
+        assert!(store.cache_dir.join("a-2.ffs").exists());
+        assert!(store.cache_dir.join("a-3.ffs").exists());
+
+        // The eviction done after writing a file in the cache behaves the same.
+        fs::write(store.cache_dir.join("a 4.ffs"), vec![0; KB])?;
+        fs::write(store.cache_dir.join(format!("a-5.ffs{}", PARTIAL_SUFFIX)), vec![0; KB])?;
+        crate::process::Command::new_shell(evict_shell_cmd(cache_root, 2*KB as u64))
+            .spawn()?.wait_for_success()?;
+        assert!(!store.cache_dir.join("a-2.ffs").exists());
+        assert!(store.cache_dir.join("a-3.ffs").exists());
+        assert!(store.cache_dir.join("a 4.ffs").exists());
+        assert!(store.cache_dir.join(format!("a-5.ffs{}", PARTIAL_SUFFIX)).exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_cached_download() -> Result<()> {
+        use crate::process::{Command, Stdio};
+        use super::super::Store as _;
+
+        let cache_root = Path::new("/tmp/ff-test-cache-download");
+        let inner_dir = Path::new("/tmp/ff-test-cache-download-inner");
+        let _ = fs::remove_dir_all(cache_root);
+        let _ = fs::remove_dir_all(inner_dir);
+        fs::create_dir_all(inner_dir)?;
+        fs::write(inner_dir.join("a-1.ffs"), "shard")?;
+
+        let url = url::Url::parse("s3://bucket/img")?;
+        let inner = super::super::ImageUrl::parse(&format!("file:{}", inner_dir.display()))?.store();
+        let store = Store::new(inner, cache_root, &url, "img", MB as u64);
+        store.prepare(false)?;
+
+        // Concurrent downloads each write their own partial file.
+        let file = store.file("a-1.ffs");
+        let downloads = (0..4).map(|_| Command::new_shell(file.download_shell_cmd())
+            .stdout(Stdio::piped()).spawn())
+            .collect::<Result<Vec<_>>>()?;
+        for download in downloads {
+            let output = download.wait_with_output()?;
+            output.ensure_success()?;
+            assert_eq!(output.stdout, b"shard");
+        }
+        assert_eq!(fs::read(store.cache_dir.join("a-1.ffs"))?, b"shard");
+        assert_eq!(fs::read_dir(&store.cache_dir)?.count(), 1);
+
+        Ok(())
+    }
+}
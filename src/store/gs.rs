@@ -14,13 +14,23 @@
 
 use anyhow::Result;
 use url::Url;
-use crate::util::UrlExt;
+use crate::{
+    consts::*,
+    util::UrlExt,
+};
+use super::RetryPolicy;
 
 // Google Cloud Storage adapter
 
 lazy_static! {
     static ref GS_CMD: String = std::env::var("GS_CMD")
         .unwrap_or_else(|_| "gcsthin".to_string());
+
+    static ref RETRY_POLICY: RetryPolicy = RetryPolicy::from_env(
+        "FF_GS", DEFAULT_REMOTE_STORE_RETRY_ATTEMPTS, &[
+            "Connection reset", "timed out", "backendError", "rateLimitExceeded",
+            "429 Too Many Requests", "500 Internal Server Error", "503 Service Unavailable",
+        ]);
 }
 
 pub struct Store {
@@ -54,13 +64,25 @@ impl super::File for File {
         format!("{} cp - \"{}\"", *GS_CMD, self.url)
     }
 
-
     fn download_shell_cmd(&self) -> String {
-        format!("{} cp \"{}\" -", *GS_CMD, self.url)
+        // gcsthin does not support range downloads. A download is only retried
+        // if it failed before emitting data.
+        let download_cmd = format!("{} cp \"{}\" -", *GS_CMD, self.url);
+        RETRY_POLICY.download_shell_cmd(self.url.as_str(), download_cmd, None)
+    }
+
+    fn delete_shell_cmd(&self) -> String {
+        // A missing object is reported as an error, which we ignore.
+        format!("{{ out=$({} rm \"{}\" 2>&1) || grep -q -e \"Not Found\" -e \"No such object\" <<< \"$out\" \
+                 || {{ echo \"$out\" >&2; false; }}; }}", *GS_CMD, self.url)
     }
 
     fn has_not_found_error(&self, stderr: &str) -> bool {
         stderr.contains("Not Found") ||
         stderr.contains("No such object")
     }
+
+    fn retry_policy(&self) -> RetryPolicy {
+        RETRY_POLICY.clone()
+    }
 }
@@ -15,6 +15,14 @@
 use anyhow::Result;
 use std::path::PathBuf;
 use crate::util::create_dir_all;
+use super::RetryPolicy;
+
+lazy_static! {
+    // Local files are not retried by default. Network filesystems (e.g., NFS)
+    // may benefit from setting FF_FILE_RETRY_ATTEMPTS.
+    static ref RETRY_POLICY: RetryPolicy = RetryPolicy::from_env(
+        "FF_FILE", 1, &["Stale file handle", "Input/output error"]);
+}
 
 pub struct Store {
     path: PathBuf,
@@ -57,10 +65,21 @@ impl super::File for File {
     }
 
     fn download_shell_cmd(&self) -> String {
-        format!("pv -q \"{}\"", self.path.to_str().unwrap())
+        let path = self.path.to_str().unwrap();
+        let download_cmd = format!("pv -q \"{}\"", path);
+        let range_cmd = format!("tail -c +$((off+1)) \"{}\"", path);
+        RETRY_POLICY.download_shell_cmd(path, download_cmd, Some(range_cmd))
+    }
+
+    fn delete_shell_cmd(&self) -> String {
+        format!("rm -f \"{}\"", self.path.to_str().unwrap())
     }
 
     fn has_not_found_error(&self, stderr: &str) -> bool {
         stderr.contains("No such file or directory")
     }
+
+    fn retry_policy(&self) -> RetryPolicy {
+        RETRY_POLICY.clone()
+    }
 }
@@ -15,6 +15,11 @@
 mod local;
 mod s3;
 mod gs;
+mod cache;
+mod replicated;
+mod retry;
+
+pub use retry::{RetryPolicy, take_retry_metrics};
 
 use anyhow::{Result, Context};
 use std::{
@@ -23,7 +28,10 @@ use std::{
     io::Write,
 };
 use url::{Url, ParseError};
-use crate::process::{Stdio, Command};
+use crate::{
+    process::{Stdio, Command, ProcessError},
+    image::ImageManifest,
+};
 
 // `Store` and `File` describe the API needed to store and retrieve images
 
@@ -37,6 +45,14 @@ pub trait Store {
     /// Returns a File object that represents a file of name `filename`.
     /// Example of file name are "manifest.json" and "XXXX-4.ffs".
     fn file(&self, filename: &str) -> Box<dyn File>;
+
+    /// on_generation_selected() is called once we know which image generation
+    /// is of interest. That's after fetching the manifest during restore, and
+    /// after writing the manifest during checkpoint. Stores keeping local state
+    /// (e.g., a cache) can discard what belongs to other generations.
+    fn on_generation_selected(&self, _img_manifest: &ImageManifest) -> Result<()> {
+        Ok(())
+    }
 }
 
 pub trait File {
@@ -46,16 +62,44 @@ pub trait File {
     /// Returns a shell command to download file
     fn download_shell_cmd(&self) -> String;
 
+    /// Returns a shell command to delete file. Deleting a file that does not
+    /// exist is not an error.
+    fn delete_shell_cmd(&self) -> String;
+
     // Returns whether stderr contains a "not found error" when the download
     // shell command failed.
     fn has_not_found_error(&self, stderr: &str) -> bool;
+
+    /// Returns how failed operations on the file should be retried.
+    fn retry_policy(&self) -> RetryPolicy {
+        RetryPolicy::none()
+    }
 }
 
 // write()/try_read() are helpers that use the `File` download/upload shell
 // commands to download and upload content.
 pub trait FileExt: File {
     /// Write content to the file, truncating it if necessary.
+    /// The write is retried whole on transient errors.
     fn write(&self, log_prefix: &'static str, data: &[u8]) -> Result<()> {
+        let policy = self.retry_policy();
+        let mut retry = 0;
+        loop {
+            match self.write_once(log_prefix, data) {
+                Err(e) if retry+1 < policy.attempts && is_retryable_error(&e, &policy) => {
+                    retry += 1;
+                    let delay = policy.backoff(retry);
+                    warn!("{}> Retrying in {:.1}s ({}/{})",
+                          log_prefix, delay.as_secs_f64(), retry, policy.attempts-1);
+                    retry::record_retry(log_prefix);
+                    std::thread::sleep(delay);
+                }
+                result => return result,
+            }
+        }
+    }
+
+    fn write_once(&self, log_prefix: &'static str, data: &[u8]) -> Result<()> {
         let mut p = Command::new_shell(&self.upload_shell_cmd())
             .stdin(Stdio::piped())
             .enable_stderr_logging(log_prefix)
@@ -78,6 +122,13 @@ pub trait FileExt: File {
             .with_context(|| format!("{}> write to stdin failed", log_prefix))
     }
 
+    fn delete(&self, log_prefix: &'static str) -> Result<()> {
+        Command::new_shell(self.delete_shell_cmd())
+            .enable_stderr_logging(log_prefix)
+            .spawn()?
+            .wait_for_success()
+    }
+
     /// Reads a file. Returns None if it doesn't exist.
     fn try_read<S>(&self, log_prefix: S) -> Result<Option<Vec<u8>>>
         where S: Into<Cow<'static, str>>
@@ -104,16 +155,52 @@ pub trait FileExt: File {
 }
 impl FileExt for dyn File {}
 
-pub struct ImageUrl(Url);
+fn is_retryable_error(e: &anyhow::Error, policy: &RetryPolicy) -> bool {
+    e.downcast_ref::<ProcessError>()
+        .and_then(|e| e.stderr_tail.as_ref())
+        .is_some_and(|st| st.tail.iter().any(|line| policy.is_retryable(line)))
+}
+
+/// An image URL can list multiple URLs, comma separated. The image is then
+/// replicated to all of them (see replicated.rs).
+pub struct ImageUrl(Vec<Url>);
+
+/// Splits a list of URLs on the commas that are followed by another URL or an
+/// absolute path. Other commas are part of a path (e.g., `s3://bucket/a,b`).
+fn split_urls(url_str: &str) -> Vec<&str> {
+    let is_url_start = |s: &str| s.starts_with('/') ||
+        ["file:", "s3:", "gs:"].iter().any(|scheme| s.starts_with(scheme));
+
+    let mut urls = vec![];
+    let mut start = 0;
+    for (i, _) in url_str.match_indices(',') {
+        if is_url_start(&url_str[i+1..]) {
+            urls.push(&url_str[start..i]);
+            start = i+1;
+        }
+    }
+    urls.push(&url_str[start..]);
+    urls
+}
 
 impl ImageUrl {
     /// If url does not start with "scheme:", it is assumed to be a file path.
     pub fn parse(url_str: &str) -> Result<Self> {
+        let urls = split_urls(url_str).into_iter()
+            .map(Self::parse_one)
+            .collect::<Result<Vec<_>>>()?;
+        if urls.len() > 1 {
+            replicated::quorum(urls.len())?;
+        }
+        Ok(Self(urls))
+    }
+
+    fn parse_one(url_str: &str) -> Result<Url> {
         match Url::parse(url_str) {
             Err(ParseError::RelativeUrlWithoutBase) => {
                 ensure!(url_str.starts_with('/'),
                         "Please use an absolute path for the image path");
-                Self::parse(&format!("file:{}", url_str))
+                Self::parse_one(&format!("file:{}", url_str))
             },
             Err(e) => bail!(e),
             Ok(url) => {
@@ -123,7 +210,7 @@ impl ImageUrl {
                     ensure!(path.chars().last() != Some('/'), "Image URL path should not end with a trailing /");
                 }
 
-                Ok(Self(match url.scheme() {
+                Ok(match url.scheme() {
                     "file" => {
                         // The url parser prefix the relative paths with /, and we
                         // have no way to know once parsed. Which is why we do error
@@ -134,21 +221,63 @@ impl ImageUrl {
                     },
                     "s3" | "gs"  => url,
                     _ => bail!("Unknown image scheme {}", url),
-                }))
+                })
             }
         }
     }
 
     pub fn image_name(&self) -> &str {
         // The unwraps are okay, we already validated that we have some in parse_image_url().
-        self.0.path_segments().unwrap().last().unwrap()
+        self.0[0].path_segments().unwrap().next_back().unwrap()
     }
 
     pub fn store(&self) -> Box<dyn Store> {
-        match self.0.scheme() {
-            "file" => Box::new(local::Store::new(self.0.path())),
-            "s3"   => Box::new(s3::Store::new(self.0.clone())),
-            "gs"   => Box::new(gs::Store::new(self.0.clone())),
+        self.make_store(true)
+    }
+
+    /// Same as `store()`, but never served from the local image cache. This is
+    /// used to read back what was written to the actual store.
+    pub fn uncached_store(&self) -> Box<dyn Store> {
+        self.make_store(false)
+    }
+
+    fn make_store(&self, cached: bool) -> Box<dyn Store> {
+        if self.0.len() == 1 {
+            return match cached {
+                true => Self::store_for(&self.0[0]),
+                false => Self::store_for_uncached(&self.0[0]),
+            };
+        }
+
+        let replicas = self.0.iter()
+            .map(|url| (Self::store_for_uncached(url), url.to_string()))
+            .collect();
+        // expect() is okay, the quorum is already validated in parse().
+        let store = Box::new(replicated::Store::new(replicas).expect("invalid quorum"));
+
+        // The cache sits in front of the replicas, so that shards are cached
+        // once, and not once per replica.
+        if !cached || self.0.iter().all(|url| url.scheme() == "file") {
+            store
+        } else {
+            cache::Store::maybe_wrap(store, &self.0[0], self.image_name())
+        }
+    }
+
+    fn store_for(url: &Url) -> Box<dyn Store> {
+        let image_name = url.path_segments().unwrap().next_back().unwrap();
+        match url.scheme() {
+            // Caching local files would not be useful
+            "file" => Self::store_for_uncached(url),
+            _ => cache::Store::maybe_wrap(Self::store_for_uncached(url), url, image_name),
+        }
+    }
+
+    fn store_for_uncached(url: &Url) -> Box<dyn Store> {
+        match url.scheme() {
+            "file" => Box::new(local::Store::new(url.path())),
+            "s3"   => Box::new(s3::Store::new(url.clone())),
+            "gs"   => Box::new(gs::Store::new(url.clone())),
             // panic!() is okay, validation is already done in parse().
             _      => panic!("Unknown image scheme"),
         }
@@ -157,7 +286,8 @@ impl ImageUrl {
 
 impl fmt::Display for ImageUrl {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.0)
+        let urls = self.0.iter().map(Url::as_str).collect::<Vec<_>>();
+        write!(f, "{}", urls.join(","))
     }
 }
 
@@ -169,6 +299,12 @@ mod test {
     fn test_from_url() {
         assert!(ImageUrl::parse("file:/tmp/img").is_ok());
         assert!(ImageUrl::parse("file:tmp/img").is_err());
+        assert!(ImageUrl::parse("file:/tmp/img1,s3://bucket/img2").is_ok());
+        assert!(ImageUrl::parse("file:/tmp/img1,file:tmp/img2").is_err());
+
+        assert_eq!(split_urls("s3://bucket/a,b,gs://bucket/c,/tmp/d,e"),
+                   vec!["s3://bucket/a,b", "gs://bucket/c", "/tmp/d,e"]);
+        assert_eq!(ImageUrl::parse("s3://bucket/a,b").unwrap().image_name(), "a,b");
     }
 
     fn test_store_read_write(store: &Box<dyn Store>) -> Result<()> {
@@ -176,12 +312,44 @@ mod test {
         store.file("f1.txt").write("test", "hello".as_bytes())?;
         assert_eq!(store.file("f1.txt").try_read("read test")?, Some("hello".as_bytes().to_vec()));
         assert_eq!(store.file("none.txt").try_read("read test")?, None);
+        store.file("f1.txt").delete("delete test")?;
+        assert_eq!(store.file("f1.txt").try_read("read test")?, None);
+        store.file("none.txt").delete("delete test")?;
         Ok(())
     }
 
     #[test]
     fn test_read_write() -> Result<()> {
         test_store_read_write(&ImageUrl::parse("file:/tmp/ff-test-files")?.store())?;
+        test_store_read_write(&ImageUrl::parse("file:/tmp/ff-test-files-r1,file:/tmp/ff-test-files-r2")?.store())?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_replicas_serve_newest_generation() -> Result<()> {
+        use crate::image::{ImageManifest, ManifestFetchResult};
+        use std::time::{Duration, SystemTime};
+
+        let dirs = ["/tmp/ff-test-gen-r1", "/tmp/ff-test-gen-r2", "/tmp/ff-test-gen-r3"];
+        for (dir, age) in dirs.iter().zip(&[Some(60), Some(0), None]) {
+            let _ = std::fs::remove_dir_all(dir);
+            let store = ImageUrl::parse(&format!("file:{}", dir))?.store();
+            store.prepare(true)?;
+            // The third replica missed all uploads.
+            if let Some(age) = age {
+                let mut img_manifest = ImageManifest::new(1, false, None);
+                img_manifest.shard_prefix = format!("gen{}", age);
+                img_manifest.created_at = Some(SystemTime::now() - Duration::from_secs(*age));
+                img_manifest.persist_to_store(&*store)?;
+            }
+        }
+
+        let url = dirs.iter().map(|d| format!("file:{}", d)).collect::<Vec<_>>().join(",");
+        let store = ImageUrl::parse(&url)?.store();
+        match ImageManifest::fetch_from_store(&*store, false)? {
+            ManifestFetchResult::Some(img_manifest) => assert_eq!(img_manifest.shard_prefix, "gen0"),
+            _ => panic!("manifest not found"),
+        }
         Ok(())
     }
 }
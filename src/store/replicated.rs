@@ -0,0 +1,297 @@
+//  Copyright 2020 Two Sigma Investments, LP.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+use anyhow::Result;
+use std::{
+    cell::Cell,
+    cmp::Reverse,
+    path::PathBuf,
+    rc::Rc,
+    time::{Duration, SystemTime},
+    fs,
+};
+use nix::sys::signal::Signal;
+use crate::{
+    consts::*,
+    process::{Command, Stdio},
+    image::{ImageManifest, ManifestFetchResult},
+    util::parse_env_var,
+};
+
+// Replicated adapter. It fans out an image to multiple stores (e.g., a local
+// NFS path and S3), and is used when the image URL lists multiple URLs.
+//
+// * Uploads are done to all replicas in parallel with `tee`. An upload succeeds
+//   when at least `quorum` replicas succeed. The exit status of each replica is
+//   recorded in a status directory that lives for the duration of the command.
+// * Once a replica fails an upload, subsequent uploads skip it. The manifest is
+//   written last, so a replica that missed a shard never gets the new manifest.
+//   It keeps serving its previous image, which remains consistent.
+// * Downloads are done from a single replica: the one serving the newest image
+//   generation. As uploads only need a quorum, a replica may have missed the
+//   latest checkpoints, and still serve an older generation. Any
+//   `num_replicas - quorum + 1` replicas include one that has the latest
+//   generation. Replicas are probed in parallel on the first download, until
+//   we have that many manifests. On a tie, the fastest replica wins.
+
+/// Returns the number of replicas that must succeed for an upload to succeed.
+/// Defaults to a majority.
+pub fn quorum(num_replicas: usize) -> Result<usize> {
+    let quorum = parse_env_var("FF_IMAGE_REPLICA_QUORUM")?.unwrap_or(num_replicas/2 + 1);
+    ensure!(quorum >= 1 && quorum <= num_replicas,
+            "FF_IMAGE_REPLICA_QUORUM={} must be between 1 and the number of replicas ({})",
+            quorum, num_replicas);
+    Ok(quorum)
+}
+
+pub struct Store {
+    replicas: Vec<Box<dyn super::Store>>,
+    /// Names of the replicas, for error messages
+    names: Vec<String>,
+    quorum: usize,
+    /// Directory holding the upload exit status of each replica
+    status_dir: PathBuf,
+    selector: Rc<ReplicaSelector>,
+}
+
+impl Store {
+    pub fn new(replicas: Vec<(Box<dyn super::Store>, String)>) -> Result<Self> {
+        let quorum = quorum(replicas.len())?;
+        let (replicas, names): (Vec<_>, Vec<_>) = replicas.into_iter().unzip();
+        let selector = Rc::new(ReplicaSelector {
+            manifests: replicas.iter().map(|r| r.file(MANIFEST_FILE_NAME)).collect(),
+            names: names.clone(),
+            quorum,
+            selected: Cell::new(None),
+        });
+        let status_dir = NO_PRESERVE_FF_DIR.join(format!("replicas-{}", *INVOCATION_ID));
+        Ok(Self { replicas, names, quorum, status_dir, selector })
+    }
+}
+
+impl super::Store for Store {
+    fn prepare(&self, write: bool) -> Result<()> {
+        // A replica that is unavailable should not fail the checkpoint
+        // as long as we have a quorum. The upload commands will do the counting.
+        let mut num_ok = 0;
+        for (replica, name) in self.replicas.iter().zip(&self.names) {
+            match replica.prepare(write) {
+                Ok(()) => num_ok += 1,
+                Err(e) => warn!("Replica {} is unavailable: {:#}", name, e),
+            }
+        }
+        let min_ok = if write { self.quorum } else { 1 };
+        ensure!(num_ok >= min_ok, "Only {} replicas out of {} are available, {} are needed",
+                num_ok, self.replicas.len(), min_ok);
+        Ok(())
+    }
+
+    fn file(&self, filename: &str) -> Box<dyn super::File> {
+        Box::new(File {
+            files: self.replicas.iter().map(|r| r.file(filename)).collect(),
+            filename: filename.to_string(),
+            names: self.names.clone(),
+            quorum: self.quorum,
+            status_dir: self.status_dir.clone(),
+            selector: Rc::clone(&self.selector),
+        })
+    }
+
+    fn on_generation_selected(&self, img_manifest: &crate::image::ImageManifest) -> Result<()> {
+        for replica in &self.replicas {
+            replica.on_generation_selected(img_manifest)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Store {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.status_dir);
+    }
+}
+
+/// Picks the replica to download from. All files of a store share the same
+/// selector so that the manifest and the shards come from the same replica.
+struct ReplicaSelector {
+    manifests: Vec<Box<dyn super::File>>,
+    names: Vec<String>,
+    quorum: usize,
+    selected: Cell<Option<usize>>,
+}
+
+impl ReplicaSelector {
+    fn get(&self) -> usize {
+        if let Some(index) = self.selected.get() {
+            return index;
+        }
+
+        let index = self.probe().unwrap_or_else(|e| {
+            debug!("Failed to probe replicas: {:#}", e);
+            None
+        }).unwrap_or_else(|| {
+            // No replica has a manifest. We use the first one. Its download
+            // command reports the not-found error that the caller expects.
+            debug!("No replica has an image manifest");
+            0
+        });
+
+        debug!("Reading image from replica {}", self.names[index]);
+        self.selected.set(Some(index));
+        index
+    }
+
+    /// Downloads the manifest of all replicas in parallel, and returns the index
+    /// of the one with the newest generation, among the first
+    /// `num_replicas - quorum + 1` replicas to serve a manifest.
+    fn probe(&self) -> Result<Option<usize>> {
+        let mut procs = self.manifests.iter().map(|f|
+            Command::new_shell(f.download_shell_cmd())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::null())
+                .spawn()
+                .map(Some)
+        ).collect::<Result<Vec<_>>>()?;
+
+        let num_needed = self.manifests.len() - self.quorum + 1;
+        // Replicas that served a manifest, in order of arrival, with its creation time.
+        let mut served = vec![];
+        loop {
+            let mut num_running = 0;
+            for (i, slot) in procs.iter_mut().enumerate() {
+                if let Some(p) = slot {
+                    match p.try_wait()? {
+                        Some(status) if status.success() => {
+                            // unwrap() is safe, we are in the Some() branch.
+                            let output = slot.take().unwrap().wait_with_output()?;
+                            served.push((i, manifest_created_at(&output.stdout)));
+                        }
+                        Some(_) => *slot = None,
+                        None => num_running += 1,
+                    }
+                }
+            }
+            if served.len() >= num_needed || num_running == 0 {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        // The slower replicas are no longer of interest.
+        for p in procs.iter_mut().flatten() {
+            let _ = p.kill(Signal::SIGKILL);
+            let _ = p.wait();
+        }
+
+        // Newest generation first, then the fastest replica.
+        Ok(served.iter().enumerate()
+            .max_by_key(|(arrival, (_, created_at))| (*created_at, Reverse(*arrival)))
+            .map(|(_, (i, _))| *i))
+    }
+}
+
+/// Returns the creation time of a manifest. Unreadable manifests, and manifests
+/// of older images come last.
+fn manifest_created_at(manifest_json: &[u8]) -> Option<SystemTime> {
+    match ImageManifest::from_json(&String::from_utf8_lossy(manifest_json), true) {
+        Ok(ManifestFetchResult::Some(img_manifest)) => img_manifest.created_at,
+        _ => None,
+    }
+}
+
+pub struct File {
+    files: Vec<Box<dyn super::File>>,
+    filename: String,
+    names: Vec<String>,
+    quorum: usize,
+    status_dir: PathBuf,
+    selector: Rc<ReplicaSelector>,
+}
+
+impl super::File for File {
+    fn upload_shell_cmd(&self) -> String {
+        // Each replica reads from its own fifo, fed by `tee -p`. The -p flag
+        // makes tee continue feeding the other replicas when one of them fails.
+        // A replica with a recorded failure is skipped without reading its fifo.
+        // We can unwrap() because the path is valid UTF8, as path comes from a String
+        let dir = self.status_dir.to_str().unwrap();
+        let fifo = |i| format!("\"{}/{}.{}.fifo\"", dir, self.filename, i);
+        let status = |i| format!("\"{}/{}/{}.status\"", dir, i, self.filename);
+        let indices = (0..self.files.len()).collect::<Vec<_>>();
+        let fifos = indices.iter().map(|&i| fifo(i)).collect::<Vec<_>>().join(" ");
+
+        let mut cmd = vec![
+            format!("mkdir -p {}", indices.iter().map(|i| format!("\"{}/{}\"", dir, i))
+                .collect::<Vec<_>>().join(" ")),
+            // A previous attempt of this upload must not mark the replica as failed
+            format!("rm -f \"{}\"/*/\"{}.status\"", dir, self.filename),
+            format!("rm -f {fifos} && mkfifo {fifos} || exit 1", fifos=fifos),
+        ];
+        let mut jobs = String::new();
+        for (i, (file, name)) in self.files.iter().zip(&self.names).enumerate() {
+            jobs += &format!(
+                "{{ if grep -qsvx 0 \"{dir}/{i}/\"*; then \
+                      echo \"Skipping replica {name}, it failed a previous upload\" >&2; false; \
+                    else {upload}; fi < {fifo}; echo $? > {status}; }} & ",
+                dir=dir, i=i, name=name, upload=file.upload_shell_cmd(),
+                fifo=fifo(i), status=status(i));
+        }
+        cmd.push(format!("{}tee -p {} > /dev/null", jobs, fifos));
+        cmd.push("wait".to_string());
+        cmd.push("n=0".to_string());
+        for (i, name) in self.names.iter().enumerate() {
+            cmd.push(format!(
+                "if [ \"$(cat {status})\" = 0 ]; then n=$((n+1)); \
+                 else echo \"Upload of {filename} to replica {name} failed\" >&2; fi",
+                status=status(i), filename=self.filename, name=name));
+        }
+        cmd.push(format!("rm -f {}", fifos));
+        cmd.push(format!("[ $n -ge {quorum} ] || {{ echo \"Upload of {filename} succeeded on \
+                          $n replicas, {quorum} are needed\" >&2; false; }}",
+                         quorum=self.quorum, filename=self.filename));
+
+        // The commands are grouped as the caller may pipe them with others.
+        format!("{{ {}; }}", cmd.join("; "))
+    }
+
+    fn download_shell_cmd(&self) -> String {
+        self.files[self.selector.get()].download_shell_cmd()
+    }
+
+    fn delete_shell_cmd(&self) -> String {
+        // Unlike uploads, deletes must succeed on all replicas. Otherwise, the
+        // file could be served later by a replica that still has it.
+        let mut cmd = vec!["n=0".to_string()];
+        for (file, name) in self.files.iter().zip(&self.names) {
+            cmd.push(format!(
+                "if {{ {delete}; }}; then n=$((n+1)); \
+                 else echo \"Delete of {filename} on replica {name} failed\" >&2; fi",
+                delete=file.delete_shell_cmd(), filename=self.filename, name=name));
+        }
+        cmd.push(format!("[ $n -eq {} ]", self.files.len()));
+        format!("{{ {}; }}", cmd.join("; "))
+    }
+
+    fn has_not_found_error(&self, stderr: &str) -> bool {
+        self.files[self.selector.get()].has_not_found_error(stderr)
+    }
+
+    fn retry_policy(&self) -> super::RetryPolicy {
+        // Writes are retried on all replicas at once, so we retry as much as
+        // the most lenient replica.
+        self.files.iter()
+            .map(|f| f.retry_policy())
+            .fold(super::RetryPolicy::none(), |a, b| a.union(&b))
+    }
+}
@@ -0,0 +1,319 @@
+//  Copyright 2020 Two Sigma Investments, LP.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+use std::{
+    collections::BTreeMap,
+    io::Write,
+    path::PathBuf,
+    time::Duration,
+    fs,
+};
+use serde_json::Value;
+use crate::{
+    consts::*,
+    util::parse_env_var_or_warn,
+};
+
+// Retries of transient store failures (e.g., S3 throttling, or a connection reset).
+//
+// * Each backend has its own policy, configurable with environment variables
+//   prefixed by the backend name (e.g., FF_S3_RETRY_ATTEMPTS).
+// * Retryable errors are recognized by looking for known strings in the stderr
+//   of the failed command, similarly to `File::has_not_found_error()`.
+// * Downloads are retried in the shell command itself. When the backend supports
+//   range requests, the download resumes where it failed, as the bytes already
+//   emitted have been consumed by the next stage of the pipeline.
+// * Small writes (the manifest) are retried whole by `FileExt::write()`.
+// * Uploads of files of moderate size (file system layers, logs) are spooled to
+//   a temporary file, and retried whole in the shell command itself.
+// * Shard uploads are not retried, nor resumed. The shard stream comes from
+//   criu-image-streamer and cannot be replayed, and spooling the memory of the
+//   application to disk would defeat the purpose of streaming it. Resuming would
+//   need multipart uploads driven part by part (e.g., `aws s3api upload-part`),
+//   which S3_CMD and GS_CMD, being `cp`-like commands, don't provide. A failed
+//   shard upload fails the checkpoint, which the caller retries.
+// * The wrappers run in a subshell, whose EXIT trap removes their temporary
+//   directory. Spooled files can be large, they must not leak on failures.
+// * Retries are recorded in a file, and reported in the next metrics event.
+
+lazy_static! {
+    static ref RETRY_LOG_PATH: PathBuf =
+        NO_PRESERVE_FF_DIR.join(format!("store-retries-{}", *INVOCATION_ID));
+}
+
+/// Delay between retries never exceeds this value.
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// Number of attempts, including the first one. 1 means no retries.
+    pub attempts: u32,
+    /// Delay before the first retry. It doubles on every retry.
+    pub backoff: Duration,
+    /// An error is retryable when stderr contains one of these strings.
+    pub retryable_errors: Vec<String>,
+}
+
+impl RetryPolicy {
+    pub fn none() -> Self {
+        Self { attempts: 1, backoff: Duration::from_millis(0), retryable_errors: vec![] }
+    }
+
+    /// Reads <prefix>_RETRY_ATTEMPTS, <prefix>_RETRY_BACKOFF_MS, and
+    /// <prefix>_RETRYABLE_ERRORS (comma separated) from the environment.
+    pub fn from_env(prefix: &str, default_attempts: u32, default_errors: &[&str]) -> Self {
+        let name = |name: &str| format!("{}_{}", prefix, name);
+
+        let attempts = parse_env_var_or_warn(&name("RETRY_ATTEMPTS"))
+            .unwrap_or(default_attempts)
+            .max(1);
+        let backoff = parse_env_var_or_warn(&name("RETRY_BACKOFF_MS"))
+            .map(Duration::from_millis)
+            .unwrap_or(DEFAULT_RETRY_BACKOFF);
+        let retryable_errors = std::env::var(name("RETRYABLE_ERRORS")).ok()
+            .map(|s| s.split(',').filter(|e| !e.is_empty()).map(String::from).collect())
+            .unwrap_or_else(|| default_errors.iter().map(|e| e.to_string()).collect());
+
+        Self { attempts, backoff, retryable_errors }
+    }
+
+    /// Returns a policy that retries as much as the most lenient of the two.
+    pub fn union(&self, other: &Self) -> Self {
+        let mut retryable_errors = self.retryable_errors.clone();
+        for e in &other.retryable_errors {
+            if !retryable_errors.contains(e) {
+                retryable_errors.push(e.clone());
+            }
+        }
+
+        Self {
+            attempts: self.attempts.max(other.attempts),
+            backoff: self.backoff.max(other.backoff),
+            retryable_errors,
+        }
+    }
+
+    pub fn is_retryable(&self, stderr: &str) -> bool {
+        self.retryable_errors.iter().any(|e| stderr.contains(e.as_str()))
+    }
+
+    /// Returns the delay to wait before the retry number `retry` (starting at 1).
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let factor = 1u32.checked_shl(retry.saturating_sub(1)).unwrap_or(u32::MAX);
+        self.backoff.checked_mul(factor)
+            .unwrap_or(MAX_RETRY_BACKOFF)
+            .min(MAX_RETRY_BACKOFF)
+    }
+
+    /// Wraps `download_cmd` into a shell loop that retries on retryable errors.
+    /// `range_cmd` downloads the file from the byte offset `$off`. When absent,
+    /// a download can only be retried if it failed before emitting any bytes.
+    pub fn download_shell_cmd(&self, label: &str, download_cmd: String, range_cmd: Option<String>) -> String {
+        if self.attempts <= 1 {
+            return download_cmd;
+        }
+
+        let max_retries = self.attempts - 1;
+        let delays = self.shell_delays();
+        let patterns = self.shell_patterns();
+        let (range_cmd, can_resume) = match range_cmd {
+            Some(cmd) => (cmd, "true"),
+            None => ("false".to_string(), "[ $off -eq 0 ]"),
+        };
+        // We can unwrap() because the path is valid UTF8, as it comes from Strings
+        let retry_log = RETRY_LOG_PATH.to_str().unwrap();
+
+        // The byte count is computed by `wc` in the background, reading from a
+        // fifo fed by `tee`. `wait $!` waits for `wc` to write the count.
+        format!("( \
+            d=$(mktemp -d) || exit 1; trap 'rm -rf \"$d\"' EXIT; trap 'exit 1' INT TERM; \
+            mkfifo \"$d/fifo\" || exit 1; off=0; retry=0; delays=({delays}); \
+            while :; do \
+                wc -c < \"$d/fifo\" > \"$d/cnt\" & \
+                if [ $off -eq 0 ]; then {download_cmd}; else {range_cmd}; fi 2> \"$d/err\" \
+                    | tee \"$d/fifo\"; rc=$?; wait $!; \
+                cat \"$d/err\" >&2; \
+                [ $rc -eq 0 ] && break; \
+                off=$((off + $(cat \"$d/cnt\"))); \
+                [ $retry -lt {max_retries} ] && {can_resume} && grep -qF {patterns} \"$d/err\" || break; \
+                retry=$((retry+1)); \
+                echo \"Retrying download of {label} from offset $off ($retry/{max_retries})\" >&2; \
+                echo {label_quoted} >> \"{retry_log}\" 2>/dev/null; \
+                sleep ${{delays[retry-1]}}; \
+            done; \
+            exit $rc )",
+            delays=delays, download_cmd=download_cmd, range_cmd=range_cmd,
+            max_retries=max_retries, can_resume=can_resume, patterns=patterns,
+            label=label, label_quoted=shell_quote(label), retry_log=retry_log)
+    }
+
+    /// Wraps `upload_cmd` into a shell loop that retries on retryable errors.
+    /// The data is first spooled to a temporary file so that it can be replayed.
+    /// It is meant for files of moderate size, not for shards.
+    pub fn upload_shell_cmd(&self, label: &str, upload_cmd: String) -> String {
+        if self.attempts <= 1 {
+            return upload_cmd;
+        }
+
+        let max_retries = self.attempts - 1;
+        // We can unwrap() because the path is valid UTF8, as it comes from Strings
+        let retry_log = RETRY_LOG_PATH.to_str().unwrap();
+
+        format!("( \
+            d=$(mktemp -d) || exit 1; trap 'rm -rf \"$d\"' EXIT; trap 'exit 1' INT TERM; \
+            cat > \"$d/data\" || exit 1; retry=0; delays=({delays}); \
+            while :; do \
+                {{ {upload_cmd}; }} < \"$d/data\" 2> \"$d/err\"; rc=$?; \
+                cat \"$d/err\" >&2; \
+                [ $rc -eq 0 ] && break; \
+                [ $retry -lt {max_retries} ] && grep -qF {patterns} \"$d/err\" || break; \
+                retry=$((retry+1)); \
+                echo \"Retrying upload of {label} ($retry/{max_retries})\" >&2; \
+                echo {label_quoted} >> \"{retry_log}\" 2>/dev/null; \
+                sleep ${{delays[retry-1]}}; \
+            done; \
+            exit $rc )",
+            delays=self.shell_delays(), upload_cmd=upload_cmd, max_retries=max_retries,
+            patterns=self.shell_patterns(), label=label, label_quoted=shell_quote(label),
+            retry_log=retry_log)
+    }
+
+    /// Returns the delays of each retry, space separated, for a bash array.
+    fn shell_delays(&self) -> String {
+        (1..self.attempts)
+            .map(|retry| format!("{:.3}", self.backoff(retry).as_secs_f64()))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /// Returns the retryable errors as `grep -e` arguments.
+    fn shell_patterns(&self) -> String {
+        self.retryable_errors.iter()
+            .map(|e| format!("-e {}", shell_quote(e)))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+/// Records a retry of `label`, to be reported in metrics.
+pub fn record_retry(label: &str) {
+    let _ = fs::OpenOptions::new().create(true).append(true).open(&*RETRY_LOG_PATH)
+        .and_then(|mut f| writeln!(f, "{}", label));
+}
+
+/// Returns the number of retries per file since the last call, as a metrics
+/// fragment. Returns an empty object if there were none.
+pub fn take_retry_metrics() -> Value {
+    let content = match fs::read_to_string(&*RETRY_LOG_PATH) {
+        Ok(content) => content,
+        Err(_) => return json!({}),
+    };
+    let _ = fs::remove_file(&*RETRY_LOG_PATH);
+
+    let mut retries = BTreeMap::<&str, u32>::new();
+    for label in content.lines() {
+        *retries.entry(label).or_default() += 1;
+    }
+    json!({"store_retries": retries})
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let policy = RetryPolicy { backoff: Duration::from_millis(500), ..RetryPolicy::none() };
+        assert_eq!(policy.backoff(1), Duration::from_millis(500));
+        assert_eq!(policy.backoff(3), Duration::from_millis(2000));
+        assert_eq!(policy.backoff(100), MAX_RETRY_BACKOFF);
+    }
+
+    #[test]
+    fn test_resumable_download() -> anyhow::Result<()> {
+        use crate::process::{Command, Stdio};
+
+        let dir = PathBuf::from("/tmp/ff-test-retry");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir)?;
+        let marker = dir.join("failed-once");
+
+        // The first attempt emits 5 bytes, and fails with a retryable error.
+        let download_cmd = format!(
+            "if [ -e {m} ]; then cat {d}/data; else touch {m}; head -c 5 {d}/data; \
+             echo 'Connection reset' >&2; false; fi",
+            m=marker.display(), d=dir.display());
+        let range_cmd = format!("tail -c +$((off+1)) {}/data", dir.display());
+        fs::write(dir.join("data"), "hello world")?;
+
+        let policy = RetryPolicy {
+            attempts: 3,
+            backoff: Duration::from_millis(1),
+            retryable_errors: vec!["Connection reset".to_string()],
+        };
+        let output = Command::new_shell(policy.download_shell_cmd("data", download_cmd, Some(range_cmd)))
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()?
+            .wait_with_output()?;
+        output.ensure_success()?;
+        assert_eq!(output.stdout, b"hello world");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_upload_retry() -> anyhow::Result<()> {
+        use crate::process::{Command, Stdio};
+
+        let dir = PathBuf::from("/tmp/ff-test-upload-retry");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir)?;
+        let marker = dir.join("failed-once");
+
+        // The first attempt consumes part of its input, and fails with a retryable error.
+        let upload_cmd = format!(
+            "if [ -e {m} ]; then cat > {d}/data; else touch {m}; head -c 5 > /dev/null; \
+             echo 'Connection reset' >&2; false; fi",
+            m=marker.display(), d=dir.display());
+
+        let policy = RetryPolicy {
+            attempts: 3,
+            backoff: Duration::from_millis(1),
+            retryable_errors: vec!["Connection reset".to_string()],
+        };
+        let mut p = Command::new_shell(policy.upload_shell_cmd("data", upload_cmd))
+            .stdin(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()?;
+        p.take_stdin().unwrap().write_all(b"hello world")?;
+        p.wait_for_success()?;
+        assert_eq!(fs::read(dir.join("data"))?, b"hello world");
+
+        // The spooled data is removed when the upload fails too.
+        let tmp_dir = dir.join("tmp");
+        fs::create_dir_all(&tmp_dir)?;
+        let cmd = format!("export TMPDIR={}; {}",
+            tmp_dir.display(), policy.upload_shell_cmd("data", "false".to_string()));
+        let mut p = Command::new_shell(cmd).stdin(Stdio::null()).stderr(Stdio::null()).spawn()?;
+        assert!(p.wait_for_success().is_err());
+        assert_eq!(fs::read_dir(&tmp_dir)?.count(), 0);
+
+        Ok(())
+    }
+}
@@ -18,12 +18,25 @@ use crate::{
     consts::*,
     util::UrlExt,
 };
+use super::RetryPolicy;
 
 // AWS S3 adapter
 
 lazy_static! {
     static ref S3_CMD: String = std::env::var("S3_CMD")
         .unwrap_or_else(|_| "aws s3".to_string());
+
+    // Range downloads need the lower level s3api command. We derive it from
+    // S3_CMD so that options such as --endpoint-url are kept.
+    static ref S3API_CMD: Option<String> = S3_CMD.strip_suffix(" s3")
+        .map(|prefix| format!("{} s3api", prefix));
+
+    static ref RETRY_POLICY: RetryPolicy = RetryPolicy::from_env(
+        "FF_S3", DEFAULT_REMOTE_STORE_RETRY_ATTEMPTS, &[
+            "Connection reset", "Connection was closed", "timed out", "Read timeout",
+            "Could not connect to the endpoint", "SlowDown", "InternalError",
+            "ServiceUnavailable", "RequestTimeout",
+        ]);
 }
 
 pub struct Store {
@@ -62,10 +75,24 @@ impl super::File for File {
     }
 
     fn download_shell_cmd(&self) -> String {
-        format!("{} cp \"{}\" -", *S3_CMD, self.url)
+        let download_cmd = format!("{} cp \"{}\" -", *S3_CMD, self.url);
+        // get-object writes the object to the given file, and its metadata to stdout.
+        let range_cmd = S3API_CMD.as_ref().map(|s3api_cmd| format!(
+            "{} get-object --bucket \"{}\" --key \"{}\" --range \"bytes=$off-\" /dev/fd/3 3>&1 > /dev/null",
+            s3api_cmd, self.url.host_str().unwrap_or_default(), self.url.path().trim_start_matches('/')));
+        RETRY_POLICY.download_shell_cmd(self.url.as_str(), download_cmd, range_cmd)
+    }
+
+    fn delete_shell_cmd(&self) -> String {
+        // Deleting a missing object succeeds on S3.
+        format!("{} rm \"{}\"", *S3_CMD, self.url)
     }
 
     fn has_not_found_error(&self, stderr: &str) -> bool {
         stderr.contains("Not Found")
     }
+
+    fn retry_policy(&self) -> RetryPolicy {
+        RETRY_POLICY.clone()
+    }
 }
@@ -0,0 +1,447 @@
+//  Copyright 2020 Two Sigma Investments, LP.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+use anyhow::{Result, Context};
+use std::{
+    cell::RefCell,
+    convert::TryFrom,
+    fs,
+    io::{Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    os::unix::process::ExitStatusExt,
+    path::PathBuf,
+    process::ExitStatus,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use nix::sys::signal::Signal;
+use serde_json::Value;
+use url::Url;
+use crate::consts::*;
+
+// When FF_TRACE_EXPORTER is set, the run, checkpoint, extract, and stop
+// commands emit a trace in the OTLP/JSON format. The trace ID is derived from
+// the invocation ID. The root span covers the whole command, with a child span
+// per metrics action (e.g., restore, checkpoint), and a span per process of a
+// `ProcessGroup`, carrying its command, exit status, and stderr tail.
+// The shard prefix of an image is the invocation ID of the command that
+// checkpointed it. This way, a restore links to the trace of its checkpoint.
+// FF_TRACE_EXPORTER is one of:
+// * http://host:port/path   OTLP/HTTP collector. The path defaults to /v1/traces
+// * file:/path              OTLP/JSON lines, appended to the file
+// Spans are exported when an action ends, and when the command exits.
+
+const DEFAULT_OTLP_PATH: &str = "/v1/traces";
+const SPAN_KIND_INTERNAL: u32 = 1;
+const STATUS_CODE_OK: u32 = 1;
+const STATUS_CODE_ERROR: u32 = 2;
+
+lazy_static! {
+    static ref EXPORTER: Option<Exporter> = std::env::var("FF_TRACE_EXPORTER").ok()
+        .and_then(|url| url.parse()
+            .map_err(|e| warn!("Traces are not exported to FF_TRACE_EXPORTER: {:#}", e))
+            .ok());
+
+    static ref ROOT: Mutex<Option<RootSpan>> = Mutex::new(None);
+
+    // Ended spans, waiting to be exported
+    static ref PENDING_SPANS: Mutex<Vec<Value>> = Mutex::new(Vec::new());
+
+    // Number of exports in progress
+    static ref PENDING_EXPORTS: AtomicUsize = AtomicUsize::new(0);
+}
+
+thread_local! {
+    // The IDs of the action spans the current thread is in, innermost last.
+    static SPAN_STACK: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+}
+
+enum Exporter {
+    Http { url: Url },
+    File { path: PathBuf },
+}
+
+impl FromStr for Exporter {
+    type Err = anyhow::Error;
+
+    fn from_str(url: &str) -> Result<Self> {
+        if let Some(path) = url.strip_prefix("file:") {
+            return Ok(Exporter::File { path: PathBuf::from(path) });
+        }
+
+        let mut url = Url::parse(url).with_context(|| format!("Invalid URL `{}`", url))?;
+        ensure!(url.scheme() == "http",
+                "Unknown trace exporter `{}`. Expected http://host:port/path or file:/path", url);
+        ensure!(url.host_str().is_some(), "Missing host in `{}`", url);
+        if url.path() == "/" {
+            url.set_path(DEFAULT_OTLP_PATH);
+        }
+        Ok(Exporter::Http { url })
+    }
+}
+
+impl Exporter {
+    fn export(&self, request: &Value) -> Result<()> {
+        let body = serde_json::to_string(request)?;
+        match self {
+            Exporter::File { path } => {
+                let mut file = fs::OpenOptions::new().create(true).append(true).open(path)
+                    .with_context(|| format!("Failed to open {}", path.display()))?;
+                // A single write keeps lines whole when several processes append.
+                file.write_all((body + "\n").as_bytes())
+                    .with_context(|| format!("Failed to write to {}", path.display()))?;
+            }
+            Exporter::Http { url } => {
+                post_json(url, &body).with_context(|| format!("Failed to export traces to {}", url))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A minimal HTTP/1.1 client. We only need to POST a body, and read the status.
+fn post_json(url: &Url, body: &str) -> Result<()> {
+    let timeout = Duration::from_secs(TRACE_EXPORT_TIMEOUT_SECS);
+    // unwrap() is safe, the host is checked when parsing the exporter.
+    let host = url.host_str().unwrap();
+    let port = url.port_or_known_default().unwrap_or(80);
+    let addr = (host, port).to_socket_addrs()?.next()
+        .ok_or_else(|| anyhow!("{} does not resolve to any address", host))?;
+
+    let mut stream = TcpStream::connect_timeout(&addr, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+
+    let path = match url.query() {
+        Some(query) => format!("{}?{}", url.path(), query),
+        None => url.path().to_string(),
+    };
+    write!(stream, "POST {} HTTP/1.1\r\n\
+                    Host: {}:{}\r\n\
+                    Content-Type: application/json\r\n\
+                    Content-Length: {}\r\n\
+                    Connection: close\r\n\r\n{}",
+           path, host, port, body.len(), body)?;
+
+    // The status line is of the form "HTTP/1.1 200 OK"
+    let mut response = [0; 64];
+    let len = stream.read(&mut response)?;
+    let response = String::from_utf8_lossy(&response[..len]);
+    let status = response.split_whitespace().nth(1).unwrap_or_default();
+    ensure!(status.starts_with('2'), "Unexpected response: {}",
+            response.lines().next().unwrap_or_default());
+    Ok(())
+}
+
+/// Returns `id` in hex, left padded with zeros to `len` bytes.
+fn hex_id(id: &str, len: usize) -> String {
+    let hex: String = id.bytes().take(len).map(|b| format!("{:02x}", b)).collect();
+    format!("{:0>width$}", hex, width = 2*len)
+}
+
+/// The trace ID of the command of the given invocation ID.
+fn trace_id(invocation_id: &str) -> String {
+    hex_id(invocation_id, 16)
+}
+
+/// The root span ID of the command of the given invocation ID. Deriving it
+/// from the invocation ID makes it possible to link to it from another command.
+fn root_span_id(invocation_id: &str) -> String {
+    hex_id(invocation_id, 8)
+}
+
+fn random_span_id() -> String {
+    format!("{:016x}", rand::random::<u64>())
+}
+
+fn unix_nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos().to_string()
+}
+
+fn attribute(key: &str, value: Value) -> Value {
+    let value = match value {
+        Value::String(s) => json!({"stringValue": s}),
+        Value::Bool(b) => json!({"boolValue": b}),
+        // OTLP/JSON encodes 64 bit integers as strings
+        Value::Number(n) if n.is_i64() => json!({"intValue": n.to_string()}),
+        Value::Number(n) => json!({"doubleValue": n}),
+        Value::Array(values) => json!({"arrayValue": {"values":
+            values.into_iter().map(|v| attribute("", v)["value"].take()).collect::<Vec<_>>()
+        }}),
+        _ => json!({"stringValue": value.to_string()}),
+    };
+    json!({"key": key, "value": value})
+}
+
+fn status(error: Option<String>) -> Value {
+    match error {
+        None => json!({"code": STATUS_CODE_OK}),
+        Some(message) => json!({"code": STATUS_CODE_ERROR, "message": message}),
+    }
+}
+
+struct RootSpan {
+    name: String,
+    started_at: SystemTime,
+    attributes: Vec<Value>,
+    links: Vec<Value>,
+}
+
+fn is_tracing() -> bool {
+    EXPORTER.is_some() && ROOT.lock().unwrap().is_some()
+}
+
+/// The span that new spans of the current thread are children of.
+fn current_parent_id() -> String {
+    SPAN_STACK.with(|stack| stack.borrow().last().cloned())
+        .unwrap_or_else(|| root_span_id(&INVOCATION_ID))
+}
+
+fn push_span(span: Value) {
+    PENDING_SPANS.lock().unwrap().push(span);
+}
+
+/// Starts the trace of the command. It does nothing when FF_TRACE_EXPORTER is not set.
+pub fn start(command: &str) {
+    if EXPORTER.is_none() {
+        return;
+    }
+    *ROOT.lock().unwrap() = Some(RootSpan {
+        name: command.to_string(),
+        started_at: SystemTime::now() - START_TIME.elapsed(),
+        attributes: vec![
+            attribute("fastfreeze.command", json!(command)),
+            attribute("fastfreeze.invocation_id", json!(*INVOCATION_ID)),
+        ],
+        links: vec![],
+    });
+}
+
+/// Sets an attribute of the root span.
+pub fn set_root_attribute(key: &str, value: Value) {
+    if let Some(root) = ROOT.lock().unwrap().as_mut() {
+        root.attributes.retain(|a| a["key"] != key);
+        root.attributes.push(attribute(key, value));
+    }
+}
+
+/// Links the root span to the trace of the checkpoint that produced the image
+/// of the given shard prefix.
+pub fn link_to_checkpoint(shard_prefix: &str) {
+    set_root_attribute("fastfreeze.source_shard_prefix", json!(shard_prefix));
+    if let Some(root) = ROOT.lock().unwrap().as_mut() {
+        root.links.push(json!({
+            "traceId": trace_id(shard_prefix),
+            "spanId": root_span_id(shard_prefix),
+            "attributes": [attribute("fastfreeze.link", json!("checkpoint"))],
+        }));
+    }
+}
+
+/// A span covering an action. New spans of the current thread are its
+/// children until it ends.
+pub struct ActionSpan {
+    id: Option<String>,
+    name: String,
+    parent_id: String,
+    started_at: SystemTime,
+}
+
+impl ActionSpan {
+    pub fn enter(name: &str) -> Self {
+        let parent_id = current_parent_id();
+        let id = if is_tracing() {
+            let id = random_span_id();
+            SPAN_STACK.with(|stack| stack.borrow_mut().push(id.clone()));
+            Some(id)
+        } else {
+            None
+        };
+        Self { id, name: name.to_string(), parent_id, started_at: SystemTime::now() }
+    }
+
+    /// Ends the span with the outcome of the metrics event of the action,
+    /// and exports the spans ended so far.
+    pub fn exit(self, event: &Value) {
+        let id = match self.id {
+            Some(id) => id,
+            None => return,
+        };
+        SPAN_STACK.with(|stack| stack.borrow_mut().retain(|i| *i != id));
+
+        let error = match event["outcome"].as_str() {
+            Some("error") => Some(event["error"].as_str().unwrap_or("error").to_string()),
+            _ => None,
+        };
+        push_span(json!({
+            "traceId": trace_id(&INVOCATION_ID),
+            "spanId": id,
+            "parentSpanId": self.parent_id,
+            "name": self.name,
+            "kind": SPAN_KIND_INTERNAL,
+            "startTimeUnixNano": unix_nanos(self.started_at),
+            "endTimeUnixNano": unix_nanos(SystemTime::now()),
+            "attributes": [attribute("fastfreeze.action", json!(self.name))],
+            "status": status(error),
+        }));
+        export_pending();
+    }
+}
+
+/// Records the span of a process that has exited.
+pub fn record_process(
+    name: &str,
+    display_cmd: &str,
+    started_at: SystemTime,
+    exit_status: ExitStatus,
+    stderr_tail: Option<Vec<String>>,
+) {
+    if !is_tracing() {
+        return;
+    }
+
+    let mut attributes = vec![attribute("process.command_line", json!(display_cmd))];
+    let error = if let Some(exit_code) = exit_status.code() {
+        attributes.push(attribute("process.exit.code", json!(exit_code)));
+        Some(format!("exit_code={}", exit_code)).filter(|_| exit_code != 0)
+    } else if let Some(signal) = exit_status.signal() {
+        let signal = Signal::try_from(signal)
+            .map_or_else(|_| format!("signal {}", signal), |s| s.to_string());
+        attributes.push(attribute("process.exit.signal", json!(signal)));
+        Some(format!("caught fatal {}", signal))
+    } else {
+        None
+    };
+    if let Some(tail) = stderr_tail {
+        attributes.push(attribute("fastfreeze.stderr_tail", json!(tail)));
+    }
+
+    push_span(json!({
+        "traceId": trace_id(&INVOCATION_ID),
+        "spanId": random_span_id(),
+        "parentSpanId": current_parent_id(),
+        "name": name,
+        "kind": SPAN_KIND_INTERNAL,
+        "startTimeUnixNano": unix_nanos(started_at),
+        "endTimeUnixNano": unix_nanos(SystemTime::now()),
+        "attributes": attributes,
+        "status": status(error),
+    }));
+}
+
+fn export_request(spans: Vec<Value>) -> Value {
+    let host = hostname::get().map_or_else(
+        |_| "unknown".to_string(),
+        |h| h.to_string_lossy().into_owned());
+    json!({"resourceSpans": [{
+        "resource": {"attributes": [
+            attribute("service.name", json!("fastfreeze")),
+            attribute("service.version", json!(env!("CARGO_PKG_VERSION"))),
+            attribute("host.name", json!(host)),
+        ]},
+        "scopeSpans": [{
+            "scope": {"name": "fastfreeze"},
+            "spans": spans,
+        }],
+    }]})
+}
+
+/// Exports the ended spans in the background, so that tracing never delays
+/// FastFreeze.
+fn export_pending() {
+    let exporter = match *EXPORTER {
+        Some(ref exporter) => exporter,
+        None => return,
+    };
+    let spans = std::mem::take(&mut *PENDING_SPANS.lock().unwrap());
+    if spans.is_empty() {
+        return;
+    }
+
+    PENDING_EXPORTS.fetch_add(1, Ordering::SeqCst);
+    std::thread::spawn(move || {
+        if let Err(e) = exporter.export(&export_request(spans)) {
+            warn!("{:#}", e);
+        }
+        PENDING_EXPORTS.fetch_sub(1, Ordering::SeqCst);
+    });
+}
+
+/// Ends the root span, and waits for the spans to be exported, up to
+/// TRACE_EXPORT_TIMEOUT_SECS. Called before exiting.
+pub fn finish(result: &Result<()>) {
+    let root = match ROOT.lock().unwrap().take() {
+        Some(root) => root,
+        None => return,
+    };
+
+    push_span(json!({
+        "traceId": trace_id(&INVOCATION_ID),
+        "spanId": root_span_id(&INVOCATION_ID),
+        "name": root.name,
+        "kind": SPAN_KIND_INTERNAL,
+        "startTimeUnixNano": unix_nanos(root.started_at),
+        "endTimeUnixNano": unix_nanos(SystemTime::now()),
+        "attributes": root.attributes,
+        "links": root.links,
+        "status": status(result.as_ref().err().map(|e| format!("{:#}", e))),
+    }));
+    export_pending();
+
+    let deadline = Instant::now() + Duration::from_secs(TRACE_EXPORT_TIMEOUT_SECS);
+    while PENDING_EXPORTS.load(Ordering::SeqCst) > 0 && Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_ids() {
+        assert_eq!(trace_id("abc123"), "00000000000000000000616263313233");
+        assert_eq!(root_span_id("abc123"), "0000616263313233");
+        assert_eq!(random_span_id().len(), 16);
+    }
+
+    #[test]
+    fn test_exporter() -> Result<()> {
+        match "http://localhost:4318".parse()? {
+            Exporter::Http { url } => assert_eq!(url.as_str(), "http://localhost:4318/v1/traces"),
+            _ => panic!("expected an http exporter"),
+        }
+        assert!(matches!("file:/tmp/traces.jsonl".parse()?, Exporter::File { .. }));
+        assert!("https://localhost:4318".parse::<Exporter>().is_err());
+
+        let path = std::env::temp_dir().join(format!("ff-traces-test-{}.jsonl", std::process::id()));
+        let exporter: Exporter = format!("file:{}", path.display()).parse()?;
+        exporter.export(&export_request(vec![json!({"name": "a"})]))?;
+        let request: Value = serde_json::from_str(&fs::read_to_string(&path)?)?;
+        assert_eq!(request["resourceSpans"][0]["scopeSpans"][0]["spans"][0]["name"], "a");
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_attribute() {
+        assert_eq!(attribute("a", json!(3)), json!({"key": "a", "value": {"intValue": "3"}}));
+        assert_eq!(attribute("b", json!(["x"])),
+                   json!({"key": "b", "value": {"arrayValue": {"values": [{"stringValue": "x"}]}}}));
+    }
+}
@@ -211,6 +211,26 @@ pub fn get_home_dir() -> Option<PathBuf> {
         .and_then(|h| if h.to_string_lossy().is_empty() { None } else { Some(h) })
 }
 
+/// Parses the environment variable `name`. Returns None when it is not set.
+pub fn parse_env_var<T>(name: &str) -> Result<Option<T>>
+    where T: std::str::FromStr, T::Err: std::fmt::Display
+{
+    env::var(name).ok()
+        .map(|s| s.parse::<T>().map_err(|e| anyhow!("{}=`{}` is invalid: {}", name, s, e)))
+        .transpose()
+}
+
+/// Same as `parse_env_var()`, but an invalid value is reported, and ignored.
+/// This is for settings that are read lazily, where failing is not an option.
+pub fn parse_env_var_or_warn<T>(name: &str) -> Option<T>
+    where T: std::str::FromStr, T::Err: std::fmt::Display
+{
+    parse_env_var(name).unwrap_or_else(|e| {
+        warn!("{:#}. Using the default value", e);
+        None
+    })
+}
+
 pub trait JsonMerge {
     fn merge(self, b: Value) -> Self;
 }
@@ -108,7 +108,7 @@ fn inject_env_system_wide(env: &EnvVars) -> Result<()> {
     }().with_context(|| format!("Failed to create {}", LD_INJECT_ENV_PATH.display()))
 }
 
-fn ensure_system_wide_virtualization_is_enabled() -> Result<()> {
+pub fn ensure_system_wide_virtualization_is_enabled() -> Result<()> {
     // Check if applications are getting virtualization env injection via libvirtcpuid.
     let output = || -> Result<_> {
         Command::new(&["env"])